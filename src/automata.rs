@@ -1,11 +1,12 @@
 use std::collections::HashSet;
 use std::cmp::max;
 use crate::editor::Editor;
-use crate::utils::{v_jump_to_line, Position, PromptCallbackCode, Promptable, ScrollDirection};
+use crate::utils::{v_jump_to_line, Motion, Operator, Position, PromptCallbackCode, Promptable, RepeatableChange, ScrollDirection};
 use termion::event::Key;
 use crate::config::INVARIANT_ERROR_MESSAGE;
 use crate::terminal::Terminal;
 use crate::log;
+use crate::registers::Registers;
 
 #[derive(Debug, PartialEq)]
 pub enum EditorState {
@@ -23,27 +24,38 @@ pub enum EditorState {
     Lowercase,
     Uppercase,
     G,
-    Z
+    Z,
+    Inner,
+    Around,
+    Register,
+    Record,
+    Execute,
+    Mark
 }
 
 pub struct EditorFSM {
     pub state:              EditorState,
     pub command_buffer:     String,
-    pub last_command:       String,
+    pub last_command:       Option<RepeatableChange>,
     pub command_count:      usize,
     pub nav_object_count:   usize,
+    pub operator:           Option<Operator>,
+    pub register:           Option<char>,
+    pub executed:           String,
 }
 
-impl Promptable for EditorFSM { }
 
 impl EditorFSM {
     pub fn new() -> Self {
         EditorFSM {
             state: EditorState::Normal,
             command_buffer: String::new(),
-            last_command: String::new(),
+            last_command: None,
             command_count: 0,
             nav_object_count: 0,
+            operator: None,
+            register: None,
+            executed: String::new(),
         }
     }
 
@@ -52,16 +64,72 @@ impl EditorFSM {
     pub fn success_exit(&mut self) {
         self.command_count = 0;
         self.nav_object_count = 0;
+        self.operator = None;
+        self.register = None;
         self.state = EditorState::Normal;
         self.success_log();
+        self.executed = std::mem::take(&mut self.command_buffer);
+    }
+
+    pub fn failure_exit(&mut self) {
+        self.command_count = 0;
+        self.nav_object_count = 0;
+        self.operator = None;
+        self.register = None;
+        self.state = EditorState::Normal;
         self.command_buffer.clear();
     }
 
+    // remembers a finished change for '.', along with the register it used
+    pub fn record_change(&mut self, keys: &str, count: usize) {
+        self.last_command = Some(RepeatableChange {
+            keys: keys.to_string(),
+            count,
+            register: self.register,
+            inserted: None,
+        });
+    }
+
+    pub fn begin_operator(&mut self, key: char) {
+        if let Some(operator) = Operator::from_key(key) {
+            self.state = match operator {
+                Operator::Delete => EditorState::Delete,
+                Operator::Change => EditorState::Change,
+                Operator::Yank => EditorState::Yank,
+                Operator::Lowercase => EditorState::Lowercase,
+                Operator::Uppercase => EditorState::Uppercase,
+                Operator::Togglecase => EditorState::Togglecase,
+                // only a visual selection shifts lines for now, and it doesn't wait for a motion
+                Operator::ShiftRight | Operator::ShiftLeft => return,
+            };
+            self.operator = Some(operator);
+            self.command_buffer.push(key);
+        }
+    }
+
     pub fn run(&mut self, base_key: &char, editor: &mut Editor) {
+        let origin = editor.cursor_position;
+        self.executed.clear();
+        self.run_command(base_key, editor);
+
+        // a motion that can't go anywhere (j on the last line, w at the end of the file...) fails, which stops a macro
+        let keys = if self.executed.is_empty() { base_key.to_string() } else { self.executed.clone() };
+        let moved = origin.x != editor.cursor_position.x || origin.y != editor.cursor_position.y;
+        if !moved && is_failable_motion(&keys) {
+            editor.abort_replay();
+        }
+    }
+
+    fn run_command(&mut self, base_key: &char, editor: &mut Editor) {
         use self::commands::{move_right, move_down, move_left, move_up, to_last_line,
                              to_line_start, to_top_screen, to_bottom_screen, to_mid_screen, to_line_end,
                              to_next_word_end, to_next_word_start, to_prev_word_end, to_first_line_graph, to_last_line_graph,
-                             to_prev_word_start};
+                             to_prev_word_start, insert_before, append_after, insert_line_start, append_line_end,
+                             open_line_below, open_line_above, put_after, put_before, undo, undo_line, redo,
+                             undo_older, undo_newer, repeat_last_change, search_forward, search_backward, search_next, search_prev,
+                             search_word_forward, search_word_backward};
+        use self::operators::operator_pending;
+        use self::visual::{visual_kind, visual_pending, visual_state};
 
         // STATE MACHINE FOR INPUT HANDLING
         match *base_key {
@@ -122,29 +190,103 @@ impl EditorFSM {
                 to_line_end(self, editor, 1);
                 return;
             },
+            'i' => {
+                self.record_change("i", 0);
+                insert_before(self, editor, 1);
+                return;
+            },
+            'a' => {
+                self.record_change("a", 0);
+                append_after(self, editor, 1);
+                return;
+            },
+            'I' => {
+                self.record_change("I", 0);
+                insert_line_start(self, editor, 1);
+                return;
+            },
+            'A' => {
+                self.record_change("A", 0);
+                append_line_end(self, editor, 1);
+                return;
+            },
+            'o' => {
+                self.record_change("o", 0);
+                open_line_below(self, editor, 1);
+                return;
+            },
+            'O' => {
+                self.record_change("O", 0);
+                open_line_above(self, editor, 1);
+                return;
+            },
+            'p' => {
+                self.record_change("p", 0);
+                put_after(self, editor, 1);
+                return;
+            },
+            'P' => {
+                self.record_change("P", 0);
+                put_before(self, editor, 1);
+                return;
+            },
+            'u' => {
+                undo(self, editor, 1);
+                return;
+            },
+            '.' => {
+                repeat_last_change(self, editor, 0);
+                return;
+            },
+            'q' if editor.recording.is_some() => {
+                editor.stop_recording();
+                return;
+            },
+            'U' => {
+                undo_line(self, editor, 1);
+                return;
+            },
+            '/' => {
+                search_forward(self, editor, 1);
+                return;
+            },
+            '?' => {
+                search_backward(self, editor, 1);
+                return;
+            },
+            'n' => {
+                search_next(self, editor, 1);
+                return;
+            },
+            'N' => {
+                search_prev(self, editor, 1);
+                return;
+            },
+            '*' => {
+                search_word_forward(self, editor, 1);
+                return;
+            },
+            '#' => {
+                search_word_backward(self, editor, 1);
+                return;
+            },
             _ => ()
         }
 
 
         // STATE MACHINE FOR EDITOR STATE
         match base_key {
-            'v' | 'V' => {
-                if *base_key == 'V' {
-                    self.state = EditorState::MLVisual;
-                    self.command_buffer.push(*base_key);
-                } else {
-                    self.state = EditorState::Visual;
-                    self.command_buffer.push(*base_key);
-                }
+            'v' | 'V' | '\x16' => {
+                let kind = visual_kind(*base_key).expect(INVARIANT_ERROR_MESSAGE);
+                self.state = visual_state(kind);
+                self.command_buffer.push(*base_key);
+                editor.start_visual(kind);
+                editor.refresh_screen().ok();
             },
             'r' => {
                 self.state = EditorState::Replace;
                 self.command_buffer.push(*base_key);
             },
-            '/' => {
-                self.state = EditorState::Search;
-                self.command_buffer.push(*base_key);
-            },
             'f' => {
                 self.state = EditorState::LineScan;
                 self.command_buffer.push(*base_key);
@@ -157,16 +299,23 @@ impl EditorFSM {
                 self.state = EditorState::Z;
                 self.command_buffer.push(*base_key);
             },
-            'c' => {
-                self.state = EditorState::Change;
+            'c' | 'y' | 'd' => {
+                self.begin_operator(*base_key);
+            },
+            '"' => {
+                self.state = EditorState::Register;
                 self.command_buffer.push(*base_key);
             },
-            'y' => {
-                self.state = EditorState::Yank;
+            'q' => {
+                self.state = EditorState::Record;
                 self.command_buffer.push(*base_key);
             },
-            'd' => {
-                self.state = EditorState::Delete;
+            '@' => {
+                self.state = EditorState::Execute;
+                self.command_buffer.push(*base_key);
+            },
+            'm' => {
+                self.state = EditorState::Mark;
                 self.command_buffer.push(*base_key);
             },
             '0'..='9' => {
@@ -177,7 +326,64 @@ impl EditorFSM {
             _ => ()
         }
 
-        self.prompt_exec( |fsm, key| {
+        let fsm = self;
+        editor.prompt_exec( |editor, key| {
+            if let Some(operator) = fsm.operator {
+                return operator_pending(fsm, editor, operator, key);
+            }
+            if editor.selection.is_some() {
+                return visual_pending(fsm, editor, key);
+            }
+            if fsm.state == EditorState::Record || fsm.state == EditorState::Execute {
+                return match key {
+                    Key::Char(name) if fsm.state == EditorState::Record && (name.is_ascii_alphanumeric() || name == '"') => {
+                        editor.start_recording(name);
+                        fsm.command_buffer.push(name);
+                        fsm.success_exit();
+                        PromptCallbackCode::Success
+                    },
+                    Key::Char(name) if fsm.state == EditorState::Execute && (Registers::is_valid(name) || name == '@') => {
+                        let count = fsm.command_count;
+                        fsm.command_buffer.push(name);
+                        fsm.success_exit();
+                        editor.execute_macro(name, count);
+                        PromptCallbackCode::Success
+                    },
+                    _ => {
+                        fsm.failure_exit();
+                        PromptCallbackCode::Failure
+                    }
+                };
+            }
+            if fsm.state == EditorState::Mark {
+                return match key {
+                    Key::Char(name) if name.is_ascii_lowercase() => {
+                        editor.document.marks.insert(name, editor.cursor_position);
+                        fsm.command_buffer.push(name);
+                        fsm.success_exit();
+                        PromptCallbackCode::Success
+                    },
+                    _ => {
+                        fsm.failure_exit();
+                        PromptCallbackCode::Failure
+                    }
+                };
+            }
+            if fsm.state == EditorState::Register {
+                return match key {
+                    Key::Char(name) if Registers::is_valid(name) => {
+                        fsm.register = Some(name);
+                        fsm.state = EditorState::Normal;
+                        fsm.command_buffer.push(name);
+                        PromptCallbackCode::Continue
+                    },
+                    _ => {
+                        fsm.failure_exit();
+                        PromptCallbackCode::Failure
+                    }
+                };
+            }
+
             match key {
                 Key::Char('g') => {
                     if fsm.state == EditorState::G {
@@ -252,6 +458,18 @@ impl EditorFSM {
                     }
                     return PromptCallbackCode::Continue;
                 },
+                Key::Char('v') if fsm.state == EditorState::G => {
+                    // 'gv' brings back the last selection
+                    if !editor.reselect() {
+                        fsm.failure_exit();
+                        return PromptCallbackCode::Failure;
+                    }
+                    fsm.state = visual_state(editor.selection.as_ref().map(|selection| selection.kind).unwrap_or_default());
+                    fsm.command_buffer.push('v');
+                    editor.refresh_screen().ok();
+
+                    PromptCallbackCode::Continue
+                },
                 Key::Char('~') => {
                     if fsm.state == EditorState::G {
                         fsm.state = EditorState::Togglecase;
@@ -364,24 +582,124 @@ impl EditorFSM {
                                 fsm.success_exit();
                                 return PromptCallbackCode::Success;
                             },
+                            'd' | 'c' | 'y' => {
+                                fsm.begin_operator(x);
+                                return PromptCallbackCode::Continue;
+                            },
+                            '@' => {
+                                fsm.state = EditorState::Execute;
+                                fsm.command_buffer.push(x);
+                                return PromptCallbackCode::Continue;
+                            },
+                            '"' if fsm.register.is_none() => {
+                                fsm.state = EditorState::Register;
+                                fsm.command_buffer.push(x);
+                                return PromptCallbackCode::Continue;
+                            },
+                            '.' => {
+                                // a count given to '.' replaces the one the change was made with
+                                let count = fsm.command_count;
+                                fsm.success_exit();
+                                repeat_last_change(fsm, editor, count);
+                                return PromptCallbackCode::Success;
+                            },
+                            'u' | 'U' => {
+                                if x == 'u' { undo(fsm, editor, fsm.command_count); } else { undo_line(fsm, editor, fsm.command_count); }
+
+                                fsm.command_buffer.push(x);
+                                fsm.success_exit();
+                                return PromptCallbackCode::Success;
+                            },
+                            'p' | 'P' => {
+                                // a register prefix gets us here without a count
+                                let count = max(fsm.command_count, 1);
+                                fsm.record_change(&x.to_string(), fsm.command_count);
+                                if x == 'p' { put_after(fsm, editor, count); } else { put_before(fsm, editor, count); }
+
+                                fsm.command_buffer.push(x);
+                                fsm.success_exit();
+                                return PromptCallbackCode::Success;
+                            },
+                            '/' | '?' | 'n' | 'N' | '*' | '#' => {
+                                let count = fsm.command_count;
+                                fsm.command_buffer.push(x);
+                                fsm.success_exit();
+                                match x {
+                                    '/' => search_forward(fsm, editor, count),
+                                    '?' => search_backward(fsm, editor, count),
+                                    'n' => search_next(fsm, editor, count),
+                                    'N' => search_prev(fsm, editor, count),
+                                    '*' => search_word_forward(fsm, editor, count),
+                                    _ => search_word_backward(fsm, editor, count),
+                                }
+                                return PromptCallbackCode::Success;
+                            },
+                            'i' | 'a' | 'I' | 'A' | 'o' | 'O' => {
+                                fsm.record_change(&x.to_string(), fsm.command_count);
+                                match x {
+                                    'i' => insert_before(fsm, editor, fsm.command_count),
+                                    'a' => append_after(fsm, editor, fsm.command_count),
+                                    'I' => insert_line_start(fsm, editor, fsm.command_count),
+                                    'A' => append_line_end(fsm, editor, fsm.command_count),
+                                    'o' => open_line_below(fsm, editor, fsm.command_count),
+                                    _ => open_line_above(fsm, editor, fsm.command_count),
+                                }
+
+                                fsm.command_buffer.push(x);
+                                fsm.success_exit();
+                                return PromptCallbackCode::Success;
+                            },
                             _ => ()
                         }
+                    } else if fsm.state == EditorState::G && (x == '-' || x == '+') {
+                        if x == '-' { undo_older(fsm, editor, max(fsm.command_count, 1)); } else { undo_newer(fsm, editor, max(fsm.command_count, 1)); }
+
+                        fsm.command_buffer.push(x);
+                        fsm.success_exit();
+                        return PromptCallbackCode::Success;
                     }
                     return PromptCallbackCode::Continue;
                 },
+                Key::Ctrl('r') if fsm.state == EditorState::Normal => {
+                    redo(fsm, editor, fsm.command_count);
+
+                    fsm.success_exit();
+                    PromptCallbackCode::Success
+                },
                 _ => { return PromptCallbackCode::Failure; }
             }
 
         }, None);
+
+        if fsm.operator.is_some() { // <Esc> cancels a pending operator
+            fsm.failure_exit();
+        }
+        if editor.selection.is_some() { // and ends a selection
+            editor.end_visual();
+            fsm.failure_exit();
+        }
     }
 }
 
+fn is_failable_motion(keys: &str) -> bool {
+    let keys = keys.trim_start_matches(|c: char| c.is_ascii_digit());
+    let mut chars = keys.chars();
+    let motion = match (chars.next(), chars.next(), chars.next()) {
+        (Some(key), None, None) => Motion::from_key(key),
+        (Some('g'), Some(key), None) => Motion::from_g_key(key),
+        _ => None,
+    };
+
+    matches!(motion, Some(Motion::Left | Motion::Right | Motion::Down | Motion::Up | Motion::NextWordStart
+                          | Motion::NextWordEnd | Motion::PrevWordStart | Motion::PrevWordEnd))
+}
+
 pub mod commands {
     use std::collections::HashMap;
     use crate::editor::Editor;
     use crate::EditorFSM;
     use crate::utils::{find_char_position, find_string_position, get_isolated_v_char_class, get_isolated_v_str_class, get_v_char_class,
-                       is_word, ScrollDirection, VCharacterClass};
+                       is_word, InsertKind, ScrollDirection, SearchDirection, StatusMessage, VCharacterClass};
     use unicode_segmentation::UnicodeSegmentation;
     use crate::log;
 
@@ -552,7 +870,9 @@ pub mod commands {
     pub fn to_bottom_screen (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
         if action_count == 0 { return; }
         let height = editor.net_height;
-        let bottom = editor.offset.y.saturating_add(height).saturating_add(1);
+        // a file shorter than the screen ends above its bottom
+        let last = editor.document.rows.len().saturating_sub(1) as u16;
+        let bottom = editor.offset.y.saturating_add(height).saturating_add(1).min(last);
 
         if let Some(curr_row) = editor.document.rows.get(bottom as usize)
         {
@@ -1058,4 +1378,1172 @@ pub mod commands {
 
         -1
     }
-}
\ No newline at end of file
+
+    pub fn insert_before (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.enter_insert_mode(InsertKind::Before, action_count);
+    }
+
+    pub fn append_after (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        if let Some(curr_row) = editor.document.rows.get(editor.cursor_position.y as usize) {
+            if (editor.cursor_position.x as usize) < curr_row.len {
+                editor.cursor_position.x = editor.cursor_position.x.saturating_add(1);
+            }
+        }
+        editor.enter_insert_mode(InsertKind::After, action_count);
+    }
+
+    pub fn insert_line_start (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        if to_first_line_graph(fsm, editor, 1) < 0 {
+            // a blank line has no graph to land on, so we insert after the blanks like vim does
+            editor.cursor_position.x = editor.current_row_len() as u16;
+        }
+        editor.enter_insert_mode(InsertKind::LineStart, action_count);
+    }
+
+    pub fn append_line_end (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        to_line_end(fsm, editor, 1);
+        editor.cursor_position.x = editor.current_row_len() as u16;
+        editor.enter_insert_mode(InsertKind::LineEnd, action_count);
+    }
+
+    pub fn open_line_below (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.open_line_below();
+        editor.enter_insert_mode(InsertKind::LineBelow, action_count);
+    }
+
+    pub fn open_line_above (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.open_line_above();
+        editor.enter_insert_mode(InsertKind::LineAbove, action_count);
+    }
+
+    pub fn undo (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.undo(action_count);
+    }
+
+    pub fn redo (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.redo(action_count);
+    }
+
+    pub fn undo_line (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.undo_line();
+    }
+
+    pub fn undo_older (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.undo_chronologically(-(action_count as isize));
+    }
+
+    pub fn undo_newer (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.undo_chronologically(action_count as isize);
+    }
+
+    pub fn repeat_last_change (fsm: &mut EditorFSM, editor: &mut Editor, count: usize) {
+        if let Some(mut change) = fsm.last_command.clone() {
+            if count > 0 {
+                change.count = count;
+            }
+            // like vim, repeating a put from a numbered register moves on to the next one: "1p... puts "1, "2, "3
+            if let Some(register @ '1'..='8') = change.register {
+                change.register = char::from_digit(register.to_digit(10).unwrap_or(0).saturating_add(1), 10);
+            }
+            editor.replay_keys(fsm, change.replay_keys());
+        }
+    }
+
+    pub fn put_after (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.put_register(fsm.register, false, action_count);
+    }
+
+    pub fn put_before (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.put_register(fsm.register, true, action_count);
+    }
+
+    pub fn search_forward (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        if let Err(error) = editor.search_prompt(SearchDirection::Forward, action_count) {
+            editor.status_message = Some(StatusMessage::from(error.to_string()));
+        }
+    }
+
+    pub fn search_backward (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        if let Err(error) = editor.search_prompt(SearchDirection::Backward, action_count) {
+            editor.status_message = Some(StatusMessage::from(error.to_string()));
+        }
+    }
+
+    pub fn search_word_forward (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.search_word(SearchDirection::Forward, action_count);
+    }
+
+    pub fn search_word_backward (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.search_word(SearchDirection::Backward, action_count);
+    }
+
+    pub fn search_next (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.repeat_search(false, action_count);
+    }
+
+    pub fn search_prev (fsm: &mut EditorFSM, editor: &mut Editor, action_count: usize) {
+        if action_count == 0 { return; }
+        editor.repeat_search(true, action_count);
+    }
+}
+
+pub mod operators {
+    use std::cmp::{max, min};
+    use termion::event::Key;
+    use unicode_segmentation::UnicodeSegmentation;
+    use crate::automata::{EditorFSM, EditorState};
+    use crate::editor::Editor;
+    use crate::utils::{get_isolated_v_str_class, v_jump_to_line, InsertKind, Motion, Operator, Position, PromptCallbackCode,
+                       RangeKind, ScrollDirection, TextObject, TextRange};
+    use crate::config::DEFAULT_TAB_WIDTH;
+    use crate::registers::Register;
+    use super::text_objects::resolve_text_object;
+    use super::commands::{move_down, move_up, to_first_line_graph, to_last_line, to_last_line_graph, to_line_end,
+                          to_bottom_screen, to_mid_screen, to_next_word_end, to_next_word_start, to_prev_word_end,
+                          to_prev_word_start, to_top_screen};
+
+    pub fn operator_pending (fsm: &mut EditorFSM, editor: &mut Editor, operator: Operator, key: Key) -> PromptCallbackCode {
+        let c = match key {
+            Key::Char(c) => c,
+            _ => {
+                fsm.failure_exit();
+                return PromptCallbackCode::Failure;
+            }
+        };
+
+        let counts_objects = fsm.state != EditorState::G && fsm.state != EditorState::Inner && fsm.state != EditorState::Around;
+        // counts on the operator and on the motion multiply: 2d3w deletes six words
+        let has_count = fsm.command_count > 0 || fsm.nav_object_count > 0;
+        let count = max(fsm.command_count, 1).saturating_mul(max(fsm.nav_object_count, 1));
+
+        if c.is_ascii_digit() && (c != '0' || fsm.nav_object_count > 0) && counts_objects {
+            let number = c.to_digit(10).expect("failed to parse action key!");
+            fsm.nav_object_count = (fsm.nav_object_count * 10).saturating_add(number as usize);
+            fsm.command_buffer.push(c);
+            return PromptCallbackCode::Continue;
+        }
+        if fsm.state == EditorState::Inner || fsm.state == EditorState::Around {
+            let around = fsm.state == EditorState::Around;
+            fsm.command_buffer.push(c);
+
+            return match TextObject::from_key(c) {
+                Some(object) => {
+                    if let Some(range) = resolve_text_object(editor, object, around, count) {
+                        if operator != Operator::Yank {
+                            fsm.record_change(&format!("{}{}{}", operator.key(), if around { 'a' } else { 'i' }, c), if has_count { count } else { 0 });
+                        }
+                        apply_operator(editor, operator, range, fsm.register);
+                    }
+                    fsm.success_exit();
+                    PromptCallbackCode::Success
+                },
+                None => {
+                    fsm.failure_exit();
+                    PromptCallbackCode::Failure
+                }
+            };
+        }
+        if (c == 'i' || c == 'a') && counts_objects {
+            fsm.state = if c == 'i' { EditorState::Inner } else { EditorState::Around };
+            fsm.command_buffer.push(c);
+            return PromptCallbackCode::Continue;
+        }
+        if c == 'g' && fsm.state != EditorState::G {
+            fsm.state = EditorState::G;
+            fsm.command_buffer.push(c);
+            return PromptCallbackCode::Continue;
+        }
+
+        let motion = if fsm.state == EditorState::G {
+            Motion::from_g_key(c)
+        } else if c == operator.key() {
+            Some(Motion::CurrentLine)
+        } else {
+            Motion::from_key(c)
+        };
+        fsm.command_buffer.push(c);
+
+        match motion {
+            Some(motion) => {
+                let range = if operator == Operator::Change && motion == Motion::NextWordStart && !on_blank(editor) {
+                    change_word_range(editor, count)
+                } else {
+                    resolve_motion(editor, motion, count, has_count)
+                };
+                if let Some(range) = range {
+                    if operator != Operator::Yank {
+                        let keys = if fsm.state == EditorState::G { format!("{}g{}", operator.key(), c) } else { format!("{}{}", operator.key(), c) };
+                        fsm.record_change(&keys, if has_count { count } else { 0 });
+                    }
+                    apply_operator(editor, operator, range, fsm.register);
+                }
+                fsm.success_exit();
+
+                PromptCallbackCode::Success
+            },
+            None => {
+                fsm.failure_exit();
+                PromptCallbackCode::Failure
+            }
+        }
+    }
+
+    // runs the motion from the cursor and turns where it lands into the range an operator works on.
+    // the cursor is left where it was
+    pub fn resolve_motion (editor: &mut Editor, motion: Motion, count: usize, has_count: bool) -> Option<TextRange> {
+        let origin = editor.cursor_position;
+        let target = motion_target(editor, motion, count, has_count)?;
+
+        if motion.is_linewise() {
+            return Some(TextRange {
+                start: Position { x: origin.x, y: min(origin.y, target.y) },
+                end: Position { x: origin.x, y: max(origin.y, target.y) },
+                kind: RangeKind::Linewise,
+            });
+        }
+
+        let (start, mut end) = if (target.y, target.x) < (origin.y, origin.x) { (target, origin) } else { (origin, target) };
+        if motion.is_inclusive() {
+            end.x = end.x.saturating_add(1);
+        } else if end.y > start.y && end.x <= editor.document.first_graph(end.y) && (end.x == 0 || motion == Motion::NextWordStart) {
+            // an exclusive motion that ends at the start of a later line stops at the end of the line before it,
+            // so 'dw' on the last word of a line doesn't swallow the line break
+            end.y = end.y.saturating_sub(1);
+            end.x = editor.document.rows.get(end.y as usize).map(|row| row.len).unwrap_or(0) as u16;
+        }
+
+        Some(TextRange { start, end, kind: RangeKind::Charwise })
+    }
+
+    // where the motion would take the cursor, which stays where it is
+    pub fn motion_target (editor: &mut Editor, motion: Motion, count: usize, has_count: bool) -> Option<Position> {
+        let origin = editor.cursor_position;
+        let last_nav_position = editor.movement_data.last_nav_position;
+        let offset = editor.offset;
+        let last_line = editor.document.rows.len().saturating_sub(1);
+        let mut scratch = EditorFSM::new(); // motions may reset the fsm they run on, so the real one stays out of it
+        scratch.command_count = if has_count { count } else { 0 };
+        let count = max(count, 1);
+
+        if editor.document.rows.is_empty() {
+            return None;
+        }
+
+        match motion {
+            Motion::Left => {
+                editor.cursor_position.x = origin.x.saturating_sub(count as u16);
+            },
+            Motion::Right => {
+                editor.cursor_position.x = min((origin.x as usize).saturating_add(count), editor.current_row_len()) as u16;
+            },
+            Motion::Down => {
+                let count = min(count, last_line.saturating_sub(origin.y as usize));
+                if count == 0 { return None; }
+                move_down(&mut scratch, editor, count);
+            },
+            Motion::Up => {
+                let count = min(count, origin.y as usize);
+                if count == 0 { return None; }
+                move_up(&mut scratch, editor, count);
+            },
+            Motion::NextWordStart => {
+                for _ in 0..count { to_next_word_start(&mut scratch, editor, 1); }
+            },
+            Motion::NextWordEnd => {
+                for _ in 0..count { to_next_word_end(&mut scratch, editor, 1); }
+            },
+            Motion::PrevWordStart => {
+                for _ in 0..count { to_prev_word_start(&mut scratch, editor, 1); }
+            },
+            Motion::PrevWordEnd => {
+                for _ in 0..count { to_prev_word_end(&mut scratch, editor, 1); }
+            },
+            Motion::LineStart => {
+                editor.cursor_position.x = 0;
+            },
+            Motion::FirstLineGraph => {
+                to_first_line_graph(&mut scratch, editor, 1);
+            },
+            Motion::LastLineGraph => {
+                editor.cursor_position.y = min((origin.y as usize).saturating_add(count).saturating_sub(1), last_line) as u16;
+                to_last_line_graph(&mut scratch, editor, 1);
+            },
+            Motion::LineEnd => {
+                editor.cursor_position.y = min((origin.y as usize).saturating_add(count).saturating_sub(1), last_line) as u16;
+                to_line_end(&mut scratch, editor, 1);
+            },
+            Motion::LastLine => {
+                if has_count { v_jump_to_line(editor, &mut scratch, &'G'); } else { to_last_line(&mut scratch, editor, 1); }
+            },
+            Motion::FirstLine => {
+                scratch.command_count = if has_count { count } else { 1 };
+                v_jump_to_line(editor, &mut scratch, &'g');
+            },
+            Motion::TopScreen => {
+                to_top_screen(&mut scratch, editor, 1);
+            },
+            Motion::MidScreen => {
+                to_mid_screen(&mut scratch, editor, 1);
+            },
+            Motion::BottomScreen => {
+                to_bottom_screen(&mut scratch, editor, 1);
+            },
+            Motion::CurrentLine => {
+                editor.cursor_position.y = min((origin.y as usize).saturating_add(count).saturating_sub(1), last_line) as u16;
+            },
+        }
+
+        let target = editor.cursor_position;
+        editor.cursor_position = origin;
+        editor.movement_data.last_nav_position = last_nav_position;
+        editor.offset = offset;
+
+        Some(target)
+    }
+
+    // 'cw' on a non-blank works like 'ce', except that it never leaves the word the cursor is on
+    fn change_word_range (editor: &mut Editor, count: usize) -> Option<TextRange> {
+        let origin = editor.cursor_position;
+        let row = editor.document.rows.get(origin.y as usize)?;
+        let graphemes = row.string.graphemes(true).collect::<Vec<&str>>();
+        let class = get_isolated_v_str_class(graphemes.get(origin.x as usize)?);
+        let mut end = origin.x as usize;
+        while end.saturating_add(1) < graphemes.len() && get_isolated_v_str_class(graphemes[end.saturating_add(1)]) == class {
+            end = end.saturating_add(1);
+        }
+
+        let mut end = Position { x: end as u16, y: origin.y };
+        if count > 1 {
+            editor.cursor_position = end;
+            if let Some(range) = resolve_motion(editor, Motion::NextWordEnd, count.saturating_sub(1), true) {
+                end = Position { x: range.end.x.saturating_sub(1), y: range.end.y };
+            }
+            editor.cursor_position = origin;
+        }
+
+        Some(TextRange { start: origin, end: Position { x: end.x.saturating_add(1), y: end.y }, kind: RangeKind::Charwise })
+    }
+
+    fn on_blank (editor: &Editor) -> bool {
+        editor.document.rows
+            .get(editor.cursor_position.y as usize)
+            .and_then(|row| row.string.graphemes(true).nth(editor.cursor_position.x as usize).map(|g| g.trim().is_empty()))
+            .unwrap_or(true)
+    }
+
+    pub fn apply_operator (editor: &mut Editor, operator: Operator, range: TextRange, register: Option<char>) {
+        let TextRange { start, end, kind } = range;
+
+        match operator {
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                shift_lines(editor, start.y, end.y, operator == Operator::ShiftRight, 1);
+                return;
+            },
+            Operator::Lowercase | Operator::Uppercase | Operator::Togglecase => {
+                change_case(editor, operator, range);
+                return;
+            },
+            _ => ()
+        }
+
+        if kind == RangeKind::Linewise {
+            let text = editor.document.lines_text(start.y, end.y);
+            editor.store_register(register, operator, Register { text, kind });
+
+            match operator {
+                Operator::Delete => {
+                    editor.document.delete_lines(start.y, end.y);
+                    editor.cursor_position.y = min(start.y as usize, editor.document.rows.len().saturating_sub(1)) as u16;
+                    editor.cursor_position.x = editor.document.first_graph(editor.cursor_position.y);
+                },
+                Operator::Change => {
+                    // the lines collapse into a single empty one that we start typing on
+                    editor.document.delete_range(&Position { x: 0, y: start.y }, &Position { x: u16::MAX, y: end.y });
+                    editor.cursor_position = Position { x: 0, y: start.y };
+                    editor.enter_insert_mode(InsertKind::Before, 1);
+                },
+                _ => {
+                    editor.cursor_position.y = start.y;
+                },
+            }
+        } else if kind == RangeKind::Blockwise {
+            let text = (start.y..=end.y).map(|y| block_columns(editor, y, start.x, end.x))
+                .map(|(y, from, to)| editor.document.rows.get(y as usize).map(|row| row.substring(from, to).to_string()).unwrap_or_default())
+                .collect::<Vec<String>>()
+                .join("\n");
+            editor.store_register(register, operator, Register { text, kind });
+
+            if operator != Operator::Yank {
+                for y in start.y..=end.y {
+                    let (y, from, to) = block_columns(editor, y, start.x, end.x);
+                    editor.document.delete_range(&Position { x: from as u16, y }, &Position { x: to as u16, y });
+                }
+            }
+            editor.cursor_position = start;
+            if operator == Operator::Change {
+                editor.cursor_position.x = min(start.x as usize, editor.current_row_len()) as u16;
+                editor.enter_insert_mode(InsertKind::Before, 1);
+            } else {
+                editor.cursor_position.x = min(start.x as usize, editor.current_row_len().saturating_sub(1)) as u16;
+            }
+        } else {
+            let text = editor.document.text_range(&start, &end);
+            editor.store_register(register, operator, Register { text, kind });
+
+            match operator {
+                Operator::Delete => {
+                    editor.document.delete_range(&start, &end);
+                    editor.cursor_position = start;
+                    editor.cursor_position.x = min(start.x as usize, editor.current_row_len().saturating_sub(1)) as u16;
+                },
+                Operator::Change => {
+                    editor.document.delete_range(&start, &end);
+                    editor.cursor_position = start;
+                    editor.enter_insert_mode(InsertKind::Before, 1);
+                },
+                _ => {
+                    editor.cursor_position = start;
+                },
+            }
+        }
+
+        editor.movement_data.last_nav_position.x = editor.cursor_position.x;
+        editor.scroll(ScrollDirection::None);
+    }
+
+    // the part of line 'y' that a block over the columns 'from'..'to' covers, clamped to the line
+    fn block_columns (editor: &Editor, y: u16, from: u16, to: u16) -> (u16, usize, usize) {
+        let len = editor.document.rows.get(y as usize).map(|row| row.len).unwrap_or(0);
+        (y, min(from as usize, len), min(to as usize, len))
+    }
+
+    // '>' and '<': adds or removes 'count' levels of indentation on the lines 'first' to 'last'
+    pub fn shift_lines (editor: &mut Editor, first: u16, last: u16, right: bool, count: usize) {
+        let width = (DEFAULT_TAB_WIDTH as usize).saturating_mul(max(count, 1));
+
+        for y in first..=last {
+            let line = match editor.document.rows.get(y as usize) {
+                Some(row) => row.string.clone(),
+                None => break,
+            };
+            // like vim, empty lines are left alone
+            if line.is_empty() {
+                continue;
+            }
+            let shifted = if right {
+                format!("{}{}", " ".repeat(width), line)
+            } else {
+                let indent = line.chars().take(width).take_while(|c| *c == ' ' || *c == '\t').count();
+                line[indent..].to_string()
+            };
+            editor.document.replace_line(y as usize, &shifted);
+        }
+
+        editor.cursor_position.y = first;
+        editor.cursor_position.x = editor.document.first_graph(first);
+        editor.movement_data.last_nav_position.x = editor.cursor_position.x;
+        editor.scroll(ScrollDirection::None);
+    }
+
+    // 'u', 'U' and '~' on a selection: changes the case of every character in the range
+    pub fn change_case (editor: &mut Editor, operator: Operator, range: TextRange) {
+        let TextRange { start, end, kind } = range;
+
+        for y in start.y..=end.y {
+            let line = match editor.document.rows.get(y as usize) {
+                Some(row) => row.string.clone(),
+                None => break,
+            };
+            let len = line.graphemes(true).count();
+            let (from, to) = match kind {
+                RangeKind::Linewise => (0, len),
+                RangeKind::Blockwise => (min(start.x as usize, len), min(end.x as usize, len)),
+                RangeKind::Charwise => (
+                    if y == start.y { start.x as usize } else { 0 },
+                    if y == end.y { min(end.x as usize, len) } else { len },
+                ),
+            };
+
+            let changed = line.graphemes(true).enumerate().map(|(index, grapheme)| {
+                if index < from || index >= to {
+                    return grapheme.to_string();
+                }
+                match operator {
+                    Operator::Lowercase => grapheme.to_lowercase(),
+                    Operator::Uppercase => grapheme.to_uppercase(),
+                    _ => grapheme.chars().map(|c| if c.is_uppercase() { c.to_lowercase().to_string() } else { c.to_uppercase().to_string() }).collect(),
+                }
+            }).collect::<String>();
+            if changed != line {
+                editor.document.replace_line(y as usize, &changed);
+            }
+        }
+
+        editor.cursor_position = if kind == RangeKind::Linewise { Position { x: editor.cursor_position.x, y: start.y } } else { start };
+        editor.cursor_position.x = min(editor.cursor_position.x as usize, editor.current_row_len().saturating_sub(1)) as u16;
+        editor.movement_data.last_nav_position.x = editor.cursor_position.x;
+        editor.scroll(ScrollDirection::None);
+    }
+}
+
+pub mod text_objects {
+    use std::cmp::{max, min};
+    use termion::event::Key;
+    use unicode_segmentation::UnicodeSegmentation;
+    use crate::automata::{EditorFSM, EditorState};
+    use crate::editor::Editor;
+    use crate::utils::{get_v_char_class, Position, PromptCallbackCode, RangeKind, Selection, TextObject, TextRange, VCharacterClass};
+
+    pub fn resolve_text_object (editor: &Editor, object: TextObject, around: bool, count: usize) -> Option<TextRange> {
+        let count = max(count, 1);
+        if editor.document.rows.is_empty() {
+            return None;
+        }
+
+        match object {
+            TextObject::Word => word_object(editor, around, count),
+            TextObject::Bracket(open, close) => bracket_object(editor, open, close, around, count),
+            TextObject::Quote(quote) => quote_object(editor, quote, around),
+            TextObject::Paragraph => paragraph_object(editor, around, count),
+            TextObject::Sentence => sentence_object(editor, around, count),
+        }
+    }
+
+    // 'vi(' and friends: selects the object while in one of the visual states
+    pub fn select_text_object (fsm: &mut EditorFSM, editor: &mut Editor, key: Key) -> Option<PromptCallbackCode> {
+        let c = match key { Key::Char(c) => c, _ => return None };
+
+        if fsm.state == EditorState::Visual || fsm.state == EditorState::MLVisual || fsm.state == EditorState::BlockVisual {
+            if c != 'i' && c != 'a' { return None; }
+            fsm.state = if c == 'i' { EditorState::Inner } else { EditorState::Around };
+            fsm.command_buffer.push(c);
+            return Some(PromptCallbackCode::Continue);
+        }
+        if fsm.state != EditorState::Inner && fsm.state != EditorState::Around {
+            return None;
+        }
+
+        let around = fsm.state == EditorState::Around;
+        let range = TextObject::from_key(c).and_then(|object| resolve_text_object(editor, object, around, fsm.command_count));
+        fsm.command_buffer.push(c);
+        fsm.command_count = 0;
+
+        if let Some(range) = range {
+            let (start, end) = if range.kind == RangeKind::Linewise {
+                let len = editor.document.rows.get(range.end.y as usize).map(|row| row.len).unwrap_or(0);
+                (Position { x: 0, y: range.start.y }, Position { x: len.saturating_sub(1) as u16, y: range.end.y })
+            } else {
+                (range.start, Position { x: range.end.x.saturating_sub(1), y: range.end.y })
+            };
+            let mut selection = Selection::new(start, range.kind);
+            selection.update(end);
+            editor.selection = Some(selection);
+            editor.cursor_position = end;
+        }
+        fsm.state = super::visual::visual_state(editor.selection.as_ref().map(|selection| selection.kind).unwrap_or_default());
+
+        Some(PromptCallbackCode::Continue)
+    }
+
+    fn grapheme_class (grapheme: &str) -> VCharacterClass {
+        get_v_char_class(grapheme.chars().next().unwrap_or(' '))
+    }
+
+    // (start, end, class) of every run of graphemes sharing a character class, 'end' being inclusive
+    fn class_runs (graphemes: &[&str]) -> Vec<(usize, usize, VCharacterClass)> {
+        let mut runs: Vec<(usize, usize, VCharacterClass)> = vec![];
+        for (index, grapheme) in graphemes.iter().enumerate() {
+            let class = grapheme_class(grapheme);
+            match runs.last_mut() {
+                Some(run) if run.2 == class => run.1 = index,
+                _ => runs.push((index, index, class)),
+            }
+        }
+
+        runs
+    }
+
+    fn word_object (editor: &Editor, around: bool, count: usize) -> Option<TextRange> {
+        let Position { x, y } = editor.cursor_position;
+        let row = editor.document.rows.get(y as usize)?;
+        let graphemes = row.string.graphemes(true).collect::<Vec<&str>>();
+        if graphemes.is_empty() { return None; }
+
+        let runs = class_runs(&graphemes);
+        let x = min(x as usize, graphemes.len().saturating_sub(1));
+        let current = runs.iter().position(|run| run.0 <= x && x <= run.1)?;
+        let last = runs.len().saturating_sub(1);
+        let mut start = runs[current].0;
+        let mut end_run = current;
+
+        if !around {
+            // white space between words counts as an object of its own
+            end_run = min(current.saturating_add(count).saturating_sub(1), last);
+        } else if runs[current].2 == VCharacterClass::Blank {
+            // the white space and the words after it
+            let mut words = 0;
+            while words < count && end_run < last {
+                end_run += 1;
+                if runs[end_run].2 != VCharacterClass::Blank { words += 1; }
+            }
+        } else {
+            let mut words = 1;
+            while words < count && end_run < last {
+                end_run += 1;
+                if runs[end_run].2 != VCharacterClass::Blank { words += 1; }
+            }
+            if end_run < last && runs[end_run.saturating_add(1)].2 == VCharacterClass::Blank {
+                end_run += 1;
+            } else if current > 0 && runs[current.saturating_sub(1)].2 == VCharacterClass::Blank {
+                // nothing trails the word, so the white space before it goes instead
+                start = runs[current.saturating_sub(1)].0;
+            }
+        }
+
+        Some(TextRange {
+            start: Position { x: start as u16, y },
+            end: Position { x: runs[end_run].1.saturating_add(1) as u16, y },
+            kind: RangeKind::Charwise,
+        })
+    }
+
+    fn bracket_object (editor: &Editor, open: char, close: char, around: bool, count: usize) -> Option<TextRange> {
+        let rows = &editor.document.rows;
+        let cursor = editor.cursor_position;
+        let (open, close) = (open.to_string(), close.to_string());
+
+        // walk back to the count-th bracket that isn't closed before the cursor
+        let mut balance = 0_usize;
+        let mut found = 0_usize;
+        let mut open_position = None;
+        'backward: for y in (0..=min(cursor.y as usize, rows.len().saturating_sub(1))).rev() {
+            let graphemes = rows[y].string.graphemes(true).collect::<Vec<&str>>();
+            if graphemes.is_empty() { continue; }
+            let start_x = if y == cursor.y as usize { min(cursor.x as usize, graphemes.len().saturating_sub(1)) } else { graphemes.len().saturating_sub(1) };
+
+            for x in (0..=start_x).rev() {
+                let at_cursor = y == cursor.y as usize && x == cursor.x as usize;
+                if graphemes[x] == close && !at_cursor {
+                    balance += 1;
+                } else if graphemes[x] == open {
+                    if balance == 0 {
+                        found += 1;
+                        if found == count {
+                            open_position = Some(Position { x: x as u16, y: y as u16 });
+                            break 'backward;
+                        }
+                    } else {
+                        balance -= 1;
+                    }
+                }
+            }
+        }
+        let open_position = open_position?;
+
+        let mut depth = 0_usize;
+        let mut close_position = None;
+        'forward: for (y, row) in rows.iter().enumerate().skip(open_position.y as usize) {
+            let graphemes = row.string.graphemes(true).collect::<Vec<&str>>();
+            let start_x = if y == open_position.y as usize { open_position.x as usize } else { 0 };
+
+            for (x, grapheme) in graphemes.iter().enumerate().skip(start_x) {
+                if *grapheme == open {
+                    depth += 1;
+                } else if *grapheme == close {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        close_position = Some(Position { x: x as u16, y: y as u16 });
+                        break 'forward;
+                    }
+                }
+            }
+        }
+        let close_position = close_position?;
+
+        if around {
+            return Some(TextRange {
+                start: open_position,
+                end: Position { x: close_position.x.saturating_add(1), y: close_position.y },
+                kind: RangeKind::Charwise,
+            });
+        }
+
+        let open_len = rows[open_position.y as usize].len as u16;
+        if open_position.y < close_position.y
+            && open_position.x.saturating_add(1) >= open_len
+            && close_position.x <= editor.document.first_graph(close_position.y) {
+            // a block whose brackets sit on lines of their own: the lines in between are the object
+            if close_position.y.saturating_sub(open_position.y) < 2 { return None; }
+            return Some(TextRange {
+                start: Position { x: 0, y: open_position.y.saturating_add(1) },
+                end: Position { x: 0, y: close_position.y.saturating_sub(1) },
+                kind: RangeKind::Linewise,
+            });
+        }
+
+        Some(TextRange {
+            start: Position { x: open_position.x.saturating_add(1), y: open_position.y },
+            end: close_position,
+            kind: RangeKind::Charwise,
+        })
+    }
+
+    fn quote_object (editor: &Editor, quote: char, around: bool) -> Option<TextRange> {
+        let Position { x, y } = editor.cursor_position;
+        let row = editor.document.rows.get(y as usize)?;
+        let graphemes = row.string.graphemes(true).collect::<Vec<&str>>();
+        let quote = quote.to_string();
+        let x = x as usize;
+
+        let quotes = graphemes.iter().enumerate()
+            .filter(|(index, g)| {
+                // a quote preceded by an odd number of backslashes is escaped
+                **g == quote && graphemes[..*index].iter().rev().take_while(|p| **p == "\\").count() % 2 == 0
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+
+        let (open, close) = if let Some(k) = quotes.iter().position(|q| *q == x) {
+            if k % 2 == 0 { (quotes[k], *quotes.get(k.saturating_add(1))?) } else { (quotes[k.saturating_sub(1)], quotes[k]) }
+        } else {
+            let before = quotes.iter().filter(|q| **q < x).count();
+            if before % 2 == 1 {
+                (quotes[before.saturating_sub(1)], *quotes.get(before)?)
+            } else {
+                // not inside a string: the first one after the cursor is used
+                (*quotes.get(before)?, *quotes.get(before.saturating_add(1))?)
+            }
+        };
+
+        let (mut start, mut end) = if around { (open, close.saturating_add(1)) } else { (open.saturating_add(1), close) };
+        if around {
+            let trailing = graphemes[end..].iter().take_while(|g| g.trim().is_empty()).count();
+            if trailing > 0 {
+                end = end.saturating_add(trailing);
+            } else {
+                start = start.saturating_sub(graphemes[..start].iter().rev().take_while(|g| g.trim().is_empty()).count());
+            }
+        }
+
+        Some(TextRange {
+            start: Position { x: start as u16, y },
+            end: Position { x: end as u16, y },
+            kind: RangeKind::Charwise,
+        })
+    }
+
+    fn is_blank_line (editor: &Editor, y: usize) -> bool {
+        editor.document.rows.get(y).map(|row| row.string.trim().is_empty()).unwrap_or(true)
+    }
+
+    // the last line of the run of blank (or non-blank) lines that 'from' belongs to
+    fn line_run_end (editor: &Editor, from: usize) -> usize {
+        let last = editor.document.rows.len().saturating_sub(1);
+        let blank = is_blank_line(editor, from);
+        let mut end = from;
+        while end < last && is_blank_line(editor, end.saturating_add(1)) == blank {
+            end += 1;
+        }
+
+        end
+    }
+
+    fn line_run_start (editor: &Editor, from: usize) -> usize {
+        let blank = is_blank_line(editor, from);
+        let mut start = from;
+        while start > 0 && is_blank_line(editor, start.saturating_sub(1)) == blank {
+            start -= 1;
+        }
+
+        start
+    }
+
+    fn paragraph_object (editor: &Editor, around: bool, count: usize) -> Option<TextRange> {
+        let y = min(editor.cursor_position.y as usize, editor.document.rows.len().saturating_sub(1));
+        let last = editor.document.rows.len().saturating_sub(1);
+        let mut start = line_run_start(editor, y);
+        let mut end = line_run_end(editor, y);
+
+        if !around {
+            // like words, a block of blank lines counts as a paragraph
+            for _ in 1..count {
+                if end >= last { break; }
+                end = line_run_end(editor, end.saturating_add(1));
+            }
+        } else {
+            if end < last {
+                // the paragraph and the blank lines after it, or the blank lines and the paragraph after them
+                end = line_run_end(editor, end.saturating_add(1));
+            } else if !is_blank_line(editor, y) && start > 0 {
+                start = line_run_start(editor, start.saturating_sub(1));
+            }
+            for _ in 1..count {
+                for _ in 0..2 {
+                    if end < last { end = line_run_end(editor, end.saturating_add(1)); }
+                }
+            }
+        }
+
+        Some(TextRange {
+            start: Position { x: 0, y: start as u16 },
+            end: Position { x: 0, y: end as u16 },
+            kind: RangeKind::Linewise,
+        })
+    }
+
+    fn sentence_object (editor: &Editor, around: bool, count: usize) -> Option<TextRange> {
+        let cursor = editor.cursor_position;
+        if is_blank_line(editor, cursor.y as usize) {
+            return paragraph_object(editor, around, count);
+        }
+
+        // the paragraph flattened into cells, the line breaks becoming blanks at the end of each line
+        let first = line_run_start(editor, cursor.y as usize);
+        let last = line_run_end(editor, cursor.y as usize);
+        let mut cells: Vec<(Position, &str)> = vec![];
+        for y in first..=last {
+            let row = &editor.document.rows[y];
+            for (x, grapheme) in row.string.graphemes(true).enumerate() {
+                cells.push((Position { x: x as u16, y: y as u16 }, grapheme));
+            }
+            if y != last {
+                cells.push((Position { x: row.len as u16, y: y as u16 }, " "));
+            }
+        }
+        if cells.is_empty() { return None; }
+
+        let is_blank = |index: usize| cells[index].1.trim().is_empty();
+        let is_sentence_end = |grapheme: &str| grapheme == "." || grapheme == "!" || grapheme == "?";
+        let is_closing = |grapheme: &str| grapheme == ")" || grapheme == "]" || grapheme == "\"" || grapheme == "'";
+
+        // alternating sentences and the white space between them as (start, end, is_white), 'end' being inclusive
+        let mut segments: Vec<(usize, usize, bool)> = vec![];
+        let mut index = 0;
+        while index < cells.len() {
+            let start = index;
+            if is_blank(index) {
+                while index < cells.len() && is_blank(index) { index += 1; }
+                segments.push((start, index.saturating_sub(1), true));
+                continue;
+            }
+            while index < cells.len() {
+                if is_sentence_end(cells[index].1) {
+                    let mut next = index.saturating_add(1);
+                    while next < cells.len() && is_closing(cells[next].1) { next += 1; }
+                    if next >= cells.len() || is_blank(next) {
+                        index = next;
+                        break;
+                    }
+                }
+                index += 1;
+            }
+            segments.push((start, index.saturating_sub(1), false));
+        }
+
+        let cursor_index = cells.iter()
+            .position(|(position, _)| position.y == cursor.y && position.x >= cursor.x)
+            .unwrap_or(cells.len().saturating_sub(1));
+        let current = segments.iter().position(|segment| segment.0 <= cursor_index && cursor_index <= segment.1)?;
+        let last_segment = segments.len().saturating_sub(1);
+        let mut start_segment = current;
+        let mut end_segment;
+
+        if !around {
+            end_segment = min(current.saturating_add(count).saturating_sub(1), last_segment);
+        } else {
+            end_segment = current;
+            let mut sentences = if segments[current].2 { 0 } else { 1 };
+            while sentences < count && end_segment < last_segment {
+                end_segment += 1;
+                if !segments[end_segment].2 { sentences += 1; }
+            }
+            if !segments[current].2 {
+                if end_segment < last_segment && segments[end_segment.saturating_add(1)].2 {
+                    end_segment += 1;
+                } else if current > 0 && segments[current.saturating_sub(1)].2 {
+                    start_segment = current.saturating_sub(1);
+                }
+            }
+        }
+
+        let start = cells[segments[start_segment].0].0;
+        let end = cells[segments[end_segment].1].0;
+
+        Some(TextRange {
+            start,
+            end: Position { x: end.x.saturating_add(1), y: end.y },
+            kind: RangeKind::Charwise,
+        })
+    }
+}
+
+pub mod visual {
+    use std::cmp::{max, min};
+    use termion::event::Key;
+    use crate::automata::{EditorFSM, EditorState};
+    use crate::editor::Editor;
+    use crate::registers::Registers;
+    use crate::utils::{BlockInsert, InsertKind, Motion, Operator, Position, PromptCallbackCode, RangeKind, ScrollDirection};
+    use super::operators::{apply_operator, motion_target, shift_lines};
+    use super::text_objects::select_text_object;
+
+    pub fn visual_state (kind: RangeKind) -> EditorState {
+        match kind {
+            RangeKind::Charwise => EditorState::Visual,
+            RangeKind::Linewise => EditorState::MLVisual,
+            RangeKind::Blockwise => EditorState::BlockVisual,
+        }
+    }
+
+    pub fn visual_kind (key: char) -> Option<RangeKind> {
+        match key {
+            'v' => Some(RangeKind::Charwise),
+            'V' => Some(RangeKind::Linewise),
+            '\x16' => Some(RangeKind::Blockwise), // Ctrl-V
+            _ => None
+        }
+    }
+
+    // handles a key while a selection is up: motions extend it, operators work on it and end it
+    pub fn visual_pending (fsm: &mut EditorFSM, editor: &mut Editor, key: Key) -> PromptCallbackCode {
+        let code = visual_key(fsm, editor, key);
+
+        if matches!(code, PromptCallbackCode::Continue) {
+            editor.update_selection();
+            editor.scroll(ScrollDirection::None);
+            // the selection is drawn as it grows, except while a macro or '.' is replaying keys
+            if editor.pending_keys.is_empty() {
+                editor.refresh_screen().ok();
+            }
+        }
+
+        code
+    }
+
+    fn visual_key (fsm: &mut EditorFSM, editor: &mut Editor, key: Key) -> PromptCallbackCode {
+        if let Some(code) = select_text_object(fsm, editor, key) {
+            return code;
+        }
+        let kind = match editor.selection.as_ref() {
+            Some(selection) => selection.kind,
+            None => return PromptCallbackCode::Failure,
+        };
+        let c = match key {
+            Key::Char(c) => c,
+            Key::Ctrl('v') => '\x16',
+            _ => return PromptCallbackCode::Continue,
+        };
+
+        if fsm.state == EditorState::Register {
+            if Registers::is_valid(c) {
+                fsm.register = Some(c);
+                fsm.command_buffer.push(c);
+            }
+            fsm.state = visual_state(kind);
+            return PromptCallbackCode::Continue;
+        }
+        if fsm.state == EditorState::G {
+            fsm.state = visual_state(kind);
+            fsm.command_buffer.push(c);
+            if c == 'v' {
+                // 'gv' in visual mode swaps to the previous selection
+                let current = editor.selection.clone().map(|selection| (selection, editor.cursor_position));
+                if editor.reselect() {
+                    editor.last_selection = current;
+                    fsm.state = visual_state(editor.selection.as_ref().map(|selection| selection.kind).unwrap_or(kind));
+                }
+            } else if let Some(motion) = Motion::from_g_key(c) {
+                move_selection(fsm, editor, motion);
+            }
+            return PromptCallbackCode::Continue;
+        }
+        if c.is_ascii_digit() && (c != '0' || fsm.command_count > 0) {
+            let number = c.to_digit(10).expect("failed to parse action key!");
+            fsm.command_count = (fsm.command_count * 10).saturating_add(number as usize);
+            fsm.command_buffer.push(c);
+            return PromptCallbackCode::Continue;
+        }
+
+        match c {
+            'v' | 'V' | '\x16' => {
+                let new_kind = visual_kind(c).unwrap_or(kind);
+                // the key that started the selection ends it, the others switch to their kind of selection
+                if new_kind == kind {
+                    editor.end_visual();
+                    fsm.success_exit();
+                    return PromptCallbackCode::Success;
+                }
+                if let Some(selection) = editor.selection.as_mut() {
+                    selection.kind = new_kind;
+                }
+                fsm.state = visual_state(new_kind);
+            },
+            'o' | 'O' => {
+                let cursor = editor.cursor_position;
+                if let Some(selection) = editor.selection.as_mut() {
+                    let anchor = selection.position;
+                    // 'O' in a block goes to the other corner on the same line
+                    if c == 'O' && kind == RangeKind::Blockwise {
+                        selection.position.x = cursor.x;
+                        editor.cursor_position.x = anchor.x;
+                    } else {
+                        selection.position = cursor;
+                        editor.cursor_position = anchor;
+                    }
+                    editor.movement_data.last_nav_position.x = editor.cursor_position.x;
+                }
+            },
+            'g' => {
+                fsm.state = EditorState::G;
+                fsm.command_buffer.push(c);
+            },
+            '"' => {
+                fsm.state = EditorState::Register;
+                fsm.command_buffer.push(c);
+            },
+            ':' => {
+                // the command line starts out with the selected lines as its range
+                editor.end_visual();
+                for key in ":'<,'>".chars().rev() {
+                    editor.pending_keys.push_front(Key::Char(key));
+                }
+                fsm.success_exit();
+                return PromptCallbackCode::Success;
+            },
+            'I' | 'A' if kind == RangeKind::Blockwise => {
+                fsm.command_buffer.push(c);
+                block_insert(editor, c == 'A');
+                fsm.success_exit();
+                return PromptCallbackCode::Success;
+            },
+            _ => {
+                if let Some(operator) = Operator::from_visual_key(c) {
+                    fsm.command_buffer.push(c);
+                    apply_to_selection(fsm, editor, operator);
+                    fsm.success_exit();
+                    return PromptCallbackCode::Success;
+                }
+                if let Some(motion) = Motion::from_key(c) {
+                    fsm.command_buffer.push(c);
+                    move_selection(fsm, editor, motion);
+                }
+            },
+        }
+
+        PromptCallbackCode::Continue
+    }
+
+    fn move_selection (fsm: &mut EditorFSM, editor: &mut Editor, motion: Motion) {
+        let has_count = fsm.command_count > 0;
+        let count = max(fsm.command_count, 1);
+        fsm.command_count = 0;
+
+        if let Some(target) = motion_target(editor, motion, count, has_count) {
+            let len = editor.document.rows.get(target.y as usize).map(|row| row.len).unwrap_or(0);
+            editor.cursor_position = target;
+            editor.cursor_position.x = min(target.x as usize, len.saturating_sub(1)) as u16;
+            if !motion.is_linewise() {
+                editor.movement_data.last_nav_position.x = editor.cursor_position.x;
+            }
+        }
+        if let Some(selection) = editor.selection.as_mut() {
+            if !motion.is_linewise() {
+                selection.to_line_end = motion == Motion::LineEnd;
+            }
+        }
+    }
+
+    fn apply_to_selection (fsm: &mut EditorFSM, editor: &mut Editor, operator: Operator) {
+        let range = match editor.selection.as_ref() {
+            Some(selection) => selection.range(),
+            None => return,
+        };
+        editor.end_visual();
+
+        match operator {
+            // '3>' shifts three times as far
+            Operator::ShiftRight | Operator::ShiftLeft => shift_lines(editor, range.start.y, range.end.y, operator == Operator::ShiftRight, fsm.command_count),
+            _ => apply_operator(editor, operator, range, fsm.register),
+        }
+
+        // what replaces a block goes into each of its lines
+        if operator == Operator::Change && range.kind == RangeKind::Blockwise {
+            if let Some(session) = editor.insert_session.as_mut() {
+                session.block = Some(BlockInsert { lines: (range.start.y.saturating_add(1), range.end.y), column: range.start.x, pad: false, to_line_end: false });
+            }
+        }
+    }
+
+    // 'I' and 'A' on a block: starts typing on its first line, before or after the block
+    fn block_insert (editor: &mut Editor, append: bool) {
+        let selection = match editor.selection.clone() {
+            Some(selection) => selection,
+            None => return,
+        };
+        editor.end_visual();
+
+        let ((left, top), (right, bottom)) = (selection.start, selection.end);
+        let to_line_end = append && selection.to_line_end;
+        editor.cursor_position = Position { x: left, y: top };
+        let len = editor.current_row_len();
+        let column = if to_line_end { len } else if append { (right as usize).saturating_add(1) } else { min(left as usize, len) };
+        if len < column {
+            editor.document.insert_str(&Position { x: len as u16, y: top }, &" ".repeat(column.saturating_sub(len)));
+        }
+        editor.cursor_position.x = column as u16;
+        editor.enter_insert_mode(InsertKind::Before, 1);
+
+        if let Some(session) = editor.insert_session.as_mut() {
+            let column = if append { right.saturating_add(1) } else { left };
+            session.block = Some(BlockInsert { lines: (top.saturating_add(1), bottom), column, pad: append, to_line_end });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::editor::Editor;
+    use crate::utils::{Position, RangeKind, TextObject};
+    use super::text_objects::resolve_text_object;
+
+    #[test]
+    fn test_text_object_ranges () {
+        let mut editor = Editor::default();
+        editor.document.populate(vec!["let x = f(a, (b), \"c d\");", "fn f() {", "    body", "}"]);
+
+        editor.cursor_position = Position { x: 14, y: 0 };
+        let range = resolve_text_object(&editor, TextObject::Bracket('(', ')'), false, 2).unwrap();
+        assert_eq!((range.start.x, range.start.y, range.end.x, range.end.y), (10, 0, 23, 0));
+
+        editor.cursor_position = Position { x: 20, y: 0 };
+        let range = resolve_text_object(&editor, TextObject::Quote('"'), false, 1).unwrap();
+        assert_eq!((range.start.x, range.start.y, range.end.x, range.end.y), (19, 0, 22, 0));
+
+        editor.cursor_position = Position { x: 19, y: 0 };
+        let range = resolve_text_object(&editor, TextObject::Word, true, 1).unwrap();
+        assert_eq!((range.start.x, range.start.y, range.end.x, range.end.y), (19, 0, 21, 0));
+
+        editor.cursor_position = Position { x: 4, y: 2 };
+        let range = resolve_text_object(&editor, TextObject::Bracket('{', '}'), false, 1).unwrap();
+        assert!(range.kind == RangeKind::Linewise);
+        assert_eq!((range.start.y, range.end.y), (2, 2));
+    }
+}
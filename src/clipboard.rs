@@ -0,0 +1,159 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use crate::terminal::TerminalBackend;
+
+// backs the '+' and '*' registers, which both go to the same system clipboard
+pub trait ClipboardProvider {
+    fn name(&self) -> String;
+    fn set(&mut self, text: &str, terminal: &mut dyn TerminalBackend) -> Result<(), String>;
+    // None when the clipboard can't be read, in which case the registers fall back to what we last copied
+    fn get(&mut self) -> Option<String>;
+}
+
+// copies through the terminal with an OSC 52 sequence, which also works over ssh
+#[derive(Default)]
+pub struct Osc52Clipboard;
+
+impl ClipboardProvider for Osc52Clipboard {
+    fn name(&self) -> String {
+        "osc52".to_string()
+    }
+
+    fn set(&mut self, text: &str, terminal: &mut dyn TerminalBackend) -> Result<(), String> {
+        terminal.set_clipboard(text).map_err(|error| format!("clipboard: {}", error))
+    }
+
+    fn get(&mut self) -> Option<String> {
+        // most terminals refuse OSC 52 queries, so reading is not attempted
+        None
+    }
+}
+
+// shells out to a clipboard command such as 'xclip', 'wl-copy' or 'pbcopy'
+pub struct CommandClipboard {
+    pub copy:               Vec<String>,
+    pub paste:              Option<Vec<String>>,
+}
+
+impl CommandClipboard {
+    pub fn from_command(command: &str) -> Self {
+        let copy = command.split_whitespace().map(String::from).collect::<Vec<String>>();
+        let paste = match copy.first().map(String::as_str) {
+            Some("xclip") => Some("xclip -selection clipboard -o"),
+            Some("xsel") => Some("xsel --clipboard --output"),
+            Some("wl-copy") => Some("wl-paste --no-newline"),
+            Some("pbcopy") => Some("pbpaste"),
+            _ => None,
+        };
+
+        Self { copy, paste: paste.map(|paste| paste.split_whitespace().map(String::from).collect()) }
+    }
+}
+
+impl ClipboardProvider for CommandClipboard {
+    fn name(&self) -> String {
+        self.copy.join(" ")
+    }
+
+    fn set(&mut self, text: &str, _terminal: &mut dyn TerminalBackend) -> Result<(), String> {
+        let (program, args) = self.copy.split_first().ok_or("clipboard: no command set")?;
+        let error = |error: std::io::Error| format!("clipboard: {}: {}", program, error);
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(error)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).map_err(error)?;
+        }
+
+        let status = child.wait().map_err(error)?;
+        if !status.success() {
+            return Err(format!("clipboard: {} exited with {}", program, status));
+        }
+
+        Ok(())
+    }
+
+    fn get(&mut self) -> Option<String> {
+        let (program, args) = self.paste.as_ref()?.split_first()?;
+        let output = Command::new(program).args(args).stderr(Stdio::null()).output().ok()?;
+
+        if output.status.success() { String::from_utf8(output.stdout).ok() } else { None }
+    }
+}
+
+// keeps the clipboard in memory; clones share their contents, so tests can look at what was copied
+#[derive(Default, Clone)]
+pub struct MemoryClipboard {
+    pub contents:           Rc<RefCell<Option<String>>>,
+}
+
+impl ClipboardProvider for MemoryClipboard {
+    fn name(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn set(&mut self, text: &str, _terminal: &mut dyn TerminalBackend) -> Result<(), String> {
+        *self.contents.borrow_mut() = Some(text.to_string());
+        Ok(())
+    }
+
+    fn get(&mut self) -> Option<String> {
+        self.contents.borrow().clone()
+    }
+}
+
+pub fn provider_from_option(value: &str) -> Box<dyn ClipboardProvider> {
+    match value {
+        "" | "osc52" => Box::new(Osc52Clipboard),
+        command => Box::new(CommandClipboard::from_command(command)),
+    }
+}
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let triple = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for n in 0..4 {
+            if n <= chunk.len() {
+                encoded.push(ALPHABET[(triple >> (18 - n * 6) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_encode () {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode("héllo\n".as_bytes()), "aMOpbGxvCg==");
+    }
+
+    #[test]
+    fn test_command_clipboard_paste_command () {
+        let clipboard = CommandClipboard::from_command("wl-copy");
+        assert_eq!(clipboard.paste, Some(vec!["wl-paste".to_string(), "--no-newline".to_string()]));
+        assert_eq!(CommandClipboard::from_command("my-copier --in").paste, None);
+    }
+}
@@ -4,3 +4,12 @@ pub const PACKAGE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 pub const EDITOR_NAME: &'static str = "Termite Editor";
 pub const DEFAULT_TAB_WIDTH: u8 = 4;
 pub const INVARIANT_ERROR_MESSAGE: &'static str = "PROGRAM INVARIANT VIOLATED!";
+pub const DEFAULT_BACKUP: bool = false; // keep a 'file~' copy of the previous version on save
+pub const DEFAULT_CLIPBOARD: &'static str = "osc52"; // or a copy command such as "xclip -selection clipboard", "wl-copy" or "pbcopy"
+pub const DEFAULT_UNDOFILE: bool = true; // keep the undo history of saved files in UNDO_DIR
+pub const UNDO_DIR: &'static str = "termite/undo"; // under $XDG_STATE_HOME, or ~/.local/state
+pub const HISTORY_FILE: &'static str = "termite/history.json"; // under $XDG_STATE_HOME, or ~/.local/state
+pub const HISTORY_SIZE: usize = 100; // lines kept for each kind of prompt
+pub const STATUS_MESSAGE_TIMEOUT: u64 = 5; // seconds
+pub const DEFAULT_IGNORECASE: bool = false; // searches ignore case...
+pub const DEFAULT_SMARTCASE: bool = false; // ...unless the pattern has an uppercase letter in it
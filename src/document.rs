@@ -1,20 +1,38 @@
 use crate::filetype::FileType;
+use crate::regex::Regex;
 use crate::row::Row;
-use crate::utils::{HighlightStreak, HighlightingOptions, Position};
+use crate::undo::{default_undo_dir, Edit, UndoTree};
+use crate::utils::{HighlightingOptions, Position};
 
+use crate::config::{DEFAULT_BACKUP, DEFAULT_UNDOFILE};
+
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
-use unicode_segmentation;
+use unicode_segmentation::UnicodeSegmentation;
 
 pub struct Document {
-    pub rows:           Vec<Row>,
-    pub file_name:      String,
-    pub is_loaded:      bool,
-    pub dirty:          bool,
-    pub file_type:      FileType,
-    pub hl_streak:      HighlightStreak,
+    pub rows:               Vec<Row>,
+    pub file_name:          String,
+    pub is_loaded:          bool,
+    pub dirty:              bool,
+    pub file_type:          FileType,
+    pub highlighted_rows:   usize, // how many rows from the top have their highlighting up to date
+    pub line_ending:        &'static str,
+    pub trailing_newline:   bool,
+    pub backup:             bool,
+    pub undo:               UndoTree,
+    pub undofile:           bool,
+    pub undo_dir:           Option<PathBuf>,
+    pub marks:              HashMap<char, Position>,
+    pub marked_lines:       Option<LineMarks>, // the lines ':g' has yet to get to, while it runs
 }
 
 impl Default for Document {
@@ -28,30 +46,449 @@ impl Default for Document {
                 name: "rust".to_owned(),
                 highlighting_ops: HighlightingOptions::default(),
             },
-            hl_streak: HighlightStreak::default(),
+            highlighted_rows: 0,
+            line_ending: "\n",
+            trailing_newline: true,
+            backup: DEFAULT_BACKUP,
+            undo: UndoTree::default(),
+            undofile: DEFAULT_UNDOFILE,
+            undo_dir: default_undo_dir(),
+            marks: HashMap::new(),
+            marked_lines: None,
         }
     }
 }
 
 impl Document {
-    pub fn load(&mut self) {
+    pub fn load(&mut self) -> Result<(), Error> {
         let variables: Vec<String> = env::args().collect();
-        if let Some(file_name) = &variables.get(1) {
-            let contents =
-                fs::read_to_string(file_name).expect("Something went wrong reading the file");
-            // todo: later we use a more elegant way to exit with error message
-            for (index, row) in contents.lines().enumerate() {
-                self.rows.push(Row::from(row.to_owned()));
+        let result = match variables.get(1) {
+            Some(file_name) => self.load_file(file_name).map_err(|error| Error::new(error.kind(), format!("\"{}\" {}", file_name, error))),
+            None => Ok(()),
+        };
+
+        self.is_loaded = true;
+        result
+    }
+
+    pub fn load_file(&mut self, file_name: &str) -> Result<(), Error> {
+        let contents = match fs::read_to_string(file_name) {
+            Ok(contents) => contents,
+            // a file that doesn't exist yet starts out empty, and ':w' creates it
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                self.file_name = file_name.to_string();
+                return Ok(());
+            },
+            Err(error) => return Err(error),
+        };
+        for (index, row) in contents.lines().enumerate() {
+            self.rows.push(Row::from(row.to_owned()));
+        }
+        self.file_name = file_name.to_string();
+        self.line_ending = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+        self.trailing_newline = contents.is_empty() || contents.ends_with('\n');
+
+        // the history from an earlier session only applies if nothing touched the file since
+        if let Some(undo_dir) = self.undo_dir.as_ref().filter(|_| self.undofile) {
+            if let Some(undo) = UndoTree::read(undo_dir, Path::new(file_name), &contents) {
+                self.undo = undo;
             }
         }
 
+        Ok(())
+    }
+
+    pub fn contents(&self) -> String {
+        let mut contents = self.rows.iter().map(|row| row.string.as_str()).collect::<Vec<&str>>().join(self.line_ending);
+        if self.trailing_newline && !self.rows.is_empty() {
+            contents.push_str(self.line_ending);
+        }
+
+        contents
+    }
+
+    pub fn save(&mut self) -> Result<usize, Error> {
+        if self.file_name.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "E32: No file name"));
+        }
+        let contents = self.contents();
+        write_atomically(Path::new(&self.file_name), contents.as_bytes(), self.backup)?;
+        self.dirty = false;
+        self.undo.commit();
+        self.undo.saved = self.undo.current;
+
+        if let Some(undo_dir) = self.undo_dir.as_ref().filter(|_| self.undofile) {
+            // the file itself is written by now, so losing its history is not worth failing the save over
+            let _ = self.undo.write(undo_dir, Path::new(&self.file_name), &contents);
+        }
+
+        Ok(contents.len())
+    }
+
+    pub fn save_as(&mut self, file_name: &str) -> Result<usize, Error> {
+        let previous_name = std::mem::replace(&mut self.file_name, file_name.to_string());
+        let result = self.save();
+        if result.is_err() {
+            self.file_name = previous_name;
+        }
+
+        result
+    }
+
+    pub fn populate(&mut self, lines: Vec<&str>) {
+        self.rows = lines.into_iter().map(Row::from).collect();
+        self.highlighted_rows = 0;
+        self.undo = UndoTree::default();
         self.is_loaded = true;
     }
 
+    // runs an edit touching the lines 'first' to 'last' (plus any it adds after them) and records it for undo
+    fn edit_lines<T>(&mut self, first: usize, last: usize, edit: impl FnOnce(&mut Self) -> T) -> T {
+        let len_before = self.rows.len();
+        let old = self.lines(first, last.saturating_add(1));
+        let result = edit(self);
+
+        let new_len = (old.len().saturating_add(self.rows.len())).saturating_sub(len_before);
+        let new = self.lines(first, first.saturating_add(new_len));
+        if old != new {
+            self.shift_marks(first, &old, &new);
+            self.undo.record(Edit { first, old, new });
+        }
+
+        result
+    }
+
+    // moves the marks along with an edit that put 'new' in place of the 'old' lines starting at 'first'.
+    // only the lines that differ count as changed, and the marks on changed lines that are gone go with them
+    fn shift_marks(&mut self, first: usize, old: &[String], new: &[String]) {
+        // matching the ends first, so that deleting one of two equal lines takes the first one's mark
+        let suffix = old.iter().rev().zip(new.iter().rev()).take_while(|(old, new)| old == new).count();
+        let prefix = old[..old.len() - suffix].iter().zip(&new[..new.len() - suffix]).take_while(|(old, new)| old == new).count();
+        let start = first.saturating_add(prefix);
+        let old_end = first.saturating_add(old.len() - suffix);
+        let new_end = first.saturating_add(new.len() - suffix);
+
+        self.marks.retain(|_, position| match LineMarks::moved(position.y as usize, start, old_end, new_end) {
+            Some(y) => {
+                position.y = y as u16;
+                true
+            },
+            None => false,
+        });
+        if let Some(marked_lines) = self.marked_lines.as_mut() {
+            marked_lines.shift(start, old_end, new_end);
+        }
+    }
+
+    fn lines(&self, from: usize, to: usize) -> Vec<String> {
+        self.rows.iter().take(to).skip(from).map(|row| row.string.clone()).collect()
+    }
+
+    // puts 'lines' in place of the 'count' lines starting at 'first', without recording anything
+    fn replace_lines(&mut self, first: usize, count: usize, lines: &[String]) {
+        let first = min(first, self.rows.len());
+        let end = min(first.saturating_add(count), self.rows.len());
+        self.rows.splice(first..end, lines.iter().map(|line| Row::from(line.as_str())));
+        self.unhighlight_from(first);
+    }
+
+    pub fn replace_line(&mut self, y: usize, text: &str) {
+        if y >= self.rows.len() {
+            return;
+        }
+        self.edit_lines(y, y, |document| {
+            document.rows[y] = Row::from(text);
+            document.dirty = true;
+            document.unhighlight_from(y);
+        });
+    }
+
+    // puts 'lines' in place of the 'count' lines starting at 'first'. a 'first' just past the last line appends them
+    pub fn splice_lines(&mut self, first: usize, count: usize, lines: &[String]) {
+        if first > self.rows.len() {
+            return;
+        }
+        self.edit_lines(first, first.saturating_add(count).saturating_sub(1), |document| {
+            document.replace_lines(first, count, lines);
+            document.dirty = true;
+        });
+    }
+
+    // steps back to the parent state and returns the cursor position from before the undone change
+    pub fn undo(&mut self) -> Option<Position> {
+        self.undo.commit();
+        let current = self.undo.current;
+        if current == 0 {
+            return None;
+        }
+
+        let edits = std::mem::take(&mut self.undo.nodes[current].edits);
+        for edit in edits.iter().rev() {
+            self.replace_lines(edit.first, edit.new.len(), &edit.old);
+            self.shift_marks(edit.first, &edit.new, &edit.old);
+        }
+        self.undo.nodes[current].edits = edits;
+
+        let node = &self.undo.nodes[current];
+        let (parent, cursor) = (node.parent, node.cursor);
+        self.undo.nodes[parent].redo_child = Some(current);
+        self.undo.current = parent;
+        self.dirty = self.undo.current != self.undo.saved;
+
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self) -> Option<Position> {
+        self.undo.commit();
+        let child = self.undo.nodes[self.undo.current].redo_child?;
+
+        let edits = std::mem::take(&mut self.undo.nodes[child].edits);
+        for edit in edits.iter() {
+            self.replace_lines(edit.first, edit.old.len(), &edit.new);
+            self.shift_marks(edit.first, &edit.old, &edit.new);
+        }
+        self.undo.nodes[child].edits = edits;
+
+        self.undo.current = child;
+        self.dirty = self.undo.current != self.undo.saved;
+
+        Some(self.undo.nodes[child].cursor)
+    }
+
+    // moves to any state in the tree: back up to a common ancestor, then down the other branch
+    pub fn undo_to(&mut self, target: usize) -> Option<Position> {
+        self.undo.commit();
+        if target >= self.undo.nodes.len() || target == self.undo.current {
+            return None;
+        }
+
+        let mut cursor = None;
+        while self.undo.path_down(self.undo.current, target).is_none() {
+            cursor = self.undo();
+        }
+        for node in self.undo.path_down(self.undo.current, target).unwrap_or_default() {
+            let current = self.undo.current;
+            self.undo.nodes[current].redo_child = Some(node);
+            cursor = self.redo();
+        }
+
+        cursor
+    }
+
+    pub fn insert(&mut self, at: &Position, c: char) {
+        if at.y as usize > self.rows.len() {
+            return;
+        }
+        self.edit_lines(at.y as usize, at.y as usize, |document| document.insert_unrecorded(at, c));
+    }
+
+    fn insert_unrecorded(&mut self, at: &Position, c: char) {
+        self.dirty = true;
+
+        if c == '\n' {
+            self.insert_newline(at);
+        } else if at.y as usize == self.rows.len() {
+            let mut row = Row::default();
+            row.insert(0, c);
+            self.rows.push(row);
+        } else if let Some(row) = self.rows.get_mut(at.y as usize) {
+            row.insert(at.x as usize, c);
+        }
+        self.unhighlight_from(at.y as usize);
+    }
+
+    fn insert_newline(&mut self, at: &Position) {
+        if at.y as usize >= self.rows.len() {
+            self.rows.push(Row::default());
+            return;
+        }
+        let new_row = self.rows[at.y as usize].split(at.x as usize);
+        self.rows.insert(at.y.saturating_add(1) as usize, new_row);
+    }
+
+    pub fn delete(&mut self, at: &Position) {
+        if at.y as usize >= self.rows.len() {
+            return;
+        }
+        let y = at.y as usize;
+        let joins = at.x as usize >= self.rows[y].len && y < self.rows.len().saturating_sub(1);
+        let last = if joins { y.saturating_add(1) } else { y };
+        self.edit_lines(y, last, |document| document.delete_unrecorded(at));
+    }
+
+    fn delete_unrecorded(&mut self, at: &Position) {
+        let len = self.rows.len();
+        self.dirty = true;
+
+        if at.x as usize >= self.rows[at.y as usize].len && (at.y as usize) < len.saturating_sub(1) {
+            // deleting past the end of a row joins it with the next one
+            let next_row = self.rows.remove(at.y.saturating_add(1) as usize);
+            self.rows[at.y as usize].append(&next_row);
+        } else {
+            self.rows[at.y as usize].delete(at.x as usize);
+        }
+        self.unhighlight_from(at.y as usize);
+    }
+
+    // text between two positions, 'end' being exclusive. lines are joined with '\n'
+    pub fn text_range(&self, start: &Position, end: &Position) -> String {
+        let (start, end) = self.clamp_range(start, end);
+        let mut text = String::new();
+
+        for y in start.y..=end.y {
+            if let Some(row) = self.rows.get(y as usize) {
+                let from = if y == start.y { start.x as usize } else { 0 };
+                let to = if y == end.y { end.x as usize } else { row.len };
+                text.push_str(row.substring(from, to));
+                if y != end.y {
+                    text.push('\n');
+                }
+            }
+        }
+
+        text
+    }
+
+    // removes the text between two positions ('end' being exclusive) and returns it
+    pub fn delete_range(&mut self, start: &Position, end: &Position) -> String {
+        if self.rows.is_empty() {
+            return String::new();
+        }
+        let (start, end) = self.clamp_range(start, end);
+        let removed = self.text_range(&start, &end);
+        if removed.is_empty() {
+            return removed;
+        }
+
+        self.edit_lines(start.y as usize, end.y as usize, |document| document.delete_range_unrecorded(&start, &end));
+
+        removed
+    }
+
+    fn delete_range_unrecorded(&mut self, start: &Position, end: &Position) {
+        if start.y == end.y {
+            self.rows[start.y as usize].delete_range(start.x as usize, end.x as usize);
+        } else {
+            let tail = self.rows[end.y as usize].split(end.x as usize);
+            self.rows[start.y as usize].split(start.x as usize);
+            self.rows[start.y as usize].append(&tail);
+            self.rows.drain(start.y.saturating_add(1) as usize..=end.y as usize);
+        }
+        self.dirty = true;
+        self.unhighlight_from(start.y as usize);
+    }
+
+    // inserts (possibly multi-line) text and returns the position right after it
+    pub fn insert_str(&mut self, at: &Position, text: &str) -> Position {
+        if at.y as usize > self.rows.len() || text.is_empty() {
+            return *at;
+        }
+
+        self.edit_lines(at.y as usize, at.y as usize, |document| document.insert_str_unrecorded(at, text))
+    }
+
+    fn insert_str_unrecorded(&mut self, at: &Position, text: &str) -> Position {
+        if at.y as usize == self.rows.len() {
+            self.rows.push(Row::default());
+        }
+        self.dirty = true;
+
+        let y = at.y as usize;
+        let x = min(at.x as usize, self.rows[y].len);
+        let mut lines = text.split('\n');
+        let first = lines.next().unwrap_or("");
+        let rest: Vec<&str> = lines.collect();
+
+        let end = if rest.is_empty() {
+            let len_before = self.rows[y].len;
+            self.rows[y].insert_str(x, first);
+            Position { x: x.saturating_add(self.rows[y].len.saturating_sub(len_before)) as u16, y: y as u16 }
+        } else {
+            let tail = self.rows[y].split(x);
+            self.rows[y].insert_str(x, first);
+            let last_index = y.saturating_add(rest.len());
+            for (index, line) in rest.iter().enumerate() {
+                self.rows.insert(y.saturating_add(index).saturating_add(1), Row::from(*line));
+            }
+            let end_x = self.rows[last_index].len;
+            self.rows[last_index].append(&tail);
+            Position { x: end_x as u16, y: last_index as u16 }
+        };
+        self.unhighlight_from(y);
+
+        end
+    }
+
+    // the text of whole lines, each one terminated by '\n' (the way linewise registers hold it)
+    pub fn lines_text(&self, first: u16, last: u16) -> String {
+        let mut text = String::new();
+        for row in self.rows.iter().skip(first as usize).take(last.saturating_sub(first).saturating_add(1) as usize) {
+            text.push_str(&row.string);
+            text.push('\n');
+        }
+
+        text
+    }
+
+    pub fn delete_lines(&mut self, first: u16, last: u16) -> String {
+        if first as usize >= self.rows.len() {
+            return String::new();
+        }
+        let last = min(last as usize, self.rows.len().saturating_sub(1)) as u16;
+        let removed = self.lines_text(first, last);
+
+        if last as usize == self.rows.len().saturating_sub(1) && first > 0 {
+            // there is no line after the range, so the newline before it goes instead
+            let prev_len = self.rows[first.saturating_sub(1) as usize].len as u16;
+            self.delete_range(&Position { x: prev_len, y: first.saturating_sub(1) }, &Position { x: u16::MAX, y: last });
+        } else if last as usize == self.rows.len().saturating_sub(1) {
+            self.delete_range(&Position { x: 0, y: first }, &Position { x: u16::MAX, y: last });
+        } else {
+            self.delete_range(&Position { x: 0, y: first }, &Position { x: 0, y: last.saturating_add(1) });
+        }
+
+        removed
+    }
+
+    pub fn first_graph(&self, y: u16) -> u16 {
+        self.rows
+            .get(y as usize)
+            .and_then(|row| row.string.graphemes(true).position(|g| !g.chars().all(char::is_whitespace)))
+            .unwrap_or(0) as u16
+    }
+
+    fn clamp_range(&self, start: &Position, end: &Position) -> (Position, Position) {
+        let (mut start, mut end) = if (start.y, start.x) <= (end.y, end.x) { (*start, *end) } else { (*end, *start) };
+        let last = self.rows.len().saturating_sub(1) as u16;
+        for position in [&mut start, &mut end] {
+            if position.y > last {
+                position.y = last;
+                position.x = u16::MAX;
+            }
+            let len = self.rows.get(position.y as usize).map(|row| row.len).unwrap_or(0);
+            position.x = min(position.x as usize, len) as u16;
+        }
+
+        (start, end)
+    }
+
+    pub fn unhighlight_rows(&mut self) {
+        self.highlighted_rows = 0;
+        for row in self.rows.iter_mut() {
+            row.unhighlight();
+        }
+    }
+
+    // after an edit starting at row 'y'. the rows above it are as they were, and the ones below it are only
+    // highlighted again if the comment/quote streak reaching them changed
+    pub fn unhighlight_from(&mut self, y: usize) {
+        self.highlighted_rows = min(self.highlighted_rows, y);
+    }
+
     pub fn highlight(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         until: Option<u16>
     ) {
         // the goal is to append the highlighting option to the 'highlighting' field of all rows in the viewport
@@ -68,8 +505,413 @@ impl Document {
             _ => self.rows.len() as u16,
         };
 
-        for row in self.rows.iter_mut().take(end as usize) {
-            row.highlight(options, word, &mut self.hl_streak);
+        // the streak carries across rows, so highlighting picks up where the rows that are up to date end
+        let first = min(self.highlighted_rows, end as usize);
+        let mut hl_streak = first.checked_sub(1).map(|y| self.rows[y].hl_end.clone()).unwrap_or_default();
+        for row in self.rows.iter_mut().take(end as usize).skip(first) {
+            row.highlight(options, word, &mut hl_streak);
         }
+        self.highlighted_rows = max(self.highlighted_rows, end as usize);
+    }
+}
+
+// lines in order, each one moving up or down as lines are added or removed before it.
+// it is made to be emptied from the front while the edits happen near there, so it keeps an offset for all of the
+// lines and only goes through the ones on the shorter side of an edit
+#[derive(Debug, Default)]
+pub struct LineMarks {
+    lines:                  VecDeque<isize>, // each one less 'offset'
+    offset:                 isize,
+}
+
+impl LineMarks {
+    pub fn new(lines: Vec<usize>) -> Self {
+        Self { lines: lines.into_iter().map(|line| line as isize).collect(), offset: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn pop_first(&mut self) -> Option<usize> {
+        self.lines.pop_front().map(|line| (line + self.offset) as usize)
+    }
+
+    // where 'line' is after the lines 'start'..'old_end' became 'start'..'new_end'. the changed lines keep their places
+    // as far as there are as many of them, and None is for the ones that went
+    pub fn moved(line: usize, start: usize, old_end: usize, new_end: usize) -> Option<usize> {
+        if line < start {
+            Some(line)
+        } else if line >= old_end {
+            Some((line + new_end).saturating_sub(old_end))
+        } else if line < new_end {
+            Some(line)
+        } else {
+            None
+        }
+    }
+
+    pub fn shift(&mut self, start: usize, old_end: usize, new_end: usize) {
+        let offset = self.offset;
+        let index = |lines: &VecDeque<isize>, end: usize| lines.partition_point(|line| line + offset < end as isize);
+        let gone = index(&self.lines, min(old_end, new_end).max(start))..index(&self.lines, old_end);
+        let after = gone.start;
+        self.lines.drain(gone);
+
+        let delta = new_end as isize - old_end as isize;
+        if delta == 0 {
+            return;
+        }
+        if after < self.lines.len() - after {
+            self.offset += delta;
+            self.lines.range_mut(..after).for_each(|line| *line -= delta);
+        } else {
+            self.lines.range_mut(after..).for_each(|line| *line += delta);
+        }
+    }
+}
+
+// writes go to a synced temp file next to the target which then replaces it in one rename,
+// so a failure at any point leaves the original file untouched
+pub fn write_atomically(path: &Path, data: &[u8], backup: bool) -> Result<(), Error> {
+    let path = match fs::canonicalize(path) { // write through symlinks instead of replacing them
+        Ok(real_path) => real_path,
+        Err(_) => path.to_path_buf(),
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().ok_or(Error::new(ErrorKind::InvalidInput, "E32: No file name"))?;
+    let metadata = fs::metadata(&path).ok();
+
+    if backup && metadata.is_some() {
+        let mut backup_name = file_name.to_os_string();
+        backup_name.push("~");
+        fs::copy(&path, directory.join(backup_name))?;
+    }
+
+    let hidden_path = |suffix: &str| {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(file_name);
+        name.push(format!(".termite-{}.{}", std::process::id(), suffix));
+        directory.join(name)
+    };
+    let temp_path = hidden_path("tmp");
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        });
+    if let Err(error) = written {
+        fs::remove_file(&temp_path).ok();
+        return Err(error);
+    }
+
+    if let Some(metadata) = &metadata {
+        let preserved = fs::set_permissions(&temp_path, metadata.permissions())
+            .and_then(|_| chown(&temp_path, Some(metadata.uid()), Some(metadata.gid())));
+
+        if preserved.is_err() {
+            // we can't hand the file back to its owner through a rename, so it gets overwritten in place
+            fs::remove_file(&temp_path).ok();
+            return overwrite_in_place(&path, &hidden_path("orig"), |file| file.write_all(data));
+        }
+    }
+
+    if let Err(error) = fs::rename(&temp_path, &path) {
+        fs::remove_file(&temp_path).ok();
+        return Err(error);
+    }
+    if let Ok(directory) = File::open(&directory) {
+        directory.sync_all().ok(); // persist the rename itself
+    }
+
+    Ok(())
+}
+
+// the file is copied aside first and put back from the copy if the write fails. if even that fails, the copy stays
+// where it is and the error says where to find it
+fn overwrite_in_place(path: &Path, copy: &Path, write: impl FnOnce(&mut File) -> Result<(), Error>) -> Result<(), Error> {
+    fs::copy(path, copy)?;
+    let written = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        });
+
+    match written {
+        Ok(()) => {
+            fs::remove_file(copy).ok();
+            Ok(())
+        },
+        Err(error) if fs::copy(copy, path).is_ok() => {
+            fs::remove_file(copy).ok();
+            Err(error)
+        },
+        Err(error) => Err(Error::new(error.kind(), format!("{} (the original is in {})", error, copy.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keeps the tests from writing undo files into the real state directory
+    fn test_document() -> Document {
+        Document { undofile: false, ..Document::default() }
+    }
+
+    #[test]
+    fn test_save_keeps_line_endings() {
+        let path = env::temp_dir().join(format!("termite_save_{}.txt", std::process::id()));
+        let mut document = test_document();
+        document.populate(vec!["fn main() {", "}"]);
+        document.line_ending = "\r\n";
+        document.trailing_newline = false;
+        document.dirty = true;
+
+        let written = document.save_as(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {\r\n}");
+        assert_eq!(written, 14);
+        assert!(!document.dirty);
+        assert_eq!(document.file_name, path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_replaces_file_and_keeps_backup() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("termite_backup_{}.txt", std::process::id()));
+        let backup_path = env::temp_dir().join(format!("termite_backup_{}.txt~", std::process::id()));
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut document = test_document();
+        document.populate(vec!["new"]);
+        document.file_name = path.to_str().unwrap().to_string();
+        document.backup = true;
+        document.save().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(fs::read_to_string(&backup_path).unwrap(), "old\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup_path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_save_keeps_original() {
+        let mut document = test_document();
+        document.populate(vec!["new"]);
+        document.file_name = env::temp_dir().join("termite_missing_dir").join("file.txt").to_str().unwrap().to_string();
+
+        assert!(document.save().is_err());
+        assert!(!Path::new(&document.file_name).exists());
+
+        // a name this long leaves no room for the temp file next to it, so the save fails with the file in place
+        let name = format!("termite_{}_", std::process::id());
+        let path = env::temp_dir().join(format!("{}{}", name, "x".repeat(250 - name.len())));
+        fs::write(&path, "original\n").unwrap();
+        document.file_name = path.to_str().unwrap().to_string();
+        document.backup = false;
+        assert!(document.save().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original\n");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_failed_overwrite_in_place_restores_original() {
+        let dir = env::temp_dir().join(format!("termite_in_place_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (path, copy) = (dir.join("file.txt"), dir.join(".file.txt.orig"));
+        fs::write(&path, "original\n").unwrap();
+
+        let result = overwrite_in_place(&path, &copy, |file| {
+            file.write_all(b"half of the new")?;
+            Err(Error::other("disk full"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "disk full");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original\n");
+        assert!(!copy.exists());
+
+        overwrite_in_place(&path, &copy, |file| file.write_all(b"new\n")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!copy.exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_delete_and_insert_ranges() {
+        let mut document = test_document();
+        document.populate(vec!["one two", "three", "four five"]);
+
+        let removed = document.delete_range(&Position { x: 4, y: 0 }, &Position { x: 5, y: 2 });
+        assert_eq!(removed, "two\nthree\nfour ");
+        assert_eq!(document.contents(), "one five\n");
+        assert!(document.dirty);
+
+        let end = document.insert_str(&Position { x: 4, y: 0 }, "two\nthree\nfour ");
+        assert_eq!(end.x, 5);
+        assert_eq!(end.y, 2);
+        assert_eq!(document.contents(), "one two\nthree\nfour five\n");
+    }
+
+    #[test]
+    fn test_delete_lines() {
+        let mut document = test_document();
+        document.populate(vec!["a", "b", "c", "d"]);
+
+        assert_eq!(document.delete_lines(1, 2), "b\nc\n");
+        assert_eq!(document.contents(), "a\nd\n");
+
+        assert_eq!(document.delete_lines(1, 5), "d\n");
+        assert_eq!(document.contents(), "a\n");
+
+        document.delete_lines(0, 0);
+        assert_eq!(document.rows.len(), 1);
+        assert_eq!(document.rows[0].string, "");
+    }
+
+    #[test]
+    fn test_highlight_after_edits() {
+        let mut document = test_document();
+        document.populate(vec!["let a = 1;", "let b = 2;", "let c = 3;", "let d = 4;"]);
+        let options = HighlightingOptions::default();
+        document.highlight(&options, &None, Some(3));
+        assert_eq!(document.highlighted_rows, 3);
+        document.highlight(&options, &None, None);
+        assert_eq!(document.highlighted_rows, 4);
+
+        // only the edited row and what is below it need looking at again
+        document.insert(&Position { x: 0, y: 2 }, 'x');
+        assert_eq!(document.highlighted_rows, 2);
+        assert!(document.rows[1].is_highlighted && !document.rows[2].is_highlighted);
+
+        // a comment opened above reaches the rows below, which get highlighted again for it
+        let closed = document.rows[3].hl_start.clone();
+        document.insert_str(&Position { x: 0, y: 1 }, "/* ");
+        document.highlight(&options, &None, None);
+        assert!(document.rows[3].hl_start != closed);
+        assert!(document.rows.iter().all(|row| row.is_highlighted));
+    }
+
+    #[test]
+    fn test_line_marks() {
+        let mut marks = LineMarks::new(vec![1, 3, 5, 8]);
+        assert_eq!(marks.pop_first(), Some(1));
+
+        // two lines added at 2, then lines 4 and 5 (the one marked 3 before) replaced by a single one
+        marks.shift(2, 2, 4);
+        marks.shift(4, 6, 5);
+        assert_eq!(marks.len(), 2);
+        assert_eq!(marks.pop_first(), Some(6));
+        assert_eq!(marks.pop_first(), Some(9));
+        assert_eq!(marks.pop_first(), None);
+
+        assert_eq!(LineMarks::moved(2, 3, 5, 4), Some(2));
+        assert_eq!(LineMarks::moved(3, 3, 5, 4), Some(3));
+        assert_eq!(LineMarks::moved(4, 3, 5, 4), None);
+        assert_eq!(LineMarks::moved(7, 3, 5, 4), Some(6));
+    }
+
+    #[test]
+    fn test_save_without_file_name() {
+        let mut document = test_document();
+        document.populate(vec!["hello"]);
+
+        assert!(document.save().is_err());
+        assert_eq!(document.contents(), "hello\n");
+    }
+
+    #[test]
+    fn test_undo_redo_and_branches() {
+        let mut document = test_document();
+        document.populate(vec!["one", "two"]);
+
+        document.undo.mark_cursor(Position { x: 1, y: 0 });
+        document.insert(&Position { x: 3, y: 0 }, '!');
+        document.insert(&Position { x: 4, y: 0 }, '\n');
+        document.undo.commit();
+        document.delete_lines(1, 2);
+        document.undo.commit();
+        assert_eq!(document.contents(), "one!\n");
+
+        assert_eq!(document.undo().map(|cursor| cursor.x), Some(0));
+        assert_eq!(document.contents(), "one!\n\ntwo\n");
+        let cursor = document.undo().unwrap();
+        assert_eq!((cursor.x, cursor.y), (1, 0));
+        assert_eq!(document.contents(), "one\ntwo\n");
+        assert!(!document.dirty);
+        assert!(document.undo().is_none());
+
+        // a change after undoing starts a new branch; g- style moves still reach the old one
+        document.insert_str(&Position { x: 0, y: 1 }, "tw");
+        document.undo.commit();
+        assert_eq!(document.contents(), "one\ntwtwo\n");
+        assert!(document.redo().is_none());
+
+        document.undo_to(2);
+        assert_eq!(document.contents(), "one!\n");
+        document.undo_to(3);
+        assert_eq!(document.contents(), "one\ntwtwo\n");
+        document.undo_to(1);
+        assert_eq!(document.contents(), "one!\n\ntwo\n");
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = env::temp_dir().join(format!("termite_new_{}.txt", std::process::id()));
+        let mut document = test_document();
+        document.load_file(path.to_str().unwrap()).unwrap();
+        assert!(document.rows.is_empty());
+        assert_eq!(document.file_name, path.to_str().unwrap());
+
+        document.insert_str(&Position::default(), "new");
+        document.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        fs::remove_file(&path).unwrap();
+
+        // anything else that keeps the file from being read is an error, and leaves the document alone
+        let mut document = test_document();
+        assert!(document.load_file(env::temp_dir().to_str().unwrap()).is_err());
+        assert!(document.file_name.is_empty());
+    }
+
+    #[test]
+    fn test_undo_history_survives_reload() {
+        let path = env::temp_dir().join(format!("termite_undofile_{}.txt", std::process::id()));
+        let undo_dir = env::temp_dir().join(format!("termite_undo_{}", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+
+        let mut document = Document { undo_dir: Some(undo_dir.clone()), ..Document::default() };
+        document.load_file(path.to_str().unwrap()).unwrap();
+        document.insert_str(&Position { x: 5, y: 0 }, " edit");
+        document.save().unwrap();
+
+        let mut reloaded = Document { undo_dir: Some(undo_dir.clone()), ..Document::default() };
+        reloaded.load_file(path.to_str().unwrap()).unwrap();
+        assert!(reloaded.undo().is_some());
+        assert_eq!(reloaded.contents(), "first\n");
+        assert!(reloaded.dirty);
+
+        // once the file changes behind our back, the old history no longer applies
+        fs::write(&path, "changed\n").unwrap();
+        let mut changed = Document { undo_dir: Some(undo_dir.clone()), ..Document::default() };
+        changed.load_file(path.to_str().unwrap()).unwrap();
+        assert!(changed.undo().is_none());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&undo_dir).unwrap();
     }
 }
@@ -1,5 +1,6 @@
 use std::cell::RefCell;
-use std::cmp::max;
+use std::collections::VecDeque;
+use std::cmp::{max, min};
 use std::future::Future;
 use std::io;
 use std::io::{stdin, stdout, ErrorKind, Read, Stdout, Write};
@@ -12,29 +13,52 @@ use std::iter::{Iterator, Skip};
 use std::rc::{Rc};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::time::Duration;
 use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSlice};
 use once_cell::sync::OnceCell;
 
 use crate::automata::{EditorFSM, EditorState};
-use crate::config::{DEFAULT_QUIT_TIMES, EDITOR_NAME, PACKAGE_VERSION};
+use crate::config::{DEFAULT_IGNORECASE, DEFAULT_QUIT_TIMES, DEFAULT_SMARTCASE, EDITOR_NAME, PACKAGE_VERSION, STATUS_MESSAGE_TIMEOUT};
 use crate::document::Document;
+use crate::ex::{self, split_delimited, Registry};
+use crate::history::{default_history_file, History};
+use crate::input::{KeySource, StdinKeys};
 use crate::log;
+use crate::regex::{escape, expand, Regex};
+use crate::registers::{Register, Registers};
+use crate::clipboard::provider_from_option;
 use crate::row::Row;
-use crate::terminal::Terminal;
-use crate::utils::{die, HighlightingOptions, MovementData, Position, Size, StatusMessage, TerminalMode, ScrollDirection, Selection, Promptable, SearchDirection, Coordinate, find_string_position, GraphemeIter};
+use crate::terminal::{Color, Terminal, TerminalBackend};
+use crate::utils::{die, HighlightingOptions, MovementData, Position, Size, StatusMessage, TerminalMode, ScrollDirection, Selection, Promptable, PromptCallbackCode, SearchDirection, Coordinate, find_string_position, InsertKind, InsertSession, Operator, RangeKind, keys_to_string, string_to_keys, get_v_char_class, is_word, VCharacterClass};
+
+// the options ':set' knows, and whether each one is switched on and off
+pub const OPTION_NAMES: &[(&str, bool)] = &[("backup", true), ("clipboard", false), ("ignorecase", true), ("smartcase", true), ("undofile", true)];
 
 pub struct Editor {
     pub should_quit:                bool,
-    pub terminal:                   Terminal,
+    pub terminal:                   Box<dyn TerminalBackend>,
     pub cursor_position:            Position,
     pub offset:                     Position,
     pub document:                   Document,
     pub status_message:             Option<StatusMessage>,
     pub quit_times:                 u8,
-    pub highlighted_word:           Option<String>,
+    pub highlighted_word:           Option<Regex>,
+    pub last_search:                Option<(String, SearchDirection)>,
+    pub ignorecase:                 bool,
+    pub smartcase:                  bool,
     pub mode:                       TerminalMode,
     pub movement_data:              MovementData,
     pub selection:                  Option<Selection>,
+    pub last_selection:             Option<(Selection, Position)>,
+    pub ex_commands:                Registry,
+    pub history:                    History,
+    pub insert_session:             Option<InsertSession>,
+    pub key_source:                 Box<dyn KeySource>,
+    pub pending_keys:               VecDeque<Key>,
+    pub normal_floor:               Option<usize>, // while ':normal' runs, how many of the queued keys aren't its own
+    pub recording:                  Option<(char, Vec<Key>)>,
+    pub last_macro:                 Option<char>,
+    pub registers:                  Registers,
     pub net_height:                 u16
 }
 
@@ -48,38 +72,89 @@ impl Default for Editor {
             quit_times: DEFAULT_QUIT_TIMES,
             document: Document::default(),
             highlighted_word: None,
-            terminal: Terminal::default(),
+            last_search: None,
+            ignorecase: DEFAULT_IGNORECASE,
+            smartcase: DEFAULT_SMARTCASE,
+            terminal: Box::new(Terminal::default()),
             should_quit: false,
             status_message: None,
             mode: TerminalMode::Normal,
             movement_data: MovementData::default(),
             selection: None,
+            last_selection: None,
+            ex_commands: Registry::default(),
+            history: History::default(),
+            insert_session: None,
+            registers: Registers::default(),
+            key_source: Box::new(StdinKeys::default()),
+            pending_keys: VecDeque::new(),
+            normal_floor: None,
+            recording: None,
+            last_macro: None,
             net_height: 0
         }
     }
 }
 
 impl Promptable for Editor {
-    fn on_prompt_loop_start(&mut self, result: &str) -> Result<(), std::io::Error> {
-        self.draw_message_bar(Some(&result));
+    fn on_prompt_loop_start(&mut self, result: &str, cursor: usize) -> Result<(), std::io::Error> {
+        self.draw_prompt(result, cursor)?;
         self.terminal.flush()?;
 
         Ok(())
     }
+
+    fn next_key(&mut self) -> Option<Result<Key, std::io::Error>> {
+        // ':normal' ends whatever its keys leave unfinished, instead of waiting for more
+        if self.normal_floor.is_some_and(|floor| self.pending_keys.len() <= floor) {
+            return Some(Ok(Key::Esc));
+        }
+        if let Some(key) = self.pending_keys.pop_front() {
+            return Some(Ok(key));
+        }
+
+        // only typed keys go into a macro; whatever '.' or '@' replays is recorded as the '.' or '@' itself
+        let key = self.key_source.next_key();
+        match (key.as_ref(), self.recording.as_mut()) {
+            (Some(Ok(key)), Some((_, keys))) => keys.push(*key),
+            // the input is closed, so there is nothing left to do
+            (None, _) => self.should_quit = true,
+            _ => (),
+        }
+
+        key
+    }
+
+    fn history(&self, prompt: &str) -> Vec<String> {
+        self.history.entries(prompt).to_vec()
+    }
+
+    fn add_history(&mut self, prompt: &str, line: &str) {
+        self.history.add(prompt, line);
+    }
+
+    fn complete(&self, prompt: &str, text: &str) -> Option<(usize, Vec<String>)> {
+        if prompt == ":" { ex::complete(self, text) } else { None }
+    }
 }
 
 impl Editor {
     pub async fn run(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
-        let mut terminal = self.terminal.get_std_buffer();
         let Size { width, height } = self.terminal.get_size();
         self.net_height = height;
-        self.document.load();
+        if let Err(error) = self.document.load() {
+            self.status_message = Some(StatusMessage::from(error.to_string()));
+        }
+        self.history = History::load(default_history_file());
 
         loop {
-            match self.refresh_screen() {
-                Ok(res) => {}
-                Err(error) => die(error),
-            };
+            // no point drawing every step of a macro
+            if self.pending_keys.is_empty() {
+                match self.refresh_screen() {
+                    Ok(res) => {}
+                    Err(error) => die(error),
+                };
+            }
 
             if (self.should_quit) {
                 // print goodbye message and cleanup
@@ -87,7 +162,7 @@ impl Editor {
                 return Ok(());
             }
 
-            match self.process_keys(fsm).await {
+            match self.process_keys(fsm) {
                 Ok(res) => {}
                 Err(error) => die(error),
             }
@@ -133,19 +208,19 @@ impl Editor {
         Ok(())
     }
 
-    pub fn display_welcome_message(&self) {
+    pub fn display_welcome_message(&mut self) {
         let Size { width, height } = self.terminal.get_size();
         let welcome_message: String = "".to_owned() + EDITOR_NAME + ". v" + PACKAGE_VERSION;
         let message_len = welcome_message.len();
-        let width_diff = width - message_len as u16;
+        let width_diff = width.saturating_sub(message_len as u16);
         let pad_len = width_diff / 2;
         let mut l_pad = " ".repeat(pad_len as usize);
         l_pad.truncate(pad_len.saturating_sub(1) as usize);
         let r_pad = " ".repeat(pad_len as usize);
-        print!("~{}{}{}\n\r", l_pad, welcome_message, r_pad);
+        self.terminal.print(&format!("~{}{}{}\n\r", l_pad, welcome_message, r_pad));
     }
 
-    pub fn move_cursor(&self, pos: Position) -> Result<(), io::Error> {
+    pub fn move_cursor(&mut self, pos: Position) -> Result<(), io::Error> {
         let Position { x, y } = pos;
 
         self.terminal.goto(Position { x, y });
@@ -153,33 +228,110 @@ impl Editor {
         Ok(())
     }
 
-    pub async fn process_keys(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
-        if self.mode == TerminalMode::Normal {
-            self.process_normal_mode(fsm).await?;
+    pub fn process_keys(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
+        match self.mode {
+            TerminalMode::Normal => self.process_normal_mode(fsm)?,
+            TerminalMode::Insert => self.process_insert_mode(fsm)?,
         }
 
         Ok(())
     }
 
-    pub async fn process_normal_mode(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
+    // feeds keys through the editor as if they were typed, ahead of anything already queued
+    pub fn replay_keys(&mut self, fsm: &mut EditorFSM, keys: Vec<Key>) {
+        let floor = self.pending_keys.len();
+        for key in keys.into_iter().rev() {
+            self.pending_keys.push_front(key);
+        }
+
+        while self.pending_keys.len() > floor {
+            if let Err(error) = self.process_keys(fsm) {
+                self.status_message = Some(StatusMessage::from(error.to_string()));
+                break;
+            }
+        }
+    }
+
+    pub fn start_recording(&mut self, register: char) {
+        self.recording = Some((register, vec![]));
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some((register, mut keys)) = self.recording.take() {
+            keys.pop(); // the 'q' that stopped the recording
+            self.store_register(Some(register), Operator::Yank, Register { text: keys_to_string(&keys), kind: RangeKind::Charwise });
+        }
+    }
+
+    // '@x': queues the keys held in register 'x', 'count' times. '@@' runs the last one again
+    pub fn execute_macro(&mut self, register: char, count: usize) {
+        let register = if register == '@' { self.last_macro } else { Some(register) };
+        let register = match register {
+            Some(register) => register,
+            None => {
+                self.status_message = Some(StatusMessage::from("E748: No previously used register".to_string()));
+                return;
+            }
+        };
+        let keys = match self.registers.get(Some(register)) {
+            Some(contents) => string_to_keys(&contents.text),
+            None => return,
+        };
+        self.last_macro = Some(register);
+
+        // queued ahead of the rest, so a macro that runs another macro (or itself) carries on where it left off
+        for _ in 0..max(count, 1) {
+            for key in keys.iter().rev() {
+                self.pending_keys.push_front(*key);
+            }
+        }
+    }
+
+    // a failing command ends the macro (or '.') being replayed, and everything that was queued after it.
+    // inside ':normal' it only ends the keys for the current line
+    pub fn abort_replay(&mut self) {
+        let floor = self.normal_floor.unwrap_or(0);
+        while self.pending_keys.len() > floor {
+            self.pending_keys.pop_front();
+        }
+    }
+
+    pub fn process_normal_mode(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
         let Size { height, .. } = self.terminal.get_size();
 
-        if let Some(key) = stdin().keys().next() {
-            match key? {
+        if let Some(key) = self.next_key() {
+            let key = key?;
+            if key != Key::Ctrl('q') {
+                self.quit_times = DEFAULT_QUIT_TIMES;
+            }
+            self.document.undo.mark_cursor(self.cursor_position);
+
+            match key {
                 Key::Char(':') => {
-                    if let Some(command) = self.prompt(|editor, key|  {}, None)? {
-                        if command == "q" || command == "q!" { self.should_quit = true; }
-                        log!("{}", command);
+                    if let Some(command) = self.prompt(|editor, key, text| {}, Some(":".to_string()))? {
+                        ex::execute(self, fsm, &command);
                     }
                 },
-                Key::Char(x) => {
-                    if x == 'i' {
-                        log!("insert mode");
-                        return Ok(());
-                    } else { fsm.run(&x, self); }
+                Key::Ctrl('q') => {
+                    self.quit_times = self.quit_times.saturating_sub(1);
+                    if self.document.dirty && self.quit_times > 0 {
+                        self.status_message = Some(StatusMessage::from(format!(
+                            "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                            self.quit_times
+                        )));
+                    } else {
+                        self.should_quit = true;
+                    }
                 },
-                Key::Up | Key::Down | Key::Left | Key::Right => {},
-                _ => print!("random key pressed!"),
+                Key::Ctrl('r') => self.redo(1),
+                Key::Ctrl('v') => fsm.run(&'\x16', self),
+                Key::Char(x) => fsm.run(&x, self),
+                // arrows, F-keys and anything else unmapped do nothing
+                _ => {},
+            }
+            // a command that went into insert mode keeps its step open until <Esc>, and ':normal' keeps it open until it is done
+            if self.mode == TerminalMode::Normal && self.normal_floor.is_none() {
+                self.document.undo.commit();
             }
         }
         self.update_selection();
@@ -188,63 +340,598 @@ impl Editor {
         Ok(())
     }
 
-    pub fn process_insert_mode(&mut self) -> Result<(), io::Error> {
+    // puts the cursor on the first non-blank of a line, counting from 1
+    pub fn go_to_line(&mut self, line: usize) {
+        let y = min(line, self.document.rows.len()).saturating_sub(1) as u16;
+        self.cursor_position = Position { x: self.document.first_graph(y), y };
+        self.movement_data.last_nav_position.x = self.cursor_position.x;
+    }
+
+    // ':s/pattern/replacement/flags' on lines 'first' to 'last'. the flags are g (every match on a line, not just the first),
+    // c (ask about each one), i and I (ignore case or not), n (only count the matches) and e (no error when there are none)
+    pub fn substitute(&mut self, first: usize, last: usize, args: &str) -> Result<(), String> {
+        let delimiter = match args.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|".contains(c) => c,
+            Some(_) => return Err("E146: Regular expressions can't be delimited by letters".to_string()),
+            None => return Err("E35: No previous regular expression".to_string()),
+        };
+        let (pattern, rest) = split_delimited(&args[delimiter.len_utf8()..], delimiter);
+        let (replacement, flags) = match rest {
+            Some(rest) => split_delimited(rest, delimiter),
+            None => (String::new(), None),
+        };
+
+        let (mut global, mut confirm, mut count_only, mut quiet) = (false, false, false, false);
+        let (mut ignorecase, mut smartcase) = (self.ignorecase, self.smartcase);
+        let flags = flags.unwrap_or("").trim();
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'c' => confirm = true,
+                'n' => count_only = true,
+                'e' => quiet = true,
+                'i' => (ignorecase, smartcase) = (true, false),
+                'I' => (ignorecase, smartcase) = (false, false),
+                _ => return Err(format!("E488: Trailing characters: {}", flags)),
+            }
+        }
+
+        // an empty pattern is the last one searched for
+        let pattern = match (pattern.is_empty(), self.last_search.as_ref()) {
+            (false, _) => pattern,
+            (true, Some((last, _))) => last.clone(),
+            (true, None) => return Err("E35: No previous regular expression".to_string()),
+        };
+        let regex = Regex::new(&pattern, ignorecase, smartcase)?;
+        self.last_search = Some((pattern.clone(), SearchDirection::Forward));
+        self.set_highlighted_word(Some(regex.clone()));
+
+        let origin = self.cursor_position;
+        let (mut found, mut substitutions, mut lines, mut added) = (false, 0, 0, 0);
+        let mut last_changed = None;
+        let mut stop = false;
+        for y in first.saturating_sub(1)..last {
+            // lines broken up by earlier replacements push the rest down
+            let y = y.saturating_add(added);
+            let original = match self.document.rows.get(y) {
+                Some(row) => row.string.clone(),
+                None => break,
+            };
+            let graphemes: Vec<&str> = original.graphemes(true).collect();
+
+            let (mut output, mut done, mut from, mut count) = (String::new(), 0, 0, 0);
+            let (mut previous_end, mut previewed) = (None, false);
+            while let Some(captures) = regex.captures_from(&graphemes, from) {
+                let (start, end) = captures[0].unwrap_or((from, from));
+                from = if end > start { end } else { start.saturating_add(1) };
+                // an empty match right where the last one ended doesn't count
+                if start == end && previous_end == Some(start) {
+                    continue;
+                }
+                previous_end = Some(end);
+                found = true;
+
+                let mut answer = 'y';
+                if confirm && !count_only {
+                    let preview = format!("{}{}", output, graphemes[done..].concat());
+                    let column = output.rsplit('\n').next().unwrap_or("").graphemes(true).count().saturating_add(start.saturating_sub(done));
+                    answer = self.confirm_substitution(y, &preview, column, &replacement);
+                    previewed = true;
+                }
+                if answer == 'q' {
+                    stop = true;
+                    break;
+                }
+                if answer != 'n' {
+                    output.push_str(&graphemes[done..start].concat());
+                    output.push_str(&expand(&replacement, &captures, &graphemes));
+                    done = end;
+                    count += 1;
+                }
+                match answer {
+                    'a' => confirm = false,
+                    'l' => stop = true,
+                    _ => (),
+                }
+                if stop || !global {
+                    break;
+                }
+            }
+
+            if previewed {
+                // the previews went straight into the row, so it is put back before the change is recorded
+                self.document.rows[y] = Row::from(original.as_str());
+                self.document.unhighlight_from(y);
+            }
+            if count > 0 {
+                substitutions += count;
+                lines += 1;
+                if !count_only {
+                    output.push_str(&graphemes[done..].concat());
+                    let new_lines: Vec<String> = output.split('\n').map(String::from).collect();
+                    self.document.splice_lines(y, 1, &new_lines);
+                    added += new_lines.len().saturating_sub(1);
+                    last_changed = Some(y.saturating_add(new_lines.len().saturating_sub(1)));
+                }
+            }
+            if stop {
+                break;
+            }
+        }
+
+        self.cursor_position = origin;
+        if let Some(y) = last_changed {
+            self.go_to_line(y.saturating_add(1));
+        }
+        match (found, quiet) {
+            (false, false) => return Err(format!("E486: Pattern not found: {}", pattern)),
+            (false, true) => return Ok(()),
+            _ => (),
+        }
+
+        let plural = |count: usize, word: &str, words: &str| format!("{} {}", count, if count == 1 { word } else { words });
+        self.status_message = Some(StatusMessage::from(if count_only {
+            format!("{} on {}", plural(substitutions, "match", "matches"), plural(lines, "line", "lines"))
+        } else {
+            format!("{} on {}", plural(substitutions, "substitution", "substitutions"), plural(lines, "line", "lines"))
+        }));
+
         Ok(())
     }
 
-    pub fn draw_rows(&self) {
+    // shows a ':s///c' match on a line that has had the replacements so far made to it, and asks what to do with it:
+    // y (replace it), l (replace it and stop), n (skip it), a (replace it and all the rest) or q (stop)
+    fn confirm_substitution(&mut self, y: usize, preview: &str, column: usize, replacement: &str) -> char {
+        let preview = preview.rsplit('\n').next().unwrap_or("");
+        self.document.rows[y] = Row::from(preview);
+        self.document.unhighlight_from(y);
+        self.cursor_position = Position { x: column as u16, y: y as u16 };
+        self.scroll(ScrollDirection::None);
+        self.refresh_screen().ok();
+        self.draw_message_bar(Some(&format!("replace with {} (y/n/a/q/l)?", replacement)));
+        self.terminal.flush().ok();
+
+        let mut answer = 'q';
+        self.prompt_exec(|_, key| match key {
+            Key::Char(c) if "ynaql".contains(c) => {
+                answer = c;
+                PromptCallbackCode::Success
+            },
+            _ => PromptCallbackCode::Continue,
+        }, None).ok();
+
+        answer
+    }
+
+    pub fn set_option(&mut self, option: &str) {
+        match option {
+            "backup" | "bk" => self.document.backup = true,
+            "nobackup" | "nobk" => self.document.backup = false,
+            "backup?" | "bk?" => {
+                let value = if self.document.backup { "  backup" } else { "nobackup" };
+                self.status_message = Some(StatusMessage::from(value.to_string()));
+            },
+            "ignorecase" | "ic" => self.ignorecase = true,
+            "noignorecase" | "noic" => self.ignorecase = false,
+            "smartcase" | "scs" => self.smartcase = true,
+            "nosmartcase" | "noscs" => self.smartcase = false,
+            "undofile" | "udf" => self.document.undofile = true,
+            "noundofile" | "noudf" => self.document.undofile = false,
+            "clipboard?" | "cb?" => {
+                self.status_message = Some(StatusMessage::from(format!("  clipboard={}", self.registers.clipboard.name())));
+            },
+            _ if option.starts_with("clipboard=") || option.starts_with("cb=") => {
+                let value = option.split_once('=').map(|(_, value)| value).unwrap_or("");
+                self.registers.clipboard = provider_from_option(value);
+            },
+            _ => {
+                self.status_message = Some(StatusMessage::from(format!("E518: Unknown option: {}", option)));
+            }
+        }
+
+        // the highlighted matches follow the case options
+        if let Some(pattern) = self.highlighted_word.as_ref().map(|regex| regex.pattern.clone()) {
+            let regex = Regex::new(&pattern, self.ignorecase, self.smartcase).ok();
+            self.set_highlighted_word(regex);
+        }
+    }
+
+    pub fn save_document(&mut self, file_name: Option<&str>) -> bool {
+        let result = match file_name {
+            Some(file_name) => self.document.save_as(file_name),
+            None => self.document.save(),
+        };
+
+        match result {
+            Ok(bytes) => {
+                self.status_message = Some(StatusMessage::from(format!(
+                    "\"{}\" {}L, {}B written",
+                    self.document.file_name,
+                    self.document.rows.len(),
+                    bytes
+                )));
+                true
+            },
+            Err(error) => {
+                self.status_message = Some(StatusMessage::from(format!("\"{}\" {}", file_name.unwrap_or(&self.document.file_name), error)));
+                false
+            }
+        }
+    }
+
+    pub fn process_insert_mode(&mut self, fsm: &mut EditorFSM) -> Result<(), io::Error> {
+        if let Some(key) = self.next_key() {
+            match key? {
+                Key::Esc => {
+                    // what was typed becomes part of the change '.' repeats
+                    if let (Some(change), Some(session)) = (fsm.last_command.as_mut(), self.insert_session.as_ref()) {
+                        change.inserted = Some(session.text.clone());
+                    }
+                    self.finish_insert_session();
+                    if self.normal_floor.is_none() {
+                        self.document.undo.commit();
+                    }
+                    self.mode = TerminalMode::Normal;
+                    // like vim, leaving insert mode puts the cursor back on the last inserted character
+                    self.cursor_position.x = self.cursor_position.x.saturating_sub(1);
+                    self.movement_data.last_nav_position.x = self.cursor_position.x;
+                },
+                Key::Char(c) => {
+                    self.insert_char(c);
+                    if let Some(session) = self.insert_session.as_mut() {
+                        session.text.push(c);
+                    }
+                },
+                Key::Backspace => {
+                    if self.cursor_position.x > 0 {
+                        self.cursor_position.x = self.cursor_position.x.saturating_sub(1);
+                        self.document.delete(&self.cursor_position);
+                    } else if self.cursor_position.y > 0 {
+                        self.cursor_position.y = self.cursor_position.y.saturating_sub(1);
+                        self.cursor_position.x = self.current_row_len() as u16;
+                        self.document.delete(&self.cursor_position);
+                    }
+                    self.movement_data.last_nav_position.x = self.cursor_position.x;
+                    if let Some(session) = self.insert_session.as_mut() {
+                        session.text.pop();
+                    }
+                },
+                Key::Delete => {
+                    self.document.delete(&self.cursor_position);
+                },
+                Key::Left => {
+                    self.cursor_position.x = self.cursor_position.x.saturating_sub(1);
+                    self.movement_data.last_nav_position.x = self.cursor_position.x;
+                    self.break_insert_session();
+                },
+                Key::Right => {
+                    if (self.cursor_position.x as usize) < self.current_row_len() {
+                        self.cursor_position.x = self.cursor_position.x.saturating_add(1);
+                    }
+                    self.movement_data.last_nav_position.x = self.cursor_position.x;
+                    self.break_insert_session();
+                },
+                Key::Up => {
+                    if self.cursor_position.y > 0 {
+                        self.cursor_position.y = self.cursor_position.y.saturating_sub(1);
+                        self.cursor_position.x = min(self.movement_data.last_nav_position.x, self.current_row_len() as u16);
+                    }
+                    self.break_insert_session();
+                },
+                Key::Down => {
+                    if (self.cursor_position.y as usize) < self.document.rows.len().saturating_sub(1) {
+                        self.cursor_position.y = self.cursor_position.y.saturating_add(1);
+                        self.cursor_position.x = min(self.movement_data.last_nav_position.x, self.current_row_len() as u16);
+                    }
+                    self.break_insert_session();
+                },
+                _ => (),
+            }
+        }
+        self.scroll(ScrollDirection::None);
+
+        Ok(())
+    }
+
+    pub fn enter_insert_mode(&mut self, kind: InsertKind, count: usize) {
+        self.mode = TerminalMode::Insert;
+        self.insert_session = Some(InsertSession {
+            kind,
+            count: max(count, 1),
+            text: String::new(),
+            block: None,
+        });
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        if c == '\n' {
+            self.document.insert(&self.cursor_position, '\n');
+            self.cursor_position.y = self.cursor_position.y.saturating_add(1);
+            self.cursor_position.x = 0;
+        } else {
+            let len_before = self.current_row_len();
+            self.document.insert(&self.cursor_position, c);
+            // combining characters extend the grapheme under the cursor instead of adding a new one
+            let added = self.current_row_len().saturating_sub(len_before);
+            self.cursor_position.x = self.cursor_position.x.saturating_add(added as u16);
+        }
+        self.movement_data.last_nav_position.x = self.cursor_position.x;
+    }
+
+    pub fn open_line_below(&mut self) {
+        self.cursor_position.x = self.current_row_len() as u16;
+        self.insert_char('\n');
+    }
+
+    pub fn open_line_above(&mut self) {
+        self.cursor_position.x = 0;
+        self.document.insert(&self.cursor_position, '\n');
+        self.movement_data.last_nav_position.x = 0;
+    }
+
+    fn break_insert_session(&mut self) {
+        // moving around inside insert mode starts a fresh insert, so the count no longer applies
+        if let Some(session) = self.insert_session.as_mut() {
+            session.count = 1;
+            session.text.clear();
+            session.block = None;
+        }
+        // and a fresh undo step
+        self.document.undo.commit();
+        self.document.undo.mark_cursor(self.cursor_position);
+    }
+
+    pub fn finish_insert_session(&mut self) {
+        if let Some(session) = self.insert_session.take() {
+            for _ in 1..session.count {
+                if session.kind == InsertKind::LineBelow || session.kind == InsertKind::LineAbove {
+                    self.open_line_below();
+                }
+                for c in session.text.chars() {
+                    self.insert_char(c);
+                }
+            }
+
+            // a block insert only carries over when it stayed on one line
+            if let Some(block) = session.block.filter(|_| !session.text.is_empty() && !session.text.contains('\n')) {
+                for y in block.lines.0..=block.lines.1 {
+                    let len = match self.document.rows.get(y as usize) {
+                        Some(row) => row.len,
+                        None => break,
+                    };
+                    let column = if block.to_line_end { len } else { block.column as usize };
+                    if len < column {
+                        if !block.pad {
+                            continue;
+                        }
+                        self.document.insert_str(&Position { x: len as u16, y }, &" ".repeat(column.saturating_sub(len)));
+                    }
+                    self.document.insert_str(&Position { x: column as u16, y }, &session.text);
+                }
+            }
+        }
+    }
+
+    pub fn undo(&mut self, count: usize) {
+        let (mut steps, mut undone) = (0, 0);
+        for _ in 0..max(count, 1) {
+            let node = self.document.undo.current;
+            match self.document.undo() {
+                Some(cursor) => {
+                    self.restore_cursor(cursor);
+                    steps += 1;
+                    undone = node;
+                },
+                None => break,
+            }
+        }
+
+        if steps == 0 {
+            self.status_message = Some(StatusMessage::from("Already at oldest change".to_string()));
+        } else {
+            self.status_message = Some(StatusMessage::from(format!("{} change{}; before #{}", steps, if steps == 1 { "" } else { "s" }, undone)));
+        }
+    }
+
+    pub fn redo(&mut self, count: usize) {
+        let mut steps = 0;
+        for _ in 0..max(count, 1) {
+            match self.document.redo() {
+                Some(cursor) => {
+                    self.restore_cursor(cursor);
+                    steps += 1;
+                },
+                None => break,
+            }
+        }
+
+        if steps > 0 {
+            let current = self.document.undo.current;
+            self.status_message = Some(StatusMessage::from(format!("{} change{}; after #{}", steps, if steps == 1 { "" } else { "s" }, current)));
+        } else {
+            self.status_message = Some(StatusMessage::from("Already at newest change".to_string()));
+        }
+    }
+
+    // g- and g+: moves through the states in the order they were made, whichever branch they are on
+    pub fn undo_chronologically(&mut self, steps: isize) {
+        let current = self.document.undo.current;
+        let newest = self.document.undo.nodes.len().saturating_sub(1);
+        let target = min(current.saturating_add_signed(steps), newest);
+
+        if target == current {
+            let message = if steps < 0 { "Already at oldest change" } else { "Already at newest change" };
+            self.status_message = Some(StatusMessage::from(message.to_string()));
+            return;
+        }
+        if let Some(cursor) = self.document.undo_to(target) {
+            self.restore_cursor(cursor);
+        }
+        self.status_message = Some(StatusMessage::from(format!("at #{}", target)));
+    }
+
+    // 'U': puts back the last changed line the way it was before the changes. it is a change itself, so 'U' again redoes them
+    pub fn undo_line(&mut self) {
+        self.document.undo.commit();
+        if let Some((y, text)) = self.document.undo.line.take() {
+            if let Some(current) = self.document.rows.get(y).map(|row| row.string.clone()) {
+                self.document.undo.mark_cursor(self.cursor_position);
+                self.document.replace_line(y, &text);
+                self.document.undo.commit();
+                self.document.undo.line = Some((y, current));
+                self.restore_cursor(Position { x: 0, y: y as u16 });
+            }
+        }
+    }
+
+    pub fn restore_cursor(&mut self, cursor: Position) {
+        let last = self.document.rows.len().saturating_sub(1);
+        self.cursor_position.y = min(cursor.y as usize, last) as u16;
+        self.cursor_position.x = min(cursor.x as usize, self.current_row_len().saturating_sub(1)) as u16;
+        self.movement_data.last_nav_position.x = self.cursor_position.x;
+        self.scroll(ScrollDirection::None);
+    }
+
+    pub fn store_register(&mut self, name: Option<char>, operator: Operator, register: Register) {
+        if let Err(error) = self.registers.store(name, operator, register, self.terminal.as_mut()) {
+            self.status_message = Some(StatusMessage::from(error));
+        }
+    }
+
+    // 'p' and 'P': puts the register's text after (or before) the cursor, 'count' times
+    pub fn put_register(&mut self, name: Option<char>, before: bool, count: usize) {
+        let register = match self.registers.get(name) {
+            Some(register) => register.clone(),
+            None => {
+                self.status_message = Some(StatusMessage::from(format!("E353: Nothing in register {}", name.unwrap_or('"'))));
+                return;
+            }
+        };
+        let count = max(count, 1);
+        let Position { x, y } = self.cursor_position;
+        let at_x = if before || self.current_row_len() == 0 { x } else { x.saturating_add(1) };
+
+        match register.kind {
+            RangeKind::Linewise => {
+                let text = register.text.repeat(count);
+                let at = if before { y } else { y.saturating_add(1) };
+
+                if (at as usize) < self.document.rows.len() {
+                    self.document.insert_str(&Position { x: 0, y: at }, &text);
+                    self.cursor_position.y = at;
+                } else if let Some(last) = self.document.rows.len().checked_sub(1) {
+                    // there is no line to put the text in front of, so it goes after the last one
+                    let len = self.document.rows[last].len as u16;
+                    self.document.insert_str(&Position { x: len, y: last as u16 }, &format!("\n{}", text.strip_suffix('\n').unwrap_or(&text)));
+                    self.cursor_position.y = (last as u16).saturating_add(1);
+                } else {
+                    self.document.insert_str(&Position { x: 0, y: 0 }, text.strip_suffix('\n').unwrap_or(&text));
+                    self.cursor_position.y = 0;
+                }
+                self.cursor_position.x = self.document.first_graph(self.cursor_position.y);
+            },
+            RangeKind::Charwise => {
+                let text = register.text.repeat(count);
+                let end = self.document.insert_str(&Position { x: at_x, y }, &text);
+
+                // the cursor lands on the last character put, or on the first one for multi-line text
+                self.cursor_position = if text.contains('\n') { Position { x: at_x, y } } else { Position { x: end.x.saturating_sub(1), y } };
+            },
+            RangeKind::Blockwise => {
+                let lines = register.lines();
+                let width = lines.iter().map(|line| line.graphemes(true).count()).max().unwrap_or(0);
+
+                for (offset, line) in lines.iter().enumerate() {
+                    let row_y = y.saturating_add(offset as u16);
+                    if row_y as usize >= self.document.rows.len() {
+                        let last = self.document.rows.len().saturating_sub(1);
+                        let len = self.document.rows.get(last).map(|row| row.len).unwrap_or(0) as u16;
+                        self.document.insert_str(&Position { x: len, y: last as u16 }, "\n");
+                    }
+
+                    let row_len = self.document.rows.get(row_y as usize).map(|row| row.len).unwrap_or(0);
+                    let mut piece = " ".repeat((at_x as usize).saturating_sub(row_len));
+                    let padding = width.saturating_sub(line.graphemes(true).count());
+                    for n in 0..count {
+                        piece.push_str(line);
+                        // short lines of the block are padded when something follows them
+                        if (at_x as usize) < row_len || n.saturating_add(1) < count {
+                            piece.push_str(&" ".repeat(padding));
+                        }
+                    }
+                    self.document.insert_str(&Position { x: min(at_x as usize, row_len) as u16, y: row_y }, &piece);
+                }
+                self.cursor_position = Position { x: at_x, y };
+            },
+        }
+
+        self.movement_data.last_nav_position.x = self.cursor_position.x;
+        self.scroll(ScrollDirection::None);
+    }
+
+    pub fn current_row_len(&self) -> usize {
+        self.document.rows.get(self.cursor_position.y as usize).map(|row| row.len).unwrap_or(0)
+    }
+
+    pub fn draw_rows(&mut self) {
         let Size { height, width } = self.terminal.get_size();
         let Position {x: pos_x, y: pos_y} = self.cursor_position;
 
-        print!("{}", termion::color::Bg(termion::color::Black));
+        self.terminal.set_bg(Color::Black);
 
         for y in 0..height {
             self.terminal.clear_current_line();
-            if let Some(row) = self
-                .document
-                .rows
-                .get(self.offset.y.saturating_add(y) as usize)
-            {
-                if y == pos_y.saturating_sub(self.offset.y) {
-                    print!("{}", termion::color::Bg(termion::color::Rgb(228, 228, 228)));
-                    print!("{}" , termion::color::Bg(termion::color::Reset));
+            let index = self.offset.y.saturating_add(y) as usize;
+            if let Some(row_len) = self.document.rows.get(index).map(|row| row.len) {
+                let background = if y == pos_y.saturating_sub(self.offset.y) {
+                    self.terminal.set_bg(Color::Rgb(228, 228, 228));
+                    Color::Reset
                 } else {
-                    print!("{}" , termion::color::Bg(termion::color::Black));
-                }
-                self.draw_row(row, width);
+                    Color::Black
+                };
+                self.terminal.set_bg(background);
+                self.draw_row(index, width, background);
                 if y == pos_y.saturating_sub(self.offset.y) {
-                    let right_pad_len = if width.saturating_add(self.offset.x) > row.len as u16 {
-                        (width.saturating_add(self.offset.x) as i32).saturating_sub(row.len as i32).abs() as usize
+                    let right_pad_len = if width.saturating_add(self.offset.x) > row_len as u16 {
+                        (width.saturating_add(self.offset.x) as i32).saturating_sub(row_len as i32).abs() as usize
                     } else {
-                        (width.saturating_sub(row.len as u16) as i32).abs() as usize
+                        (width.saturating_sub(row_len as u16) as i32).abs() as usize
                     };
-                    print!("{}\n\r", " ".repeat(right_pad_len));
+                    self.terminal.print(&format!("{}\n\r", " ".repeat(right_pad_len)));
                 } else {
-                    print!("\n\r");
+                    self.terminal.print("\n\r");
                 }
 
                 if y == pos_y.saturating_sub(self.offset.y) {
-                    print!("{}", termion::color::Bg(termion::color::Black));
+                    self.terminal.set_bg(Color::Black);
                 }
-            } else if y == height / 3 {
+            } else if self.document.rows.is_empty() && y == height / 3 {
                 self.display_welcome_message();
-                return;
             } else {
-                if y == 1 {
-                    print!("\n\r");
-                } else {
-                    print!("~\n\r");
-                }
-                return;
+                // every line past the end is drawn, so the bars below stay where they belong
+                self.terminal.print("~\n\r");
             }
         }
 
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_bg(Color::Reset);
     }
 
-    pub fn draw_row(&self, row: &Row, width: u16) {
-        if (!row.string.is_empty()) {
-            row.render(self.offset.x, self.offset.x.saturating_add(width));
+    pub fn draw_row(&mut self, index: usize, width: u16, background: Color) {
+        let (start, end) = (self.offset.x, self.offset.x.saturating_add(width));
+        if let Some(row) = self.document.rows.get(index).filter(|row| !row.string.is_empty()) {
+            match self.selection.as_ref().and_then(|selection| selection.columns(index as u16, row.len)) {
+                Some((from, to)) => {
+                    let (from, to) = ((from as u16).clamp(start, end), (to as u16).clamp(start, end));
+                    row.render(self.terminal.as_mut(), start, from);
+                    self.terminal.set_bg(Color::Rgb(68, 88, 122));
+                    row.render(self.terminal.as_mut(), from, to);
+                    self.terminal.set_bg(background);
+                    row.render(self.terminal.as_mut(), to, end);
+                },
+                None => {
+                    row.render(self.terminal.as_mut(), start, end);
+                },
+            }
         }
     }
 
@@ -276,14 +963,42 @@ impl Editor {
         let now = Local::now();
         let circled_dot = format!("{}", " ⊙ ");
 
-        print!("{}", termion::color::Fg(termion::color::White));
-        print!("{}", termion::color::Bg(termion::color::AnsiValue(0)));
-        print!("{}", circled_dot);
-        print!("{}", termion::color::Fg(termion::color::Reset));
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_fg(Color::White);
+        self.terminal.set_bg(Color::AnsiValue(0));
+        self.terminal.print(&circled_dot);
+        self.terminal.set_fg(Color::Reset);
+        self.terminal.set_bg(Color::Reset);
 
         rendered_width = rendered_width.saturating_add(circled_dot.graphemes(true).count());
 
+        if let Some((register, _)) = self.recording.as_ref() {
+            let recording_label = format!(" recording @{} ", register);
+            self.terminal.set_fg(Color::Rgb(244, 240, 247));
+            self.terminal.set_bg(Color::Rgb(220, 50, 47));
+            self.terminal.print(&recording_label);
+            self.terminal.set_fg(Color::Reset);
+            self.terminal.set_bg(Color::Reset);
+
+            rendered_width = rendered_width.saturating_add(recording_label.graphemes(true).count());
+        }
+
+        let mode_label = match (&self.mode, self.selection.as_ref().map(|selection| selection.kind)) {
+            (TerminalMode::Insert, _) => Some((" -- INSERT -- ", Color::Rgb(38, 139, 210))),
+            (_, Some(RangeKind::Charwise)) => Some((" -- VISUAL -- ", Color::Rgb(211, 54, 130))),
+            (_, Some(RangeKind::Linewise)) => Some((" -- VISUAL LINE -- ", Color::Rgb(211, 54, 130))),
+            (_, Some(RangeKind::Blockwise)) => Some((" -- VISUAL BLOCK -- ", Color::Rgb(211, 54, 130))),
+            _ => None,
+        };
+        if let Some((mode_label, color)) = mode_label {
+            self.terminal.set_fg(Color::Rgb(244, 240, 247));
+            self.terminal.set_bg(color);
+            self.terminal.print(mode_label);
+            self.terminal.set_fg(Color::Reset);
+            self.terminal.set_bg(Color::Reset);
+
+            rendered_width = rendered_width.saturating_add(mode_label.graphemes(true).count());
+        }
+
         let mut progress = format!("{}%", ((y.saturating_add(1) as f64 / (self.document.rows.len() as f64)) * 100_f64).ceil());
         progress.push_str("  ");
         progress.truncate(4);
@@ -297,24 +1012,24 @@ impl Editor {
         let width_diff = width.saturating_sub(rendered_width as u16);
         let space_pad = " ".repeat(width_diff as usize);
 
-        print!("{}", termion::color::Bg(termion::color::LightWhite));
-        print!("{}", termion::color::Fg(termion::color::LightBlack));
-        print!("{}", space_pad);
-        print!("{}", termion::color::Fg(termion::color::Reset));
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_bg(Color::LightWhite);
+        self.terminal.set_fg(Color::LightBlack);
+        self.terminal.print(&space_pad);
+        self.terminal.set_fg(Color::Reset);
+        self.terminal.set_bg(Color::Reset);
 
-        print!("{}", termion::color::Bg(termion::color::Rgb(124, 120, 127)));
-        print!("{}", termion::color::Fg(termion::color::Rgb(244, 240, 247)));
-        print!("{}", status_message);
-        print!("{}", termion::color::Fg(termion::color::Reset));
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_bg(Color::Rgb(124, 120, 127));
+        self.terminal.set_fg(Color::Rgb(244, 240, 247));
+        self.terminal.print(&status_message);
+        self.terminal.set_fg(Color::Reset);
+        self.terminal.set_bg(Color::Reset);
 
-        print!("{}", termion::color::Fg(termion::color::LightWhite));
-        print!("{}", termion::color::Bg(termion::color::Rgb(44, 40, 27)));
-        print!("{}", time_bar);
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_fg(Color::LightWhite);
+        self.terminal.set_bg(Color::Rgb(44, 40, 27));
+        self.terminal.print(&time_bar);
+        self.terminal.set_bg(Color::Reset);
 
-        print!("\r");
+        self.terminal.print("\r");
 
         Ok((1))
     }
@@ -324,6 +1039,15 @@ impl Editor {
     }
 
     pub fn draw_message_bar(&mut self, message: Option<&str>) -> Result<u16, io::Error> {
+        self.draw_bar(message, None)
+    }
+
+    // a prompt being typed at, with the cursor 'cursor' chars into 'text'
+    pub fn draw_prompt(&mut self, text: &str, cursor: usize) -> Result<u16, io::Error> {
+        self.draw_bar(Some(text), Some(cursor))
+    }
+
+    fn draw_bar(&mut self, message: Option<&str>, cursor: Option<usize>) -> Result<u16, io::Error> {
         let mut rendered_width: usize = 0;
         let Size { width, height } = self.terminal.get_size();
         let Position { y, ..} = self.cursor_position;
@@ -334,37 +1058,62 @@ impl Editor {
         self.terminal.flush()?;
 
         if message.is_some() {
-            print!("{}", termion::color::Fg(termion::color::White));
-            print!("{}", termion::color::Bg(termion::color::AnsiValue(0)));
+            self.terminal.set_fg(Color::White);
+            self.terminal.set_bg(Color::AnsiValue(0));
         } else {
-            print!("{}", termion::color::Fg(termion::color::LightBlack));
-            print!("{}", termion::color::Bg(termion::color::AnsiValue(0)));
+            self.terminal.set_fg(Color::LightBlack);
+            self.terminal.set_bg(Color::AnsiValue(0));
         }
-        print!("{}", prompt_text);
-        print!("{}", termion::color::Fg(termion::color::Reset));
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.print(prompt_text);
+        self.terminal.set_fg(Color::Reset);
+        self.terminal.set_bg(Color::Reset);
 
         rendered_width = rendered_width.saturating_add(prompt_text.graphemes(true).count()).saturating_add(2);
         self.terminal.goto(Position {x: prompt_text.len() as u16, y: height.saturating_add(1)});
 
+        if message.is_none() {
+            if let Some(status) = &self.status_message {
+                if status.time.elapsed() < Duration::from_secs(STATUS_MESSAGE_TIMEOUT) {
+                    let text = status.text.graphemes(true).take(width.saturating_sub(rendered_width as u16) as usize).collect::<String>();
+                    self.terminal.print(&format!(" {}", text));
+                    rendered_width = rendered_width.saturating_add(text.graphemes(true).count()).saturating_add(1);
+                }
+            }
+        }
+
         if let Some(msg) = message {
             const MESSAGE_PAD_LEN: u16 = 2;
-            let new_message = msg.chars().rev().take(width.saturating_sub(MESSAGE_PAD_LEN).saturating_sub(rendered_width as u16) as usize).collect::<Vec<_>>().iter().rev().collect::<String>();
-            let prompt_message: String = format!(" {}_", new_message);
-            print!("{}", prompt_message);
+            let room = width.saturating_sub(MESSAGE_PAD_LEN).saturating_sub(rendered_width as u16) as usize;
+            let chars: Vec<char> = msg.chars().collect();
+            let cursor = min(cursor.unwrap_or(chars.len()), chars.len());
+            // what doesn't fit goes off the left, as long as the cursor stays in sight
+            let skip = min(chars.len().saturating_sub(room), cursor);
+            let (before, after) = chars[skip..min(chars.len(), skip.saturating_add(room))].split_at(cursor.saturating_sub(skip));
+            self.terminal.print(&format!(" {}", before.iter().collect::<String>()));
+            match after.split_first() {
+                Some((under, rest)) => {
+                    self.terminal.set_fg(Color::Black);
+                    self.terminal.set_bg(Color::White);
+                    self.terminal.print(&under.to_string());
+                    self.terminal.set_fg(Color::Reset);
+                    self.terminal.set_bg(Color::Reset);
+                    self.terminal.print(&rest.iter().collect::<String>());
+                },
+                None => self.terminal.print("_"),
+            }
             rendered_width = rendered_width.saturating_add(msg.graphemes(true).count());
         }
 
         let width_diff = width.saturating_sub(rendered_width as u16);
         let space_pad = " ".repeat(width_diff as usize);
 
-        print!("{}", termion::color::Bg(termion::color::Black));
-        print!("{}", termion::color::Fg(termion::color::LightBlack));
-        print!("{}", space_pad);
-        print!("{}", termion::color::Fg(termion::color::Reset));
-        print!("{}", termion::color::Bg(termion::color::Reset));
+        self.terminal.set_bg(Color::Black);
+        self.terminal.set_fg(Color::LightBlack);
+        self.terminal.print(&space_pad);
+        self.terminal.set_fg(Color::Reset);
+        self.terminal.set_bg(Color::Reset);
 
-        print!("\r");
+        self.terminal.print("\r");
         self.terminal.goto(last_pos);
         self.terminal.cursor_show();
         self.terminal.flush()?;
@@ -374,9 +1123,43 @@ impl Editor {
     }
 
     pub fn update_selection(&mut self) -> Result<(), io::Error> {
+        let cursor = self.cursor_position;
+        if let Some(selection) = self.selection.as_mut() {
+            selection.update(cursor);
+        }
+
         Ok(())
     }
 
+    pub fn start_visual(&mut self, kind: RangeKind) {
+        self.selection = Some(Selection::new(self.cursor_position, kind));
+    }
+
+    // drops the selection, keeping it around for 'gv'
+    pub fn end_visual(&mut self) {
+        if let Some(selection) = self.selection.take() {
+            self.last_selection = Some((selection, self.cursor_position));
+        }
+    }
+
+    // 'gv': selects what was last selected again. returns false when nothing was
+    pub fn reselect(&mut self) -> bool {
+        let (mut selection, cursor) = match self.last_selection.clone() {
+            Some(last) => last,
+            None => return false,
+        };
+        // the text may have changed since, so both ends are kept inside it
+        let last_line = self.document.rows.len().saturating_sub(1) as u16;
+        self.cursor_position = Position { x: cursor.x, y: min(cursor.y, last_line) };
+        selection.position.y = min(selection.position.y, last_line);
+        selection.update(self.cursor_position);
+        self.selection = Some(selection);
+        self.movement_data.last_nav_position.x = self.cursor_position.x;
+        self.scroll(ScrollDirection::None);
+
+        true
+    }
+
     pub fn find_char_column(&mut self, position: &Position, direction: SearchDirection, target: &str, in_line: bool) -> Coordinate {
         if in_line {
             if let Some(curr_row) = self.document.rows.get(position.y as usize) {
@@ -414,72 +1197,923 @@ impl Editor {
                 return Coordinate(-1, -1);
             }
         } else {
-            let found_index_y = AtomicUsize::new(usize::MAX);
-            let found_index_x = AtomicUsize::new(usize::MAX);
-
-            let chunk_size = max(self.document.rows.len() >> 10, 1);
-
-            let row_chunks = match direction {
-                SearchDirection::Forward => self.document.rows[position.y.saturating_add(1) as usize..].par_chunks(chunk_size),
-                SearchDirection::Backward => self.document.rows[..position.y as usize].par_chunks(chunk_size),
+            let rows = self.document.rows.len();
+            let find = |row: &Row| {
+                if !row.has_multibyte && target.len() == 1 {
+                    row.string.find(target)
+                } else {
+                    row.string.graphemes(true).position(|c| c == target)
+                }
             };
+            let found = match direction {
+                SearchDirection::Forward => self.scan_rows(position.y.saturating_add(1) as usize, rows, direction, find),
+                SearchDirection::Backward => self.scan_rows(0, position.y as usize, direction, find),
+            };
+
+            match found {
+                Some((x, y)) => Coordinate(x as i64, y as i64),
+                None => Coordinate(-1, -1),
+            }
+        }
+    }
 
-            row_chunks
-                .enumerate()
-                .try_for_each(|(chunk_idx, rows)| {
-                    if found_index_y.load(Ordering::Acquire) != usize::MAX { return Err(()); }
-
-                    if let Some(local_position) = rows.iter().position(|r| {
-                        if !r.has_multibyte {
-                            if let Some(idx) = r.string.chars().position(|c| c == target.chars().next().unwrap_or(((c as u8).saturating_sub(1)) as char)) {
-                                found_index_x.fetch_min(idx, Ordering::Relaxed);
-                                return true;
-                            }
-                            return false;
-                        } else {
-                            if let Some(idx) = r.string.graphemes(true).position(|c| {c == target}) {
-                                found_index_x.fetch_min(idx, Ordering::Relaxed);
-                                return true;
-                            }
-                            return false;
+    // the row nearest to 'first' (going forward) or to 'last' (going backward) among first..last where 'find' gives a column.
+    // the rows are scanned in parallel chunks, which keeps this quick on large files
+    pub fn scan_rows<F>(&self, first: usize, last: usize, direction: SearchDirection, find: F) -> Option<(usize, usize)>
+    where F: Fn(&Row) -> Option<usize> + Sync {
+        let rows = self.document.rows.get(first..last)?;
+        let chunk_size = max(rows.len() >> 10, 1);
+
+        // forward keeps the lowest index found so far, backward the highest one plus one (so 0 is nothing yet)
+        let found = AtomicUsize::new(if direction == SearchDirection::Forward { usize::MAX } else { 0 });
+        rows.par_chunks(chunk_size)
+            .enumerate()
+            .for_each(|(chunk_idx, chunk)| {
+                let chunk_start = chunk_idx.saturating_mul(chunk_size);
+                match direction {
+                    SearchDirection::Forward => {
+                        // a chunk can only matter when it starts before the best match so far
+                        if chunk_start >= found.load(Ordering::Acquire) { return; }
+                        if let Some(local_position) = chunk.iter().position(|row| find(row).is_some()) {
+                            found.fetch_min(chunk_start.saturating_add(local_position), Ordering::AcqRel);
                         }
+                    },
+                    SearchDirection::Backward => {
+                        if chunk_start.saturating_add(chunk.len()) <= found.load(Ordering::Acquire) { return; }
+                        if let Some(local_position) = chunk.iter().rposition(|row| find(row).is_some()) {
+                            found.fetch_max(chunk_start.saturating_add(local_position).saturating_add(1), Ordering::AcqRel);
+                        }
+                    },
+                }
+            });
 
-                    }) {
-                        let global_idx = chunk_idx.saturating_mul(chunk_size).saturating_add(local_position);
-                        found_index_y.fetch_min(global_idx, Ordering::Release);
-                        return Err(());
-                    }
+        let index = match (direction, found.into_inner()) {
+            (SearchDirection::Forward, usize::MAX) | (SearchDirection::Backward, 0) => return None,
+            (SearchDirection::Forward, index) => index,
+            (SearchDirection::Backward, index) => index.saturating_sub(1),
+        };
 
-                    Ok(())
-                })
-                .ok();
+        find(&rows[index]).map(|x| (x, first.saturating_add(index)))
+    }
+
+    // the next place 'regex' matches after 'from' (or before it going backward), wrapping around the ends of the file.
+    // the flag says whether it had to wrap
+    pub fn find_match(&self, regex: &Regex, from: Position, direction: SearchDirection) -> Option<(Position, bool)> {
+        let (x, y) = (from.x as usize, from.y as usize);
+        let rows = self.document.rows.len();
+        let row = self.document.rows.get(y)?;
+        let position = |(x, y): (usize, usize)| Position { x: x as u16, y: y as u16 };
+
+        match direction {
+            SearchDirection::Forward => {
+                let first = |row: &Row| row.match_from(regex, 0);
+                row.match_from(regex, x.saturating_add(1)).map(|start| (start, y))
+                    .or_else(|| self.scan_rows(y.saturating_add(1), rows, direction, first))
+                    .map(|found| (position(found), false))
+                    .or_else(|| self.scan_rows(0, y, direction, first).map(|found| (position(found), true)))
+                    .or_else(|| first(row).map(|start| (position((start, y)), true)))
+            },
+            SearchDirection::Backward => {
+                let last = |row: &Row| row.match_before(regex, usize::MAX);
+                row.match_before(regex, x).map(|start| (start, y))
+                    .or_else(|| self.scan_rows(0, y, direction, last))
+                    .map(|found| (position(found), false))
+                    .or_else(|| self.scan_rows(y.saturating_add(1), rows, direction, last).map(|found| (position(found), true)))
+                    .or_else(|| last(row).map(|start| (position((start, y)), true)))
+            },
+        }
+    }
 
-            let mut res_coordinate = Coordinate(-1, -1);
+    // the 'count'th match from 'from', and whether getting there wrapped around
+    pub fn nth_match(&self, regex: &Regex, from: Position, direction: SearchDirection, count: usize) -> Option<(Position, bool)> {
+        let (mut position, mut wrapped) = (from, false);
+        for _ in 0..max(count, 1) {
+            let (found, wrap) = self.find_match(regex, position, direction)?;
+            position = found;
+            wrapped |= wrap;
+        }
 
-            let found_y = found_index_y.load(Ordering::Acquire);
-            if found_y != usize::MAX {
-                res_coordinate.1 = found_y.saturating_add(position.y as usize) as i64;
+        Some((position, wrapped))
+    }
+
+    // a search pattern compiled with the case options in effect. a bad pattern is reported in the message bar
+    pub fn compile_pattern(&mut self, pattern: &str) -> Option<Regex> {
+        match Regex::new(pattern, self.ignorecase, self.smartcase) {
+            Ok(regex) => Some(regex),
+            Err(error) => {
+                self.status_message = Some(StatusMessage::from(error));
+                self.abort_replay();
+                None
             }
+        }
+    }
 
-            let found_x = found_index_x.load(Ordering::Acquire);
-            if found_x != usize::MAX {
-                res_coordinate.0 = found_x as i64;
+    // moves to the 'count'th match of 'regex', saying in the message bar when the search wrapped or found nothing
+    pub fn search(&mut self, regex: &Regex, direction: SearchDirection, count: usize) -> bool {
+        let (found, wrapped) = match self.nth_match(regex, self.cursor_position, direction, count) {
+            Some(found) => found,
+            None => {
+                self.status_message = Some(StatusMessage::from(format!("E486: Pattern not found: {}", regex.pattern)));
+                self.abort_replay();
+                return false;
             }
+        };
+
+        self.cursor_position = found;
+        self.movement_data.last_nav_position.x = found.x;
+        let message = match (wrapped, direction) {
+            (true, SearchDirection::Forward) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (true, SearchDirection::Backward) => "search hit TOP, continuing at BOTTOM".to_string(),
+            (false, SearchDirection::Forward) => format!("/{}", regex.pattern),
+            (false, SearchDirection::Backward) => format!("?{}", regex.pattern),
+        };
+        self.status_message = Some(StatusMessage::from(message));
+
+        true
+    }
 
-            if res_coordinate.0 == -1 || res_coordinate.1 == -1 {
-                Coordinate(-1, -1)
-            } else {
-                res_coordinate
+    // '/' and '?': the cursor follows the first match while the pattern is typed, and <Esc> puts it back
+    pub fn search_prompt(&mut self, direction: SearchDirection, count: usize) -> Result<bool, io::Error> {
+        let (origin, origin_offset) = (self.cursor_position, self.offset);
+        let previous = self.highlighted_word.clone();
+        let prefix = if direction == SearchDirection::Forward { "/" } else { "?" };
+        let mut cancelled = false;
+
+        let pattern = self.prompt(|editor, key, text| {
+            editor.cursor_position = origin;
+            editor.offset = origin_offset;
+            if key == Key::Esc {
+                cancelled = true;
             }
+            if cancelled || text.is_empty() {
+                editor.set_highlighted_word(previous.clone());
+            } else if let Ok(regex) = Regex::new(text, editor.ignorecase, editor.smartcase) {
+                // a pattern that doesn't compile yet (say, half way through a group) leaves things as they were
+                if let Some((found, _)) = editor.nth_match(&regex, origin, direction, count) {
+                    editor.cursor_position = found;
+                }
+                editor.set_highlighted_word(Some(regex));
+            }
+
+            if key != Key::Esc && key != Key::Char('\n') {
+                editor.scroll(ScrollDirection::None);
+                editor.refresh_screen().ok();
+            }
+        }, Some(prefix.to_string()))?;
+
+        self.cursor_position = origin;
+        self.offset = origin_offset;
+        if cancelled {
+            return Ok(false);
         }
+
+        // an empty pattern searches for the last one again
+        let pattern = match pattern.or_else(|| self.last_search.as_ref().map(|(pattern, _)| pattern.clone())) {
+            Some(pattern) => pattern,
+            None => {
+                self.status_message = Some(StatusMessage::from("E35: No previous regular expression".to_string()));
+                return Ok(false);
+            }
+        };
+        let regex = match self.compile_pattern(&pattern) {
+            Some(regex) => regex,
+            None => {
+                self.set_highlighted_word(previous);
+                return Ok(false);
+            }
+        };
+        self.last_search = Some((pattern, direction));
+        self.set_highlighted_word(Some(regex.clone()));
+
+        Ok(self.search(&regex, direction, count))
+    }
+
+    // 'n' and 'N': the last search again, the same way or the other way
+    pub fn repeat_search(&mut self, reverse: bool, count: usize) -> bool {
+        let (pattern, direction) = match self.last_search.clone() {
+            Some(last) => last,
+            None => {
+                self.status_message = Some(StatusMessage::from("E35: No previous regular expression".to_string()));
+                self.abort_replay();
+                return false;
+            }
+        };
+        let direction = match (reverse, direction) {
+            (false, direction) => direction,
+            (true, SearchDirection::Forward) => SearchDirection::Backward,
+            (true, SearchDirection::Backward) => SearchDirection::Forward,
+        };
+        // compiled again, since 'ignorecase' or 'smartcase' may have changed since
+        let regex = match self.compile_pattern(&pattern) {
+            Some(regex) => regex,
+            None => return false,
+        };
+        self.set_highlighted_word(Some(regex.clone()));
+
+        self.search(&regex, direction, count)
+    }
+
+    // the keyword under or after the cursor, or failing that the other non-blank text there, and the column it starts at.
+    // the flag says whether it is a keyword
+    pub fn word_under_cursor(&self) -> Option<(String, usize, bool)> {
+        let Position { x, y } = self.cursor_position;
+        let row = self.document.rows.get(y as usize)?;
+        let graphemes = row.graphemes();
+        let class = |index: usize| get_v_char_class(graphemes[index].chars().next().unwrap_or(' '));
+        let keyword = |index: usize| graphemes[index].chars().next().is_some_and(is_word);
+        let x = min(x as usize, graphemes.len().checked_sub(1)?);
+
+        let (mut start, is_keyword) = match (x..graphemes.len()).find(|index| keyword(*index)) {
+            Some(start) => (start, true),
+            None => ((x..graphemes.len()).find(|index| class(*index) != VCharacterClass::Blank)?, false),
+        };
+        let run_class = class(start);
+        while start > 0 && class(start.saturating_sub(1)) == run_class {
+            start -= 1;
+        }
+        let end = (start..graphemes.len()).find(|index| class(*index) != run_class).unwrap_or(graphemes.len());
+
+        Some((row.substring(start, end).to_string(), start, is_keyword))
     }
 
+    // '*' and '#': searches for the word under the cursor, as a whole word when it is a keyword
+    pub fn search_word(&mut self, direction: SearchDirection, count: usize) -> bool {
+        let (word, start, is_keyword) = match self.word_under_cursor() {
+            Some(word) => word,
+            None => {
+                self.status_message = Some(StatusMessage::from("E348: No string under cursor".to_string()));
+                self.abort_replay();
+                return false;
+            }
+        };
+        let pattern = if is_keyword { format!("\\<{}\\>", escape(&word)) } else { escape(&word) };
+        // like vim, 'smartcase' doesn't apply to a word that wasn't typed
+        let regex = match Regex::new(&pattern, self.ignorecase, false) {
+            Ok(regex) => regex,
+            Err(error) => {
+                self.status_message = Some(StatusMessage::from(error));
+                return false;
+            }
+        };
+        self.last_search = Some((pattern, direction));
+        self.set_highlighted_word(Some(regex.clone()));
+
+        // searching from the start of the word, so '#' skips the word the cursor is on
+        self.cursor_position.x = start as u16;
+        self.search(&regex, direction, count)
+    }
+
+    // rows keep their highlighting until they change, so a new pattern has them all highlighted again
+    pub fn set_highlighted_word(&mut self, word: Option<Regex>) {
+        if self.highlighted_word != word {
+            self.highlighted_word = word;
+            self.document.unhighlight_rows();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::time::Instant;
+    use crate::input::ScriptedKeys;
+    use crate::terminal::MemoryTerminal;
+
+    // types 'keys' into an editor holding 'lines', until the script runs out
+    fn type_keys(lines: Vec<&str>, keys: &str) -> Editor {
+        type_keys_into(Editor::default(), lines, keys)
+    }
+
+    fn type_keys_into(editor: Editor, lines: Vec<&str>, keys: &str) -> Editor {
+        let mut editor = Editor { key_source: Box::new(ScriptedKeys::from_str(keys)), ..editor };
+        let mut fsm = EditorFSM::new();
+        editor.document.populate(lines);
+
+        while !editor.should_quit {
+            editor.process_keys(&mut fsm).unwrap();
+        }
+        editor
+    }
+
+    fn rows(editor: &Editor) -> Vec<String> {
+        editor.document.rows.iter().map(|row| row.string.clone()).collect()
+    }
+
+    fn message(editor: &Editor) -> String {
+        editor.status_message.as_ref().map(|status| status.text.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_typed_keys () {
+        let lines = vec!["zero", "one", "two", "three", "four", "five", "six words here", "seven"];
+
+        let editor = type_keys(lines.clone(), "5jdw");
+        assert_eq!(rows(&editor)[5], "");
+        assert_eq!((editor.cursor_position.x, editor.cursor_position.y), (0, 5));
+
+        let editor = type_keys(lines.clone(), "6jwcwnew\x1bw.");
+        assert_eq!(&rows(&editor)[6..], ["six new new", "seven"]);
+
+        let editor = type_keys(lines.clone(), "ddjpu\x12");
+        assert_eq!(&rows(&editor)[..3], ["one", "two", "zero"]);
+
+        // an unfinished command just stops where the script does
+        let editor = type_keys(lines, "2d");
+        assert_eq!(editor.document.rows.len(), 8);
+    }
+
+    #[test]
+    fn test_quit () {
+        let start = |keys: &str| {
+            let mut editor = Editor { key_source: Box::new(ScriptedKeys::from_str(keys)), ..Editor::default() };
+            editor.document.populate(vec!["one", "two"]);
+            (editor, EditorFSM::new())
+        };
+
+        // with unsaved changes Ctrl-Q has to be pressed three times in a row, and any other key starts the count over
+        let (mut editor, mut fsm) = start("\x11\x11j\x11\x11\x11");
+        editor.document.dirty = true;
+        let mut quits = vec![];
+        for _ in 0..5 {
+            editor.process_keys(&mut fsm).unwrap();
+            quits.push((editor.quit_times, editor.should_quit));
+        }
+        assert_eq!(quits, [(2, false), (1, false), (3, false), (2, false), (1, false)]);
+        assert_eq!(message(&editor), "WARNING!!! File has unsaved changes. Press Ctrl-Q 1 more times to quit.");
+        editor.process_keys(&mut fsm).unwrap();
+        assert!(editor.should_quit);
+
+        let (mut editor, mut fsm) = start("\x11");
+        editor.process_keys(&mut fsm).unwrap();
+        assert!(editor.should_quit);
+
+        // ':q' refuses to drop changes, ':q!' doesn't
+        let (mut editor, mut fsm) = start("ix\x1b:q\n:q!\n");
+        for _ in 0..4 {
+            editor.process_keys(&mut fsm).unwrap();
+        }
+        assert!(!editor.should_quit);
+        assert_eq!(message(&editor), "E37: No write since last change (add ! to override)");
+        editor.process_keys(&mut fsm).unwrap();
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn test_insert_commands () {
+        let lines = vec!["one", "    two"];
+
+        let editor = type_keys(lines.clone(), "3ofoo\x1b");
+        assert_eq!(rows(&editor), ["one", "foo", "foo", "foo", "    two"]);
+        assert_eq!((editor.cursor_position.x, editor.cursor_position.y), (2, 3));
+        let editor = type_keys(lines.clone(), "j2Obar\x1b");
+        assert_eq!(rows(&editor), ["one", "bar", "bar", "    two"]);
+        let editor = type_keys(lines.clone(), "3Ax\x1b");
+        assert_eq!(rows(&editor)[0], "onexxx");
+        let editor = type_keys(lines.clone(), "l2ax\x1b");
+        assert_eq!(rows(&editor)[0], "onxxe");
+        let editor = type_keys(lines.clone(), "l2ix\x1b");
+        assert_eq!(rows(&editor)[0], "oxxne");
+
+        // 'I' starts at the first non-blank, past the indent
+        let editor = type_keys(lines, "j$I- \x1b");
+        assert_eq!(rows(&editor)[1], "    - two");
+    }
+
+    #[test]
+    fn test_operator_motions () {
+        let lines = vec!["a b c d e f g h", "  hello world", "one two three", "last"];
+
+        // the counts before the operator and the motion multiply
+        let editor = type_keys(lines.clone(), "2d3w");
+        assert_eq!(rows(&editor)[0], "g h");
+
+        let editor = type_keys(lines.clone(), "jdd");
+        assert_eq!(rows(&editor), ["a b c d e f g h", "one two three", "last"]);
+        let editor = type_keys(lines.clone(), "jccnew\x1b");
+        assert_eq!(rows(&editor)[1], "new");
+        let editor = type_keys(lines.clone(), "yyGp");
+        assert_eq!(&rows(&editor)[3..], ["last", "a b c d e f g h"]);
+
+        // linewise motions take whole lines, whichever way they go
+        let editor = type_keys(lines.clone(), "jdj");
+        assert_eq!(rows(&editor), ["a b c d e f g h", "last"]);
+        let editor = type_keys(lines.clone(), "jjdk");
+        assert_eq!(rows(&editor), ["a b c d e f g h", "last"]);
+        let editor = type_keys(lines.clone(), "jdG");
+        assert_eq!(rows(&editor), ["a b c d e f g h"]);
+        let editor = type_keys(lines.clone(), "jjdgg");
+        assert_eq!(rows(&editor), ["last"]);
+        // H and L go by the screen, which is taller than the file here
+        let on_screen = || Editor { net_height: 22, ..Editor::default() };
+        let editor = type_keys_into(on_screen(), lines.clone(), "jjdH");
+        assert_eq!(rows(&editor), ["last"]);
+        let editor = type_keys_into(on_screen(), lines.clone(), "jdL");
+        assert_eq!(rows(&editor), ["a b c d e f g h"]);
+
+        let editor = type_keys(lines.clone(), "jwwd$");
+        assert_eq!(rows(&editor)[1], "  hello ");
+        let editor = type_keys(lines.clone(), "jwwd0");
+        assert_eq!(rows(&editor)[1], "world");
+        let editor = type_keys(lines.clone(), "jwwd^");
+        assert_eq!(rows(&editor)[1], "  world");
+
+        // 'ge' goes back to the end of the word before, and takes the character it lands on
+        let editor = type_keys(lines.clone(), "jjwwge");
+        assert_eq!((editor.cursor_position.x, editor.cursor_position.y), (6, 2));
+        let editor = type_keys(lines.clone(), "jjwwdge");
+        assert_eq!(rows(&editor)[2], "one twhree");
+
+        // 'cw' on a word stops at its end, like 'ce', instead of taking the space after it
+        let editor = type_keys(lines.clone(), "jjcwsix\x1b");
+        assert_eq!(rows(&editor)[2], "six two three");
+        let editor = type_keys(lines.clone(), "jjllcwe\x1b");
+        assert_eq!(rows(&editor)[2], "one two three");
+        let editor = type_keys(lines, "jjdw");
+        assert_eq!(rows(&editor)[2], "two three");
+    }
+
+    #[test]
+    fn test_repeat_last_change () {
+        let lines = vec!["a b c d e f", "a b c d e f"];
+
+        // a count before '.' takes the place of the one the change had
+        let editor = type_keys(lines.clone(), "2dwj3.");
+        assert_eq!(rows(&editor), ["c d e f", "d e f"]);
+        let editor = type_keys(lines.clone(), "2dwj.");
+        assert_eq!(rows(&editor), ["c d e f", "c d e f"]);
+
+        let mut editor = type_keys(vec!["one two", "three four"], "\"adwj.");
+        assert_eq!(rows(&editor), ["two", "four"]);
+        assert_eq!(editor.registers.get(Some('a')).map(|register| register.text.clone()), Some("three ".to_string()));
+
+        let editor = type_keys(vec!["one two", "three four"], "ciwX\x1bj0.");
+        assert_eq!(rows(&editor), ["X two", "X four"]);
+
+        // what was typed in insert mode comes back with the command that started it
+        let editor = type_keys(vec!["one two", "three four"], "A;\x1bj.");
+        assert_eq!(rows(&editor), ["one two;", "three four;"]);
+        let editor = type_keys(vec!["one two", "three four"], "ix\x1bj03.");
+        assert_eq!(rows(&editor), ["xone two", "xxxthree four"]);
+    }
+
+    #[test]
+    fn test_macros () {
+        let lines = vec!["1", "2", "3", "4", "5", "6"];
+
+        let editor = type_keys(lines.clone(), "qaA!\x1bjq3@a");
+        assert_eq!(rows(&editor), ["1!", "2!", "3!", "4!", "5", "6"]);
+        assert_eq!(editor.registers.named.get(&'a').map(|register| register.text.clone()), Some("A!\x1bj".to_string()));
+        // '@@' runs the last one again
+        let editor = type_keys(lines.clone(), "qaA!\x1bjq@a@@");
+        assert_eq!(rows(&editor), ["1!", "2!", "3!", "4", "5", "6"]);
+        let editor = type_keys(lines.clone(), "@@");
+        assert_eq!(message(&editor), "E748: No previously used register");
+
+        // a motion that can't move ends the playback, along with the rest of the count
+        let editor = type_keys(vec!["x1", "x2", "x3"], "qaddjq5@a");
+        assert_eq!(rows(&editor), ["x2"]);
+        let editor = type_keys(lines, "qajA!\x1bq9@a");
+        assert_eq!(rows(&editor), ["1", "2!", "3!", "4!", "5!", "6!"]);
+    }
+
+    #[test]
+    fn test_visual_mode () {
+        let lines = vec!["alpha beta", "gamma delta", "epsilon", "zeta eta"];
+
+        let editor = type_keys(lines.clone(), "wvjd");
+        assert_eq!(rows(&editor), ["alpha elta", "epsilon", "zeta eta"]);
+
+        let editor = type_keys(lines.clone(), "jVjyGp");
+        assert_eq!(&rows(&editor)[3..], ["zeta eta", "gamma delta", "epsilon"]);
+
+        // 'o' moves to the other end, so the selection grows the other way
+        let editor = type_keys(lines.clone(), "jwvlohhU");
+        assert_eq!(rows(&editor)[1], "gammA DElta");
+
+        let editor = type_keys(lines.clone(), "\x16jjlld");
+        assert_eq!(rows(&editor), ["ha beta", "ma delta", "ilon", "zeta eta"]);
+
+        let editor = type_keys(lines.clone(), "l\x16jj$y$p");
+        assert_eq!(&rows(&editor)[..3], ["alpha betalpha beta", "gamma deltamma deltaa", "epsilon   psilon"]);
+
+        let editor = type_keys(lines.clone(), "vj\x1bggjgv~");
+        assert_eq!(&rows(&editor)[..2], ["ALPHA BETA", "Gamma delta"]);
+
+        let editor = type_keys(lines.clone(), "Vj2>jVU");
+        assert_eq!(&rows(&editor)[..2], ["        alpha beta", "        GAMMA DELTA"]);
+
+        let editor = type_keys(lines, "vecnew\x1b");
+        assert_eq!(rows(&editor)[0], "new beta");
+    }
+
+    #[test]
+    fn test_visual_block_insert () {
+        let lines = vec!["let a = 1;", "let bb", "x", "let ccc = 3;"];
+
+        // short lines are skipped by 'I' and 'c'...
+        let editor = type_keys(lines.clone(), "w\x16jjjI// \x1b");
+        assert_eq!(rows(&editor), ["let // a = 1;", "let // bb", "x", "let // ccc = 3;"]);
+
+        let editor = type_keys(lines.clone(), "w\x16jjjecvar\x1b");
+        assert_eq!(rows(&editor), ["let var 1;", "let var", "x", "let var = 3;"]);
+
+        // ...and padded by 'A'
+        let editor = type_keys(lines.clone(), "w\x16jjjlA|\x1b");
+        assert_eq!(rows(&editor), ["let a |= 1;", "let bb|", "x     |", "let cc|c = 3;"]);
+
+        let editor = type_keys(lines.clone(), "\x16jjj$A;\x1b");
+        assert_eq!(rows(&editor), ["let a = 1;;", "let bb;", "x;", "let ccc = 3;;"]);
+
+        // one undo step takes it all back
+        let editor = type_keys(lines.clone(), "\x16jjj$A;\x1bu");
+        assert_eq!(rows(&editor), lines);
+
+        // a line break typed into the block only goes into the first line
+        let editor = type_keys(lines, "\x16jIa\nb\x1b");
+        assert_eq!(rows(&editor), ["a", "blet a = 1;", "let bb", "x", "let ccc = 3;"]);
+    }
+
+    #[test]
+    fn test_search () {
+        let lines = vec!["one two", "two three", "four", "two"];
+        let cursor = |editor: &Editor| (editor.cursor_position.x, editor.cursor_position.y);
+
+        let editor = type_keys(lines.clone(), "/two\n");
+        assert_eq!(cursor(&editor), (4, 0));
+        assert_eq!(editor.highlighted_word.as_ref().map(|regex| regex.pattern.as_str()), Some("two"));
+
+        let editor = type_keys(lines.clone(), "/two\nn");
+        assert_eq!(cursor(&editor), (0, 1));
+
+        let editor = type_keys(lines.clone(), "/two\n3n");
+        assert_eq!(cursor(&editor), (4, 0));
+        assert_eq!(message(&editor), "search hit BOTTOM, continuing at TOP");
+
+        let editor = type_keys(lines.clone(), "?two\n");
+        assert_eq!(cursor(&editor), (0, 3));
+        assert_eq!(message(&editor), "search hit TOP, continuing at BOTTOM");
+
+        // 'N' goes the other way from the search it repeats
+        let editor = type_keys(lines.clone(), "jj?two\nN");
+        assert_eq!(cursor(&editor), (0, 3));
+
+        // an empty pattern is the last one again
+        let editor = type_keys(lines.clone(), "2/two\n/\n");
+        assert_eq!(cursor(&editor), (0, 3));
+
+        let editor = type_keys(lines.clone(), "j/zzz\n");
+        assert_eq!(cursor(&editor), (0, 1));
+        assert_eq!(message(&editor), "E486: Pattern not found: zzz");
+
+        // patterns are regular expressions, and the ones that don't compile say why
+        let editor = type_keys(lines.clone(), "/\\<t\\(wo\\|hree\\)$\nn");
+        assert_eq!(cursor(&editor), (4, 1));
+
+        let editor = type_keys(lines.clone(), "j/\\(tw\n");
+        assert_eq!(cursor(&editor), (0, 1));
+        assert_eq!(message(&editor), "E54: Unmatched \\(");
+        assert!(editor.last_search.is_none());
+
+        let editor = type_keys(lines.clone(), ":set ic\n/TWO\n");
+        assert_eq!(cursor(&editor), (4, 0));
+        let editor = type_keys(lines.clone(), ":set ic\n:set scs\n/TWO\n");
+        assert_eq!(message(&editor), "E486: Pattern not found: TWO");
+
+        let editor = type_keys(lines, "/thr\x1b");
+        assert_eq!(cursor(&editor), (0, 0));
+        assert!(editor.last_search.is_none() && editor.highlighted_word.is_none());
+    }
+
+    #[test]
+    fn test_search_word () {
+        let lines = vec!["foo bar foobar", "  (foo) foo_x", "bar foo", ""];
+        let cursor = |editor: &Editor| (editor.cursor_position.x, editor.cursor_position.y);
+
+        let editor = type_keys(lines.clone(), "*");
+        assert_eq!(cursor(&editor), (3, 1));
+        assert_eq!(editor.highlighted_word.as_ref().map(|regex| regex.pattern.as_str()), Some("\\<foo\\>"));
+
+        let editor = type_keys(lines.clone(), "*n");
+        assert_eq!(cursor(&editor), (4, 2));
+        let editor = type_keys(lines.clone(), "2*");
+        assert_eq!(cursor(&editor), (4, 2));
+
+        // '#' from the middle of a word skips the word itself
+        let editor = type_keys(lines.clone(), "ll#");
+        assert_eq!(cursor(&editor), (4, 2));
+
+        // off a word, the next one on the line is taken
+        let editor = type_keys(lines.clone(), "j*");
+        assert_eq!(cursor(&editor), (4, 2));
+
+        let editor = type_keys(lines.clone(), "G*");
+        assert_eq!(message(&editor), "E348: No string under cursor");
+
+        // and with no word after the cursor, the other non-blank text
+        let editor = type_keys(vec!["x .*", "y .*"], "ll*");
+        assert_eq!(cursor(&editor), (2, 1));
+        assert_eq!(editor.last_search.map(|(pattern, _)| pattern), Some("\\.\\*".to_string()));
+    }
+
+    #[test]
+    fn test_substitute () {
+        let lines = vec!["foo bar foo", "bar", "foo foo", "key = value"];
+
+        let editor = type_keys(lines.clone(), ":s/foo/x/\n");
+        assert_eq!(rows(&editor)[0], "x bar foo");
+
+        let editor = type_keys(lines.clone(), ":%s/foo/<&>/g\n");
+        assert_eq!(&rows(&editor)[..3], ["<foo> bar <foo>", "bar", "<foo> <foo>"]);
+        assert_eq!(message(&editor), "4 substitutions on 2 lines");
+        assert_eq!((editor.cursor_position.x, editor.cursor_position.y), (0, 2));
+
+        let editor = type_keys(lines.clone(), "G:s/\\(\\w\\+\\) = \\(\\w\\+\\)/\\2: \\1/\n");
+        assert_eq!(rows(&editor)[3], "value: key");
+
+        // ranges: numbers, '.', '$', offsets and marks
+        let editor = type_keys(lines.clone(), ":2,$s/^/-/\n");
+        assert_eq!(rows(&editor), ["foo bar foo", "-bar", "-foo foo", "-key = value"]);
+        let editor = type_keys(lines.clone(), "jj:.-1,.+1s#a#\\##g\n");
+        assert_eq!(&rows(&editor)[1..], ["b#r", "foo foo", "key = v#lue"]);
+        let editor = type_keys(lines.clone(), "jmaG:'a,.s/$/!/\n");
+        assert_eq!(&rows(&editor)[1..], ["bar!", "foo foo!", "key = value!"]);
+        let editor = type_keys(lines.clone(), "jVj:s/o/0/g\n");
+        assert_eq!(&rows(&editor)[1..3], ["bar", "f00 f00"]);
+
+        // a line break in the replacement splits the line, and it all undoes in one go
+        let editor = type_keys(lines.clone(), ":%s/ /\\r/g\n");
+        assert_eq!(rows(&editor).len(), 9);
+        assert_eq!(&rows(&editor)[..4], ["foo", "bar", "foo", "bar"]);
+        let editor = type_keys(lines.clone(), ":%s/ /\\r/g\nu");
+        assert_eq!(rows(&editor), lines);
+
+        // y, n, then a for the rest
+        let editor = type_keys(lines.clone(), ":%s/foo/x/gc\nyna");
+        assert_eq!(&rows(&editor)[..3], ["x bar foo", "bar", "x x"]);
+        let editor = type_keys(lines.clone(), ":%s/foo/x/gc\nnq");
+        assert_eq!(rows(&editor), lines);
+        let editor = type_keys(lines.clone(), ":%s/foo/x/gc\nnl");
+        assert_eq!(rows(&editor)[0], "foo bar x");
+        assert_eq!(rows(&editor)[2], "foo foo");
+
+        let editor = type_keys(lines.clone(), ":%s/FOO/x/gn\n");
+        assert_eq!(message(&editor), "E486: Pattern not found: FOO");
+        let editor = type_keys(lines.clone(), ":%s/FOO/x/gni\n");
+        assert_eq!(message(&editor), "4 matches on 2 lines");
+        assert_eq!(rows(&editor), lines);
+        let editor = type_keys(lines.clone(), ":%s/zzz/x/e\n");
+        assert!(editor.status_message.is_none());
+        let editor = type_keys(lines.clone(), ":s/foo/x/q\n");
+        assert_eq!(message(&editor), "E488: Trailing characters: q");
+        let editor = type_keys(lines.clone(), ":5s/foo/x/\n");
+        assert_eq!(message(&editor), "E16: Invalid range");
+        let editor = type_keys(lines.clone(), "/bar\n:s//baz/\n");
+        assert_eq!(rows(&editor)[0], "foo baz foo");
+        let editor = type_keys(lines, ":3\n");
+        assert_eq!(editor.cursor_position.y, 2);
+    }
+
+    #[test]
+    fn test_global () {
+        let lines = vec!["x 1", "x 2", "y", "x 3", "z"];
+
+        // the marks move up as the lines before them go, so lines next to each other all get deleted
+        let editor = type_keys(lines.clone(), ":g/x/d\n");
+        assert_eq!(rows(&editor), ["y", "z"]);
+        let editor = type_keys(lines.clone(), ":v/x/d\n");
+        assert_eq!(rows(&editor), ["x 1", "x 2", "x 3"]);
+        let editor = type_keys(lines.clone(), ":g!/x/d\n");
+        assert_eq!(rows(&editor), ["x 1", "x 2", "x 3"]);
+        let editor = type_keys(lines.clone(), ":2,4g/x/d\n");
+        assert_eq!(rows(&editor), ["x 1", "y", "z"]);
+
+        let editor = type_keys(lines.clone(), ":g/^/m0\n");
+        assert_eq!(rows(&editor), ["z", "x 3", "y", "x 2", "x 1"]);
+        let editor = type_keys(lines.clone(), ":g/x/m$\n");
+        assert_eq!(rows(&editor), ["y", "z", "x 1", "x 2", "x 3"]);
+        let editor = type_keys(lines.clone(), ":g/x/s//X/\n");
+        assert_eq!(rows(&editor), ["X 1", "X 2", "y", "X 3", "z"]);
+        let editor = type_keys(lines.clone(), ":g/x/s/ /\\r/\n");
+        assert_eq!(rows(&editor), ["x", "1", "x", "2", "y", "x", "3", "z"]);
+        let editor = type_keys(lines.clone(), ":g/./s/2/two/\n");
+        assert_eq!(rows(&editor)[1], "x two");
+
+        // all of it undoes in one step
+        let editor = type_keys(lines.clone(), ":g/x/d\nu");
+        assert_eq!(rows(&editor), lines);
+
+        let editor = type_keys(lines.clone(), ":g/w/d\n");
+        assert_eq!(message(&editor), "Pattern not found: w");
+        let editor = type_keys(lines.clone(), ":v/./d\n");
+        assert_eq!(message(&editor), "Pattern found in every line: .");
+        let editor = type_keys(lines.clone(), ":g/./s/q/r/\n");
+        assert_eq!(message(&editor), "E486: Pattern not found: q");
+        let editor = type_keys(lines.clone(), ":g/x/g/1/d\n");
+        assert_eq!(message(&editor), "E147: Cannot do :global recursive");
+        assert!(editor.document.marked_lines.is_none());
+
+        let editor = type_keys(lines.clone(), "jma:g/y/d\n");
+        assert_eq!(editor.document.marks.get(&'a').map(|position| position.y), Some(1));
+        let editor = type_keys(lines.clone(), "jma:g/x 1/d\n");
+        assert_eq!(editor.document.marks.get(&'a').map(|position| position.y), Some(0));
+        let editor = type_keys(lines.clone(), "jma:g/x 2/d\n");
+        assert!(!editor.document.marks.contains_key(&'a'));
+    }
+
+    #[test]
+    fn test_normal () {
+        let lines = vec!["a1", "b1", "c1", "d1"];
+
+        let editor = type_keys(lines.clone(), "jVj:normal A;\n");
+        assert_eq!(rows(&editor), ["a1", "b1;", "c1;", "d1"]);
+        let editor = type_keys(lines.clone(), "$:%norm i-\n");
+        assert_eq!(rows(&editor), ["-a1", "-b1", "-c1", "-d1"]);
+        // the insert is left with <Esc>, and all of the lines undo in one step
+        let editor = type_keys(lines.clone(), ":%norm Ax\nu");
+        assert_eq!(rows(&editor), lines);
+        assert!(editor.mode == TerminalMode::Normal);
+
+        // without a range it runs where the cursor is
+        let editor = type_keys(lines.clone(), "jl:norm dl\n");
+        assert_eq!(rows(&editor)[1], "b");
+        // the lines are numbered when it starts, so every other one goes here
+        let editor = type_keys(lines.clone(), ":%norm dd\n");
+        assert_eq!(rows(&editor), ["b1", "d1"]);
+        // a failing motion only stops the keys for its own line
+        let editor = type_keys(lines.clone(), ":%norm jdl\n");
+        assert_eq!(rows(&editor), ["a1", "1", "1", "1"]);
+        let editor = type_keys(lines.clone(), ":g/[bd]/norm Ax\n");
+        assert_eq!(rows(&editor), ["a1", "b1x", "c1", "d1x"]);
+
+        // an unfinished command gets <Esc> instead of waiting for keys that aren't coming
+        let editor = type_keys(lines.clone(), ":norm d\ndl");
+        assert_eq!(rows(&editor)[0], "1");
+        let editor = type_keys(lines.clone(), ":norm :s/a/x/\ndl");
+        assert_eq!(rows(&editor)[0], "1");
+        let editor = type_keys(lines, ":norm\n");
+        assert_eq!(message(&editor), "E471: Argument required");
+    }
+
+    #[test]
+    fn test_delete_and_move () {
+        let lines = vec!["a", "b", "c", "d"];
+
+        let mut editor = type_keys(lines.clone(), ":2,3d x\n");
+        assert_eq!(rows(&editor), ["a", "d"]);
+        assert_eq!(editor.registers.get(Some('x')).map(|register| register.text.clone()), Some("b\nc\n".to_string()));
+        let editor = type_keys(lines.clone(), ":d ab\n");
+        assert_eq!(message(&editor), "E488: Trailing characters: ab");
+
+        let editor = type_keys(lines.clone(), ":1,2m$\n");
+        assert_eq!(rows(&editor), ["c", "d", "a", "b"]);
+        assert_eq!(editor.cursor_position.y, 3);
+        let editor = type_keys(lines.clone(), "Gma:m0\n");
+        assert_eq!(rows(&editor), ["d", "a", "b", "c"]);
+        assert_eq!(editor.document.marks.get(&'a').map(|position| position.y), Some(0));
+        let editor = type_keys(lines.clone(), ":2m2\n");
+        assert_eq!(rows(&editor), lines);
+        let editor = type_keys(lines.clone(), ":1,3m2\n");
+        assert_eq!(message(&editor), "E134: Cannot move a range of lines into itself");
+        let editor = type_keys(lines, ":m\n");
+        assert_eq!(message(&editor), "E14: Invalid address");
+    }
+
+    #[test]
+    fn test_clipboard_yank () {
+        // the default clipboard copies through the terminal, which keeps what was copied
+        let terminal = MemoryTerminal::new(20, 6);
+        let editor = type_keys_into(Editor { terminal: Box::new(terminal.clone()), ..Editor::default() }, vec!["a", "b"], "\"+yyj\"*p");
+        assert_eq!(rows(&editor), ["a", "b", "a"]);
+        assert_eq!(terminal.screen.borrow().clipboard.as_deref(), Some("a\n"));
+    }
+
+    #[test]
+    fn test_prompt_editing () {
+        let script = |parts: &[&str], keys: &[Key]| parts.join(&keys_to_string(keys));
+
+        let editor = type_keys(vec!["foo"], &script(&[":%s/o/0/g", "0\n"], &[Key::Left, Key::Left]));
+        assert_eq!(rows(&editor), ["f0000"]);
+        let editor = type_keys(vec!["foo"], &script(&[":junk", "%s/o/0/\n:%s/o/1/ junk\x17\n"], &[Key::Ctrl('u')]));
+        assert_eq!(rows(&editor), ["f01"]);
+
+        // Up only goes through the lines that start with what was typed, and Down comes back to it
+        let editor = type_keys(vec!["foo"], &script(&[":%s/o/0/\n:set ic\nu:%", "\n"], &[Key::Up]));
+        assert_eq!(rows(&editor), ["f0o"]);
+        let editor = type_keys(vec!["foo"], &script(&[":%s/o/0/\n:set ic\nu:", "", "", "\n"], &[Key::Up, Key::Up, Key::Down]));
+        assert!(editor.ignorecase);
+        assert_eq!(rows(&editor), ["foo"]);
+        assert_eq!(editor.history.entries(":"), ["%s/o/0/", "set ic"]);
+        // each kind of prompt has its own
+        let editor = type_keys(vec!["foo"], &script(&["/o\n:", "\n"], &[Key::Up]));
+        assert_eq!(message(&editor), "/o");
+
+        let editor = type_keys(vec!["foo"], ":se\t ig\t\n");
+        assert!(editor.ignorecase);
+        let editor = type_keys(vec!["foo"], ":se\t noi\t\n:set ic\n:se\t noi\t\n");
+        assert!(!editor.ignorecase);
+
+        // the cursor is drawn where it is in the line being typed
+        let terminal = MemoryTerminal::new(20, 6);
+        let mut editor = Editor {
+            terminal: Box::new(terminal.clone()),
+            key_source: Box::new(ScriptedKeys::from(vec![Key::Char(':'), Key::Char('a'), Key::Char('b'), Key::Char('c'), Key::Left, Key::Left])),
+            ..Editor::default()
+        };
+        editor.document.populate(vec!["foo"]);
+        editor.process_keys(&mut EditorFSM::new()).unwrap();
+
+        let screen = terminal.screen.borrow();
+        assert_eq!(screen.lines()[5], ">| :abc");
+        assert_eq!(screen.cell(5, 5).unwrap().bg, Color::White);
+        assert_eq!(screen.cell(6, 5).unwrap().bg, Color::Reset);
+    }
+
+    #[test]
+    fn test_incremental_search () {
+        let terminal = MemoryTerminal::new(20, 6);
+        let mut lines = vec!["hay"; 100];
+        lines[50] = "a needle";
+        let mut editor = Editor {
+            terminal: Box::new(terminal.clone()),
+            key_source: Box::new(ScriptedKeys::from_str("/need")),
+            ..Editor::default()
+        };
+        editor.document.populate(lines);
+        editor.process_keys(&mut EditorFSM::new()).unwrap();
+
+        // the script stops mid-pattern, which the prompt takes as <Esc>: the last screen drawn showed the match
+        let screen = terminal.screen.borrow();
+        assert_eq!(&screen.lines()[..4], ["hay", "hay", "hay", "a needle"]);
+        assert_eq!(screen.cell(2, 3).unwrap().fg, Color::Rgb(38, 139, 210));
+        assert_eq!(screen.cell(6, 3).unwrap().fg, Color::Rgb(240, 240, 250));
+        assert_eq!(screen.lines()[5], ">| /need_");
+        assert_eq!((editor.cursor_position.y, editor.offset.y), (0, 0));
+    }
+
+    #[test]
+    fn test_draw_screen () {
+        let terminal = MemoryTerminal::new(40, 8);
+        let mut editor = Editor { terminal: Box::new(terminal.clone()), ..Editor::default() };
+        editor.document.populate(vec!["fn main() {", "    let x = 1;", "}"]);
+        editor.cursor_position = Position { x: 4, y: 1 };
+        editor.mode = TerminalMode::Insert;
+        editor.status_message = Some(StatusMessage::from("saved".to_string()));
+
+        editor.refresh_screen().unwrap();
+        {
+            let screen = terminal.screen.borrow();
+            let lines = screen.lines();
+            assert_eq!(&lines[..6], ["fn main() {", "    let x = 1;", "}", "~", "~", "~"]);
+            // the clock at the end of the status bar changes, so only what comes before it is compared
+            assert!(lines[6].starts_with(" ⊙  -- INSERT --          67% /3  "));
+            assert_eq!(lines[7], ">| saved");
+
+            assert_eq!(screen.cell(0, 0).unwrap().bg, Color::Black);
+            assert_eq!(screen.cell(0, 1).unwrap().bg, Color::Reset);
+            assert_eq!(screen.cell(5, 6).unwrap().bg, Color::Rgb(38, 139, 210));
+            assert_eq!(screen.cell(0, 7).unwrap().fg, Color::LightBlack);
+            assert_eq!((screen.cursor.x, screen.cursor.y, screen.cursor_visible), (4, 1, true));
+        }
+
+        editor.document.populate(vec![]);
+        editor.move_cursor(Position::default());
+        editor.draw_rows();
+        editor.draw_message_bar(Some("w foo"));
+        let lines = terminal.screen.borrow().lines();
+        assert_eq!(lines[2], format!("~        Termite Editor. v{}", PACKAGE_VERSION));
+        assert_eq!(lines[7], ">| w foo_");
+
+        editor.document.populate(vec!["one two", "three"]);
+        editor.mode = TerminalMode::Normal;
+        editor.cursor_position = Position { x: 4, y: 0 };
+        editor.start_visual(RangeKind::Charwise);
+        editor.cursor_position = Position { x: 1, y: 1 };
+        editor.update_selection();
+        editor.refresh_screen().unwrap();
+        let screen = terminal.screen.borrow();
+        let selected = |x: u16, y: u16| screen.cell(x, y).unwrap().bg == Color::Rgb(68, 88, 122);
+        assert_eq!((selected(3, 0), selected(4, 0), selected(6, 0)), (false, true, true));
+        assert_eq!((selected(0, 1), selected(1, 1), selected(2, 1)), (true, true, false));
+        assert!(screen.lines()[6].starts_with(" ⊙  -- VISUAL -- "));
+    }
+
+    #[test]
+    fn test_unmapped_keys () {
+        let terminal = MemoryTerminal::new(20, 6);
+        let mut editor = Editor {
+            terminal: Box::new(terminal.clone()),
+            key_source: Box::new(ScriptedKeys::from(vec![Key::F(1), Key::Alt('x'), Key::Left])),
+            ..Editor::default()
+        };
+        editor.document.populate(vec!["foo"]);
+        let mut fsm = EditorFSM::new();
+        for _ in 0..3 {
+            editor.process_keys(&mut fsm).unwrap();
+        }
+
+        assert_eq!(rows(&editor), ["foo"]);
+        assert!(terminal.screen.borrow().lines().iter().all(String::is_empty));
+    }
 
     #[test]
     fn test_find_char_column () {
@@ -504,7 +2138,7 @@ mod tests {
         let elapsed = now.elapsed().as_millis();
         println!("elapsed time for parallelized find char {}", elapsed);
 
-        assert_eq!(res_coordinate, Coordinate(1, 99_999));
+        assert_eq!(res_coordinate, Coordinate(1, 100_000));
 
         // let now = Instant::now();
         // let res_coordinate = editor.find_char_column_n(&current_position, SearchDirection::Forward, "{", false);
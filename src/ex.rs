@@ -0,0 +1,686 @@
+use std::cmp::{max, min};
+use std::fs;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::automata::EditorFSM;
+use crate::document::LineMarks;
+use crate::editor::{Editor, OPTION_NAMES};
+use crate::regex::Regex;
+use crate::registers::{Register, Registers};
+use crate::utils::{string_to_keys, LineRange, Operator, Position, RangeKind, SearchDirection, StatusMessage, TerminalMode};
+
+// the pieces of a ':' command line, in the order they are typed: the range, the command's name, a '!' and the argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(usize),
+    Current,                // '.'
+    Last,                   // '$'
+    Whole,                  // '%'
+    Mark(char),             // "'a", "'<"...
+    Offset(isize),          // '+N' or '-N'
+    Separator(char),        // ',' or ';'
+    Name(String),
+    Bang,
+    Argument(String),
+}
+
+// a command line with its range worked out against the editor. lines count from 1
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExCommand {
+    pub range:          Option<LineRange>,
+    pub name:           String,
+    pub bang:           bool,
+    pub argument:       String,
+}
+
+pub type Handler = fn(&mut Editor, &mut EditorFSM, &ExCommand) -> Result<(), String>;
+
+// what Tab completes in a command's argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgumentKind {
+    Text,
+    File,
+    Setting,
+}
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name:           &'static str, // written the way vim's help does, e.g. "w[rite]": the part in brackets may be left out
+    pub range:          bool,
+    pub bang:           bool,
+    pub argument:       ArgumentKind,
+    pub handler:        Handler,
+}
+
+impl Command {
+    // whether 'typed' is the command's name or an abbreviation of it that is long enough
+    pub fn matches(&self, typed: &str) -> bool {
+        let (required, optional) = self.name.split_once('[').unwrap_or((self.name, "]"));
+        typed.starts_with(required) && format!("{}{}", required, optional.trim_end_matches(']')).starts_with(typed)
+    }
+
+    pub fn full_name(&self) -> String {
+        self.name.replace(['[', ']'], "")
+    }
+}
+
+pub struct Registry {
+    pub commands:       Vec<Command>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self { commands: vec![] };
+        registry.register("q[uit]", false, true, ArgumentKind::Text, quit);
+        registry.register("w[rite]", false, true, ArgumentKind::File, write);
+        registry.register("wq", false, true, ArgumentKind::File, write_quit);
+        registry.register("x[it]", false, true, ArgumentKind::File, exit);
+        registry.register("sav[eas]", false, true, ArgumentKind::File, save_as);
+        registry.register("se[t]", false, false, ArgumentKind::Setting, set);
+        registry.register("s[ubstitute]", true, false, ArgumentKind::Text, substitute);
+        registry.register("g[lobal]", true, true, ArgumentKind::Text, global);
+        registry.register("v[global]", true, false, ArgumentKind::Text, vglobal);
+        registry.register("d[elete]", true, false, ArgumentKind::Text, delete);
+        registry.register("m[ove]", true, false, ArgumentKind::Text, move_lines);
+        registry.register("norm[al]", true, true, ArgumentKind::Text, normal);
+
+        registry
+    }
+}
+
+impl Registry {
+    // an abbreviation that fits more than one command goes to whichever was registered first
+    pub fn register(&mut self, name: &'static str, range: bool, bang: bool, argument: ArgumentKind, handler: Handler) {
+        self.commands.push(Command { name, range, bang, argument, handler });
+    }
+
+    pub fn find(&self, typed: &str) -> Option<Command> {
+        self.commands.iter().find(|command| command.matches(typed)).copied()
+    }
+}
+
+// runs a command line, saying in the message bar what went wrong if anything did
+pub fn execute(editor: &mut Editor, fsm: &mut EditorFSM, line: &str) {
+    if let Err(error) = run(editor, fsm, line) {
+        editor.status_message = Some(StatusMessage::from(error));
+        editor.abort_replay();
+    }
+}
+
+pub fn run(editor: &mut Editor, fsm: &mut EditorFSM, line: &str) -> Result<(), String> {
+    let command = parse(editor, line)?;
+    // a range on its own goes to its last line
+    if command.name.is_empty() {
+        if let Some((_, last)) = command.range {
+            editor.go_to_line(last);
+        }
+        return Ok(());
+    }
+
+    let found = editor.ex_commands.find(&command.name).ok_or_else(|| format!("E492: Not an editor command: {}", line.trim()))?;
+    if command.range.is_some() && !found.range {
+        return Err("E481: No range allowed".to_string());
+    }
+    if command.bang && !found.bang {
+        return Err("E477: No ! allowed".to_string());
+    }
+
+    (found.handler)(editor, fsm, &command)
+}
+
+pub fn parse(editor: &Editor, line: &str) -> Result<ExCommand, String> {
+    let mut command = ExCommand::default();
+    let mut range = vec![];
+    for token in tokenize(line)? {
+        match token {
+            Token::Name(name) => command.name = name,
+            Token::Bang => command.bang = true,
+            Token::Argument(argument) => command.argument = argument,
+            address => range.push(address),
+        }
+    }
+    command.range = resolve_range(editor, &range)?;
+
+    Ok(command)
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(_, c)) = chars.peek() {
+        let token = match c {
+            ' ' | '\t' | ':' => None,
+            '0'..='9' => {
+                tokens.push(Token::Number(take_number(&mut chars)?.unwrap_or(0)));
+                continue;
+            },
+            '.' => Some(Token::Current),
+            '$' => Some(Token::Last),
+            '%' => Some(Token::Whole),
+            ',' | ';' => Some(Token::Separator(c)),
+            '\'' => {
+                chars.next();
+                match chars.peek() {
+                    Some(&(_, name)) => Some(Token::Mark(name)),
+                    None => return Err("E20: Mark not set".to_string()),
+                }
+            },
+            '+' | '-' => {
+                chars.next();
+                // a sign with no number is one line
+                let amount = take_number(&mut chars)?.unwrap_or(1) as isize;
+                tokens.push(Token::Offset(if c == '+' { amount } else { -amount }));
+                continue;
+            },
+            _ => break,
+        };
+        chars.next();
+        tokens.extend(token);
+    }
+
+    let rest = &line[chars.peek().map(|(index, _)| *index).unwrap_or(line.len())..];
+    // names are letters, except for one character commands like '&'
+    let name_len = match rest.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len()),
+        Some(c) => c.len_utf8(),
+        None => return Ok(tokens),
+    };
+    tokens.push(Token::Name(rest[..name_len].to_string()));
+
+    let mut rest = &rest[name_len..];
+    if let Some(after) = rest.strip_prefix('!') {
+        tokens.push(Token::Bang);
+        rest = after;
+    }
+    let argument = rest.trim_start();
+    if !argument.is_empty() {
+        tokens.push(Token::Argument(argument.to_string()));
+    }
+
+    Ok(tokens)
+}
+
+fn take_number(chars: &mut Peekable<CharIndices>) -> Result<Option<usize>, String> {
+    let mut digits = String::new();
+    while let Some(&(_, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit()) {
+        digits.push(c);
+        chars.next();
+    }
+
+    if digits.is_empty() {
+        return Ok(None);
+    }
+    digits.parse().map(Some).map_err(|_| "E16: Invalid range".to_string())
+}
+
+// what Tab at the end of 'text' completes: a command's name, or the file or option its argument names.
+// the index is the char in 'text' the word being completed starts at
+pub fn complete(editor: &Editor, text: &str) -> Option<(usize, Vec<String>)> {
+    let tokens = tokenize(text).ok()?;
+    let name = tokens.iter().find_map(|token| match token {
+        Token::Name(name) => Some(name.as_str()),
+        _ => None,
+    });
+    let in_argument = tokens.iter().any(|token| matches!(token, Token::Bang | Token::Argument(_))) || text.ends_with(char::is_whitespace);
+
+    let (word, candidates) = match name {
+        Some(name) if in_argument => {
+            let word = text.rsplit(char::is_whitespace).next().unwrap_or("");
+            let candidates = match editor.ex_commands.find(name)?.argument {
+                ArgumentKind::File => complete_path(word),
+                ArgumentKind::Setting => complete_option(word),
+                ArgumentKind::Text => return None,
+            };
+            (word, candidates)
+        },
+        _ => {
+            let word = name.unwrap_or("");
+            let mut candidates: Vec<String> = editor.ex_commands.commands.iter()
+                .map(Command::full_name)
+                .filter(|full_name| full_name.starts_with(word))
+                .collect();
+            candidates.sort();
+            candidates.dedup();
+            (word, candidates)
+        },
+    };
+
+    Some((text.chars().count().saturating_sub(word.chars().count()), candidates))
+}
+
+// the files and directories 'word' is the start of, directories ending in '/'. hidden ones only come up when 'word' starts with a '.'
+pub fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(index) => word.split_at(index + 1),
+        None => ("", word),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, slash))
+        })
+        .collect();
+    candidates.sort();
+
+    candidates
+}
+
+// the options 'word' is the start of, with 'no' in front for the ones that can be switched off
+pub fn complete_option(word: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = OPTION_NAMES.iter()
+        .filter(|(name, _)| name.starts_with(word))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if let Some(word) = word.strip_prefix("no") {
+        candidates.extend(OPTION_NAMES.iter()
+            .filter(|(name, boolean)| *boolean && name.starts_with(word))
+            .map(|(name, _)| format!("no{}", name)));
+    }
+
+    candidates
+}
+
+// the lines a range stands for. a missing address is the cursor line, ';' puts the cursor on the address before it
+// for the ones after, and a backwards range is turned around
+pub fn resolve_range(editor: &Editor, tokens: &[Token]) -> Result<Option<LineRange>, String> {
+    let invalid = || "E16: Invalid range".to_string();
+    let lines = editor.document.rows.len();
+    let mut current = editor.cursor_position.y as usize + 1;
+    let mut addresses = vec![];
+    let mut address = None;
+
+    for token in tokens {
+        match token {
+            // a number right after an address adds to it, so '.5' is '.+5'
+            Token::Number(number) => address = Some(address.map_or(*number, |line: usize| line.saturating_add(*number))),
+            Token::Current => address = Some(current),
+            Token::Last => address = Some(lines),
+            Token::Whole => {
+                addresses.push(1);
+                address = Some(lines);
+            },
+            Token::Mark(name) => address = Some(mark_line(editor, *name)?),
+            Token::Offset(offset) => {
+                let line = address.unwrap_or(current).checked_add_signed(*offset).ok_or_else(invalid)?;
+                address = Some(line);
+            },
+            Token::Separator(separator) => {
+                let line = address.take().unwrap_or(current);
+                if *separator == ';' {
+                    current = line;
+                }
+                addresses.push(line);
+            },
+            _ => (),
+        }
+    }
+    match address {
+        Some(line) => addresses.push(line),
+        None if !addresses.is_empty() => addresses.push(current),
+        None => return Ok(None),
+    }
+
+    // with more than two addresses, the last two count
+    let last = addresses[addresses.len() - 1];
+    let first = addresses.len().checked_sub(2).map_or(last, |index| addresses[index]);
+    if max(first, last) > lines {
+        return Err(invalid());
+    }
+
+    Ok(Some((min(first, last), max(first, last))))
+}
+
+// the line a mark is on. '<' and '>' are the first and last lines of the last selection
+pub fn mark_line(editor: &Editor, name: char) -> Result<usize, String> {
+    let y = match name {
+        '<' => editor.last_selection.as_ref().map(|(selection, _)| selection.start.1),
+        '>' => editor.last_selection.as_ref().map(|(selection, _)| selection.end.1),
+        _ => editor.document.marks.get(&name).map(|position| position.y),
+    };
+
+    y.map(|y| y as usize + 1).ok_or_else(|| "E20: Mark not set".to_string())
+}
+
+// the text up to the first 'delimiter' not escaped with a backslash, where '\' followed by the delimiter is the
+// delimiter itself, and whatever comes after it. None when there is no closing delimiter
+pub fn split_delimited(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, next)) if next == delimiter => part.push(next),
+                Some((_, next)) => {
+                    part.push('\\');
+                    part.push(next);
+                },
+                None => part.push('\\'),
+            },
+            _ if c == delimiter => return (part, Some(&text[index + c.len_utf8()..])),
+            _ => part.push(c),
+        }
+    }
+
+    (part, None)
+}
+
+fn argument(command: &ExCommand) -> Option<&str> {
+    Some(command.argument.trim()).filter(|argument| !argument.is_empty())
+}
+
+fn quit(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    if editor.document.dirty && !command.bang {
+        return Err("E37: No write since last change (add ! to override)".to_string());
+    }
+    editor.should_quit = true;
+
+    Ok(())
+}
+
+fn write(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    editor.save_document(argument(command));
+
+    Ok(())
+}
+
+fn write_quit(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    if editor.save_document(argument(command)) {
+        editor.should_quit = true;
+    }
+
+    Ok(())
+}
+
+fn exit(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    // only touches the file when there is something to write
+    if (!editor.document.dirty && argument(command).is_none()) || editor.save_document(argument(command)) {
+        editor.should_quit = true;
+    }
+
+    Ok(())
+}
+
+fn save_as(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    let file_name = argument(command).ok_or_else(|| "E471: Argument required".to_string())?;
+    editor.save_document(Some(file_name));
+
+    Ok(())
+}
+
+fn set(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    editor.set_option(command.argument.trim());
+
+    Ok(())
+}
+
+fn substitute(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    let current = editor.cursor_position.y as usize + 1;
+    let (first, last) = command.range.unwrap_or((current, current));
+
+    editor.substitute(first, last, &command.argument)
+}
+
+fn global(editor: &mut Editor, fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    run_global(editor, fsm, command, command.bang)
+}
+
+fn vglobal(editor: &mut Editor, fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    run_global(editor, fsm, command, true)
+}
+
+// ':g/pattern/command' marks the lines in the range (the whole file by default) that match the pattern, or the ones
+// that don't for ':g!' and ':v', and then runs the command with the cursor on each marked line that is still there.
+// the marks move along with the lines as the command adds and removes them
+fn run_global(editor: &mut Editor, fsm: &mut EditorFSM, command: &ExCommand, invert: bool) -> Result<(), String> {
+    if editor.document.marked_lines.is_some() {
+        return Err("E147: Cannot do :global recursive".to_string());
+    }
+    let delimiter = match command.argument.chars().next() {
+        Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|".contains(c) => c,
+        Some(_) => return Err("E146: Regular expressions can't be delimited by letters".to_string()),
+        None => return Err("E148: Regular expression missing from :global".to_string()),
+    };
+    let (pattern, line) = split_delimited(&command.argument[delimiter.len_utf8()..], delimiter);
+    let line = line.unwrap_or("").to_string();
+
+    let pattern = match (pattern.is_empty(), editor.last_search.as_ref()) {
+        (false, _) => pattern,
+        (true, Some((last, _))) => last.clone(),
+        (true, None) => return Err("E35: No previous regular expression".to_string()),
+    };
+    let regex = Regex::new(&pattern, editor.ignorecase, editor.smartcase)?;
+    editor.last_search = Some((pattern.clone(), SearchDirection::Forward));
+    editor.set_highlighted_word(Some(regex.clone()));
+
+    let (first, last) = command.range.unwrap_or((1, editor.document.rows.len()));
+    let first = first.saturating_sub(1);
+    let lines: Vec<usize> = editor.document.rows.get(first..last).unwrap_or(&[])
+        .par_iter()
+        .enumerate()
+        .filter(|(_, row)| regex.is_match(&row.string) != invert)
+        .map(|(index, _)| first.saturating_add(index))
+        .collect();
+    if lines.is_empty() {
+        let message = if invert { "Pattern found in every line" } else { "Pattern not found" };
+        editor.status_message = Some(StatusMessage::from(format!("{}: {}", message, pattern)));
+        return Ok(());
+    }
+
+    editor.document.marked_lines = Some(LineMarks::new(lines));
+    let (mut result, mut found) = (Ok(()), false);
+    while let Some(y) = editor.document.marked_lines.as_mut().and_then(LineMarks::pop_first) {
+        editor.cursor_position = Position { x: 0, y: y as u16 };
+        match run(editor, fsm, &line) {
+            // a ':s' only fails for not matching when it fails on every line
+            Err(error) if error.starts_with("E486") => result = Err(error),
+            Err(error) => {
+                result = Err(error);
+                found = false;
+                break;
+            },
+            Ok(()) => found = true,
+        }
+    }
+    editor.document.marked_lines = None;
+    editor.restore_cursor(editor.cursor_position);
+
+    if found { Ok(()) } else { result }
+}
+
+// ':d [x]' deletes the lines in the range into register x
+fn delete(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    let mut chars = command.argument.trim().chars();
+    let register = chars.next();
+    if register.is_some_and(|name| !Registers::is_valid(name)) || chars.next().is_some() {
+        return Err(format!("E488: Trailing characters: {}", command.argument.trim()));
+    }
+
+    let current = editor.cursor_position.y as usize + 1;
+    let (first, last) = command.range.unwrap_or((current, current));
+    let text = editor.document.delete_lines(first.saturating_sub(1) as u16, last.saturating_sub(1) as u16);
+    editor.store_register(register, Operator::Delete, Register { text, kind: RangeKind::Linewise });
+    editor.go_to_line(first);
+
+    Ok(())
+}
+
+// ':m {address}' puts the lines in the range below the line at the address, where 0 is above the first line
+fn move_lines(editor: &mut Editor, _fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    let current = editor.cursor_position.y as usize + 1;
+    let (first, last) = command.range.unwrap_or((current, current));
+    let tokens = tokenize(command.argument.trim())?;
+    let address = match resolve_range(editor, &tokens)? {
+        Some((_, address)) if tokens.iter().all(|token| !matches!(token, Token::Name(_) | Token::Bang | Token::Argument(_))) => address,
+        _ => return Err("E14: Invalid address".to_string()),
+    };
+    if address >= first && address < last {
+        return Err("E134: Cannot move a range of lines into itself".to_string());
+    }
+
+    let count = last.saturating_add(1).saturating_sub(first);
+    // below the range, the lines land after the ones between that move up to take their place
+    let target = if address >= last { address.saturating_sub(count) } else { address };
+    if target.saturating_add(1) != first {
+        let moved: Vec<String> = editor.document.rows[first.saturating_sub(1)..last].iter().map(|row| row.string.clone()).collect();
+        // the named marks go along with their lines, which taking the lines out and putting them back would lose
+        let marks: Vec<(char, Position)> = editor.document.marks.iter()
+            .filter(|(_, position)| (first.saturating_sub(1)..last).contains(&(position.y as usize)))
+            .map(|(name, position)| (*name, *position))
+            .collect();
+
+        editor.document.splice_lines(first.saturating_sub(1), count, &[]);
+        editor.document.splice_lines(target, 0, &moved);
+        for (name, position) in marks {
+            let y = (position.y as usize).saturating_add(target).saturating_add(1).saturating_sub(first);
+            editor.document.marks.insert(name, Position { x: position.x, y: y as u16 });
+        }
+    }
+    editor.go_to_line(target.saturating_add(count));
+
+    Ok(())
+}
+
+// ':normal {keys}' runs the keys as if they were typed in normal mode, once at the cursor or once on each line of the
+// range with the cursor at its start. keys left unfinished get <Esc>, and the changes are all one undo step
+fn normal(editor: &mut Editor, fsm: &mut EditorFSM, command: &ExCommand) -> Result<(), String> {
+    let keys = string_to_keys(&command.argument);
+    if keys.is_empty() {
+        return Err("E471: Argument required".to_string());
+    }
+
+    let lines = command.range.map(|(first, last)| (first.saturating_sub(1)..last).map(Some).collect()).unwrap_or(vec![None]);
+    let outer = editor.normal_floor.replace(editor.pending_keys.len());
+    for y in lines {
+        // like vim, the lines are the ones numbered in the range when it started, so ones added or removed aren't followed
+        if let Some(y) = y {
+            if y >= editor.document.rows.len() {
+                break;
+            }
+            editor.cursor_position = Position { x: 0, y: y as u16 };
+            editor.movement_data.last_nav_position.x = 0;
+        }
+        editor.replay_keys(fsm, keys.clone());
+        while editor.mode == TerminalMode::Insert {
+            if let Err(error) = editor.process_keys(fsm) {
+                editor.status_message = Some(StatusMessage::from(error.to_string()));
+                break;
+            }
+        }
+        fsm.failure_exit();
+    }
+    editor.normal_floor = outer;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize () {
+        assert_eq!(tokenize("'<,'>s/a/b/").unwrap(), vec![
+            Token::Mark('<'), Token::Separator(','), Token::Mark('>'), Token::Name("s".to_string()), Token::Argument("/a/b/".to_string()),
+        ]);
+        assert_eq!(tokenize(" :.-,+2 wq! out.txt").unwrap(), vec![
+            Token::Current, Token::Offset(-1), Token::Separator(','), Token::Offset(2),
+            Token::Name("wq".to_string()), Token::Bang, Token::Argument("out.txt".to_string()),
+        ]);
+        assert_eq!(tokenize("%&&").unwrap(), vec![Token::Whole, Token::Name("&".to_string()), Token::Argument("&".to_string())]);
+        assert_eq!(tokenize("12").unwrap(), vec![Token::Number(12)]);
+        assert!(tokenize("'").is_err());
+    }
+
+    #[test]
+    fn test_resolve_range () {
+        let mut editor = Editor::default();
+        editor.document.populate(vec!["a"; 10]);
+        editor.cursor_position = Position { x: 0, y: 4 };
+        editor.document.marks.insert('a', Position { x: 3, y: 1 });
+        let range = |line: &str| parse(&editor, line).map(|command| command.range);
+
+        assert_eq!(range("%"), Ok(Some((1, 10))));
+        assert_eq!(range("."), Ok(Some((5, 5))));
+        assert_eq!(range(".,$"), Ok(Some((5, 10))));
+        assert_eq!(range("-2,+"), Ok(Some((3, 6))));
+        assert_eq!(range("'a,.3"), Ok(Some((2, 8))));
+        assert_eq!(range("2;+1"), Ok(Some((2, 3))));
+        assert_eq!(range("7,2"), Ok(Some((2, 7))));
+        assert_eq!(range("3,"), Ok(Some((3, 5))));
+        assert_eq!(range("q"), Ok(None));
+        assert_eq!(range("11"), Err("E16: Invalid range".to_string()));
+        assert_eq!(range("-9"), Err("E16: Invalid range".to_string()));
+        assert_eq!(range("'b"), Err("E20: Mark not set".to_string()));
+        assert_eq!(range("'<"), Err("E20: Mark not set".to_string()));
+    }
+
+    #[test]
+    fn test_complete_path () {
+        let dir = std::env::temp_dir().join(format!("termite_complete_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        for file in ["main.rs", "Cargo.toml", ".hidden"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let dir_name = format!("{}/", dir.display());
+
+        let names = |word: &str| complete_path(&format!("{}{}", dir_name, word))
+            .into_iter()
+            .map(|path| path.trim_start_matches(&dir_name).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names(""), ["Cargo.toml", "main.rs", "src/"]);
+        assert_eq!(names("s"), ["src/"]);
+        assert_eq!(names("."), [".hidden"]);
+        assert!(names("x").is_empty());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_registry () {
+        let registry = Registry::default();
+        let name = |typed: &str| registry.find(typed).map(|command| command.name);
+
+        assert_eq!(name("w"), Some("w[rite]"));
+        assert_eq!(name("wri"), Some("w[rite]"));
+        assert_eq!(name("write"), Some("w[rite]"));
+        assert_eq!(name("writes"), None);
+        assert_eq!(name("wq"), Some("wq"));
+        assert_eq!(name("s"), Some("s[ubstitute]"));
+        assert_eq!(name("se"), Some("se[t]"));
+        assert_eq!(name("sa"), None);
+        assert_eq!(name("sav"), Some("sav[eas]"));
+
+        let (mut editor, mut fsm) = (Editor::default(), EditorFSM::new());
+        editor.document.populate(vec!["one", "two"]);
+        execute(&mut editor, &mut fsm, "frobnicate now");
+        assert_eq!(editor.status_message.take().map(|status| status.text), Some("E492: Not an editor command: frobnicate now".to_string()));
+        execute(&mut editor, &mut fsm, "1,2set ic");
+        assert_eq!(editor.status_message.take().map(|status| status.text), Some("E481: No range allowed".to_string()));
+        execute(&mut editor, &mut fsm, "set! ic");
+        assert_eq!(editor.status_message.take().map(|status| status.text), Some("E477: No ! allowed".to_string()));
+
+        assert_eq!(complete(&editor, "w"), Some((0, vec!["wq".to_string(), "write".to_string()])));
+        assert_eq!(complete(&editor, "%su"), Some((1, vec!["substitute".to_string()])));
+        assert_eq!(complete(&editor, "set nou"), Some((4, vec!["noundofile".to_string()])));
+        assert_eq!(complete(&editor, "s/a/"), None);
+
+        editor.document.dirty = true;
+        execute(&mut editor, &mut fsm, "q");
+        assert!(!editor.should_quit);
+        execute(&mut editor, &mut fsm, "qui!");
+        assert!(editor.should_quit);
+    }
+}
@@ -1,4 +1,4 @@
-use termion::color;
+use crate::terminal::Color;
 pub enum Type {
     None,
     Number,
@@ -13,17 +13,17 @@ pub enum Type {
 }
 
 impl Type {
-    pub fn to_color(&self) -> impl color::Color {
+    pub fn to_color(&self) -> Color {
         match self {
-            Type::Number => color::Rgb(220, 203, 203),
-            Type::Pattern => color::Rgb(38, 139, 210),
-            Type::String => color::Rgb(204, 95, 104),
-            Type::Character => color::Rgb(204, 95, 104),
-            Type::Comment | Type::MultilineComment => color::Rgb(153, 153, 150),
-            Type::PrimaryKeyword => color::Rgb(183, 65, 14),
-            Type::SecondaryKeyword => color::Rgb(212, 220, 160),
-            Type::KnownItem => color::Rgb(42, 161, 192),
-            _ => color::Rgb(240, 240, 250),
+            Type::Number => Color::Rgb(220, 203, 203),
+            Type::Pattern => Color::Rgb(38, 139, 210),
+            Type::String => Color::Rgb(204, 95, 104),
+            Type::Character => Color::Rgb(204, 95, 104),
+            Type::Comment | Type::MultilineComment => Color::Rgb(153, 153, 150),
+            Type::PrimaryKeyword => Color::Rgb(183, 65, 14),
+            Type::SecondaryKeyword => Color::Rgb(212, 220, 160),
+            Type::KnownItem => Color::Rgb(42, 161, 192),
+            _ => Color::Rgb(240, 240, 250),
         }
     }
 }
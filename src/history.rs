@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::{HISTORY_FILE, HISTORY_SIZE};
+use crate::document::write_atomically;
+use crate::utils::state_home;
+
+// the lines entered at each kind of prompt (':', '/' and '?'), oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    pub entries:            HashMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub file:               Option<PathBuf>, // where it is kept between sessions; with None it only lasts as long as the editor
+}
+
+pub fn default_history_file() -> Option<PathBuf> {
+    Some(state_home()?.join(HISTORY_FILE))
+}
+
+impl History {
+    // what was kept in 'file', or an empty history when there is nothing there to read
+    pub fn load(file: Option<PathBuf>) -> Self {
+        let history = file.as_ref()
+            .and_then(|file| fs::read(file).ok())
+            .and_then(|data| serde_json::from_slice::<History>(&data).ok())
+            .unwrap_or_default();
+
+        Self { file, ..history }
+    }
+
+    pub fn entries(&self, kind: &str) -> &[String] {
+        self.entries.get(kind).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // a line entered again moves to the end, and past HISTORY_SIZE lines the oldest ones go
+    pub fn add(&mut self, kind: &str, line: &str) {
+        let entries = self.entries.entry(kind.to_string()).or_default();
+        entries.retain(|entry| entry != line);
+        entries.push(line.to_string());
+        if entries.len() > HISTORY_SIZE {
+            entries.drain(..entries.len() - HISTORY_SIZE);
+        }
+
+        if let Some(file) = self.file.as_ref() {
+            let _ = self.save(file);
+        }
+    }
+
+    pub fn save(&self, file: &Path) -> Result<(), Error> {
+        let data = serde_json::to_vec(self).map_err(Error::other)?;
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        write_atomically(file, &data, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_history () {
+        let file = env::temp_dir().join(format!("termite_history_{}", std::process::id())).join("history.json");
+        let mut history = History::load(Some(file.clone()));
+        assert!(history.entries(":").is_empty());
+
+        history.add(":", "w");
+        history.add("/", "foo");
+        history.add(":", "set ic");
+        history.add(":", "w");
+        assert_eq!(history.entries(":"), ["set ic", "w"]);
+
+        for index in 0..HISTORY_SIZE {
+            history.add("?", &index.to_string());
+        }
+        history.add("?", "last");
+        assert_eq!(history.entries("?").len(), HISTORY_SIZE);
+        assert_eq!(history.entries("?").first().map(String::as_str), Some("1"));
+
+        // every line entered is written out, so the next session starts with it
+        let reloaded = History::load(Some(file.clone()));
+        assert_eq!(reloaded.entries(":"), ["set ic", "w"]);
+        assert_eq!(reloaded.entries("/"), ["foo"]);
+        assert_eq!(reloaded.entries("?").last().map(String::as_str), Some("last"));
+
+        fs::remove_dir_all(file.parent().unwrap()).ok();
+    }
+}
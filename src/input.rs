@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+use std::io::{stdin, Error, Stdin};
+use termion::event::Key;
+use termion::input::{Keys, TermRead};
+use crate::utils::string_to_keys;
+
+// where the editor's keys come from. every read (normal mode, insert mode, prompts and the fsm) goes through one of these
+pub trait KeySource {
+    // None once the source has run dry
+    fn next_key(&mut self) -> Option<Result<Key, Error>>;
+}
+
+pub struct StdinKeys {
+    keys:                   Keys<Stdin>,
+}
+
+impl Default for StdinKeys {
+    fn default() -> Self {
+        Self { keys: stdin().keys() }
+    }
+}
+
+impl KeySource for StdinKeys {
+    fn next_key(&mut self) -> Option<Result<Key, Error>> {
+        self.keys.next()
+    }
+}
+
+// a fixed list of keys, for driving the editor without a terminal
+#[derive(Default)]
+pub struct ScriptedKeys {
+    pub keys:               VecDeque<Key>,
+}
+
+impl ScriptedKeys {
+    // <Esc>, <BS> and ctrl keys are written the way macro registers hold them, e.g. "ifoo\x1b" or "\x12" for Ctrl-R
+    pub fn from_str(script: &str) -> Self {
+        Self { keys: string_to_keys(script).into() }
+    }
+}
+
+impl From<Vec<Key>> for ScriptedKeys {
+    fn from(keys: Vec<Key>) -> Self {
+        Self { keys: keys.into() }
+    }
+}
+
+impl KeySource for ScriptedKeys {
+    fn next_key(&mut self) -> Option<Result<Key, Error>> {
+        self.keys.pop_front().map(Ok)
+    }
+}
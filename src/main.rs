@@ -1,13 +1,20 @@
 #![allow(unused)]
 
 mod automata;
+mod clipboard;
 mod config;
 mod document;
 mod editor;
+mod ex;
 mod filetype;
 mod highlighting;
+mod history;
+mod input;
+mod regex;
+mod registers;
 mod row;
 mod terminal;
+mod undo;
 mod utils;
 
 
@@ -0,0 +1,876 @@
+use unicode_segmentation::UnicodeSegmentation;
+use crate::utils::is_word;
+
+// a compiled search pattern, matched one grapheme at a time.
+// the syntax is vim's: groups, alternation and counted repeats are written \( \) \| \+ \= \? \{n,m}, and \< \> are word edges.
+// after \v ("very magic") they lose the backslash, so rust-style patterns such as (foo|bar)+ work as written,
+// along with rust's (?:...) groups and lazy *? +? ?? {n,m}?. \b is a word boundary in both
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regex {
+    pub pattern:            String,
+    pub ignore_case:        bool,
+    root:                   Node,
+    program:                Vec<Inst>,
+    groups:                 usize,
+}
+
+// the match, then what each \(group\) matched, as (start, end) grapheme columns
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Grapheme(String),
+    Any,
+    Class(Class),
+    LineStart,
+    LineEnd,
+    WordStart,
+    WordEnd,
+    WordBoundary,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    negated:                bool,
+    items:                  Vec<ClassItem>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Digit,
+    Space,
+    Word,
+    Alpha,
+    Alnum,
+    Lower,
+    Upper,
+    Hex,
+    Punct,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        let fold = |c: char| if ignore_case { c.to_lowercase().next().unwrap_or(c) } else { c };
+        match self {
+            ClassItem::Char(x) => fold(*x) == fold(c),
+            ClassItem::Range(from, to) => {
+                (*from..=*to).contains(&c)
+                    || (ignore_case && c.to_lowercase().chain(c.to_uppercase()).any(|c| (*from..=*to).contains(&c)))
+            },
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Space => c.is_whitespace(),
+            ClassItem::Word => is_word(c),
+            ClassItem::Alpha => c.is_alphabetic(),
+            ClassItem::Alnum => c.is_alphanumeric(),
+            ClassItem::Lower => c.is_lowercase() || (ignore_case && c.is_uppercase()),
+            ClassItem::Upper => c.is_uppercase() || (ignore_case && c.is_lowercase()),
+            ClassItem::Hex => c.is_ascii_hexdigit(),
+            ClassItem::Punct => c.is_ascii_punctuation(),
+        }
+    }
+}
+
+impl Class {
+    // a grapheme is classed by its base character, so [a-z] takes 'é' written as e and a combining accent
+    fn matches(&self, grapheme: &str, ignore_case: bool) -> bool {
+        let c = match grapheme.chars().next() {
+            Some(c) => c,
+            None => return false,
+        };
+        self.items.iter().any(|item| item.matches(c, ignore_case)) != self.negated
+    }
+
+    fn shorthand(key: char) -> Option<Class> {
+        let item = match key.to_ascii_lowercase() {
+            'd' => ClassItem::Digit,
+            's' => ClassItem::Space,
+            'w' => ClassItem::Word,
+            'a' => ClassItem::Alpha,
+            'l' => ClassItem::Lower,
+            'u' => ClassItem::Upper,
+            'x' => ClassItem::Hex,
+            'h' => return Some(Class {
+                negated: key.is_ascii_uppercase(),
+                items: vec![ClassItem::Range('a', 'z'), ClassItem::Range('A', 'Z'), ClassItem::Char('_')],
+            }),
+            _ => return None,
+        };
+
+        Some(Class { negated: key.is_ascii_uppercase(), items: vec![item] })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Literal(char),
+    Any,
+    Caret,
+    Dollar,
+    Star,
+    Plus,
+    Question,
+    Brace,
+    Bracket,
+    Open(bool), // whether the group captures
+    Close,
+    Bar,
+    WordStart,
+    WordEnd,
+    WordBoundary,
+    Shorthand(char),
+    CaseFlag(bool), // \c and \C
+    Magic(bool), // \v and \m
+}
+
+struct Parser {
+    chars:                  Vec<char>,
+    index:                  usize,
+    very_magic:             bool,
+    groups:                 usize,
+    ignore_case:            Option<bool>,
+}
+
+impl Parser {
+    // the token at the cursor and how many characters it takes up
+    fn peek(&self) -> Option<(Token, usize)> {
+        let c = *self.chars.get(self.index)?;
+        let next = self.chars.get(self.index.saturating_add(1)).copied();
+        let after = self.chars.get(self.index.saturating_add(2)).copied();
+        let vm = self.very_magic;
+
+        if c == '\\' {
+            let next = match next {
+                Some(next) => next,
+                None => return Some((Token::Literal('\\'), 1)), // a trailing backslash stands for itself
+            };
+            let token = match next {
+                '(' if !vm => Token::Open(true),
+                '%' if !vm && after == Some('(') => return Some((Token::Open(false), 3)),
+                ')' if !vm => Token::Close,
+                '|' if !vm => Token::Bar,
+                '+' if !vm => Token::Plus,
+                '?' | '=' if !vm => Token::Question,
+                '{' if !vm => Token::Brace,
+                '<' if !vm => Token::WordStart,
+                '>' if !vm => Token::WordEnd,
+                'b' => Token::WordBoundary,
+                'd' | 'D' | 's' | 'S' | 'w' | 'W' | 'a' | 'A' | 'l' | 'L' | 'u' | 'U' | 'x' | 'X' | 'h' | 'H' => Token::Shorthand(next),
+                'c' => Token::CaseFlag(true),
+                'C' => Token::CaseFlag(false),
+                'v' => Token::Magic(true),
+                'm' => Token::Magic(false),
+                't' => Token::Literal('\t'),
+                'e' => Token::Literal('\x1b'),
+                'r' => Token::Literal('\r'),
+                'n' => Token::Literal('\n'),
+                other => Token::Literal(other),
+            };
+            return Some((token, 2));
+        }
+
+        let token = match c {
+            '.' => Token::Any,
+            '*' => Token::Star,
+            '[' => Token::Bracket,
+            '^' => Token::Caret,
+            '$' => Token::Dollar,
+            '(' if vm && next == Some('?') && after == Some(':') => return Some((Token::Open(false), 3)),
+            '%' if vm && next == Some('(') => return Some((Token::Open(false), 2)),
+            '(' if vm => Token::Open(true),
+            ')' if vm => Token::Close,
+            '|' if vm => Token::Bar,
+            '+' if vm => Token::Plus,
+            '?' | '=' if vm => Token::Question,
+            '{' if vm => Token::Brace,
+            '<' if vm => Token::WordStart,
+            '>' if vm => Token::WordEnd,
+            other => Token::Literal(other),
+        };
+
+        Some((token, 1))
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let (token, width) = self.peek()?;
+        self.index = self.index.saturating_add(width);
+        Some(token)
+    }
+
+    fn peek_token(&self) -> Option<Token> {
+        self.peek().map(|(token, _)| token)
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.peek_token() == Some(Token::Bar) {
+            self.next();
+            branches.push(self.parse_concat()?);
+        }
+
+        Ok(if branches.len() == 1 { branches.remove(0) } else { Node::Alternation(branches) })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        loop {
+            match self.peek_token() {
+                None | Some(Token::Bar) | Some(Token::Close) => break,
+                Some(Token::CaseFlag(ignore_case)) => {
+                    self.next();
+                    self.ignore_case = Some(ignore_case);
+                },
+                Some(Token::Magic(very_magic)) => {
+                    self.next();
+                    self.very_magic = very_magic;
+                },
+                Some(_) => {
+                    let atom = self.parse_atom(nodes.is_empty())?;
+                    let node = self.parse_quantifier(atom)?;
+                    nodes.push(node);
+                },
+            }
+        }
+
+        Ok(if nodes.len() == 1 { nodes.remove(0) } else { Node::Concat(nodes) })
+    }
+
+    fn parse_atom(&mut self, branch_start: bool) -> Result<Node, String> {
+        let start = self.index;
+        let token = self.next().ok_or_else(|| "E867: Unexpected end of pattern".to_string())?;
+        let node = match token {
+            Token::Literal(c) => {
+                // a character and the combining marks after it are one grapheme, as they are in the text
+                let escaped = self.index.saturating_sub(start) > 1;
+                let rest: String = self.chars[start..].iter().collect();
+                match rest.graphemes(true).next() {
+                    Some(grapheme) if !escaped && grapheme.chars().count() > 1 => {
+                        self.index = start.saturating_add(grapheme.chars().count());
+                        Node::Grapheme(grapheme.to_string())
+                    },
+                    _ => Node::Grapheme(c.to_string()),
+                }
+            },
+            Token::Any => Node::Any,
+            // without \v, ^ and $ only anchor at the ends of a branch, and are plain characters elsewhere
+            Token::Caret if self.very_magic || branch_start => Node::LineStart,
+            Token::Caret => Node::Grapheme("^".to_string()),
+            Token::Dollar => {
+                let at_end = matches!(self.peek_token(), None | Some(Token::Bar) | Some(Token::Close));
+                if self.very_magic || at_end { Node::LineEnd } else { Node::Grapheme("$".to_string()) }
+            },
+            Token::WordStart => Node::WordStart,
+            Token::WordEnd => Node::WordEnd,
+            Token::WordBoundary => Node::WordBoundary,
+            Token::Shorthand(key) => Node::Class(Class::shorthand(key).ok_or_else(|| format!("E867: Unknown class \\{}", key))?),
+            Token::Bracket => Node::Class(self.parse_bracket()?),
+            Token::Open(capturing) => {
+                let index = if capturing {
+                    self.groups = self.groups.saturating_add(1);
+                    Some(self.groups)
+                } else {
+                    None
+                };
+                let inner = self.parse_alternation()?;
+                if self.next() != Some(Token::Close) {
+                    return Err(if self.very_magic { "E54: Unmatched (".to_string() } else { "E54: Unmatched \\(".to_string() });
+                }
+                Node::Group(Box::new(inner), index)
+            },
+            Token::Star if branch_start && !self.very_magic => Node::Grapheme("*".to_string()),
+            Token::Star | Token::Plus | Token::Question | Token::Brace => {
+                let text: String = self.chars[start..self.index].iter().collect();
+                return Err(format!("E64: {} follows nothing", text));
+            },
+            Token::Bar | Token::Close | Token::CaseFlag(_) | Token::Magic(_) => {
+                return Err("E867: Unexpected token".to_string());
+            },
+        };
+
+        Ok(node)
+    }
+
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let start = self.index;
+        let (mut min, mut max, mut greedy) = match self.peek_token() {
+            Some(Token::Star) => (0, None, true),
+            Some(Token::Plus) => (1, None, true),
+            Some(Token::Question) => (0, Some(1), true),
+            Some(Token::Brace) => (0, None, true),
+            _ => return Ok(atom),
+        };
+        if self.next() == Some(Token::Brace) {
+            (min, max, greedy) = self.parse_braces()?;
+        }
+
+        // rust's lazy quantifiers; vim writes these as \{-n,m}
+        if self.very_magic && self.chars.get(self.index) == Some(&'?') {
+            self.index = self.index.saturating_add(1);
+            greedy = false;
+        }
+        if matches!(self.peek_token(), Some(Token::Star | Token::Plus | Token::Question | Token::Brace)) {
+            let text: String = self.chars[start..self.index].iter().collect();
+            return Err(format!("E61: Nested {}", text));
+        }
+
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    // what comes after \{ (or { with \v): n,m} n} n,} ,m} or }, with a '-' in front for as few as possible
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>, bool), String> {
+        let error = || "E554: Syntax error in \\{...}".to_string();
+        let lazy = self.chars.get(self.index) == Some(&'-');
+        if lazy {
+            self.index = self.index.saturating_add(1);
+        }
+
+        let mut number = |parser: &mut Parser| {
+            let digits: String = parser.chars[parser.index..].iter().take_while(|c| c.is_ascii_digit()).collect();
+            parser.index = parser.index.saturating_add(digits.len());
+            digits.parse::<usize>().ok()
+        };
+        let min = number(self);
+        let (min, max) = if self.chars.get(self.index) == Some(&',') {
+            self.index = self.index.saturating_add(1);
+            (min.unwrap_or(0), number(self))
+        } else {
+            match min {
+                Some(count) => (count, Some(count)),
+                None => (0, None),
+            }
+        };
+
+        if self.chars.get(self.index) == Some(&'\\') {
+            self.index = self.index.saturating_add(1);
+        }
+        if self.chars.get(self.index) != Some(&'}') || max.is_some_and(|max| max < min) {
+            return Err(error());
+        }
+        self.index = self.index.saturating_add(1);
+
+        Ok((min, max, !lazy))
+    }
+
+    // what comes after '[', up to and including the ']'
+    // the name in a '[:name:]' that follows a '[', or None when that '[' is just a '['
+    fn posix_class_name(&self) -> Option<String> {
+        let rest = self.chars.get(self.index..)?;
+        if rest.first() != Some(&':') {
+            return None;
+        }
+        let end = rest.windows(2).skip(1).position(|pair| pair == [':', ']'])?.saturating_add(1);
+        if end > 1 { Some(rest[1..end].iter().collect()) } else { None }
+    }
+
+    fn parse_bracket(&mut self) -> Result<Class, String> {
+        let mut class = Class { negated: false, items: vec![] };
+        if self.chars.get(self.index) == Some(&'^') {
+            class.negated = true;
+            self.index = self.index.saturating_add(1);
+        }
+
+        let mut first = true;
+        loop {
+            let c = *self.chars.get(self.index).ok_or_else(|| "E769: Missing ] after [".to_string())?;
+            self.index = self.index.saturating_add(1);
+            let c = match c {
+                ']' if !first => break,
+                '[' => match self.posix_class_name() {
+                    Some(name) => {
+                        let item = match name.as_str() {
+                            "alpha" => ClassItem::Alpha,
+                            "digit" => ClassItem::Digit,
+                            "alnum" => ClassItem::Alnum,
+                            "space" => ClassItem::Space,
+                            "lower" => ClassItem::Lower,
+                            "upper" => ClassItem::Upper,
+                            "xdigit" => ClassItem::Hex,
+                            "punct" => ClassItem::Punct,
+                            name => return Err(format!("E867: Unknown character class [:{}:]", name)),
+                        };
+                        self.index = self.index.saturating_add(name.chars().count()).saturating_add(3);
+                        class.items.push(item);
+                        first = false;
+                        continue;
+                    },
+                    None => '[',
+                },
+                '\\' => {
+                    let escaped = *self.chars.get(self.index).ok_or_else(|| "E769: Missing ] after [".to_string())?;
+                    self.index = self.index.saturating_add(1);
+                    match escaped {
+                        'd' => { class.items.push(ClassItem::Digit); first = false; continue; },
+                        's' => { class.items.push(ClassItem::Space); first = false; continue; },
+                        'w' => { class.items.push(ClassItem::Word); first = false; continue; },
+                        't' => '\t',
+                        'e' => '\x1b',
+                        'n' => '\n',
+                        other => other,
+                    }
+                },
+                c => c,
+            };
+            first = false;
+
+            // a '-' between two characters makes a range, anywhere else it is a '-'
+            let is_range = self.chars.get(self.index) == Some(&'-')
+                && self.chars.get(self.index.saturating_add(1)).is_some_and(|end| *end != ']');
+            if is_range {
+                let end = self.chars[self.index.saturating_add(1)];
+                self.index = self.index.saturating_add(2);
+                if end < c {
+                    return Err("E944: Reverse range in character class".to_string());
+                }
+                class.items.push(ClassItem::Range(c, end));
+            } else {
+                class.items.push(ClassItem::Char(c));
+            }
+        }
+
+        Ok(class)
+    }
+}
+
+// 'text' as a pattern that matches it literally
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\.*[]^$~/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+// the text a ':s' replacement stands for at a match: '&' and '\0' to '\9' are the match and its groups,
+// '\r' and '\n' break the line (as a '\n'), '\t' is a tab and '\&' or '\\' the character itself
+pub fn expand(replacement: &str, captures: &Captures, graphemes: &[&str]) -> String {
+    let group = |index: usize| match captures.get(index).copied().flatten() {
+        Some((start, end)) => graphemes[start..end].concat(),
+        None => String::new(),
+    };
+    let mut expanded = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => expanded.push_str(&group(0)),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => expanded.push_str(&group(digit as usize - '0' as usize)),
+                Some('r') | Some('n') => expanded.push('\n'),
+                Some('t') => expanded.push('\t'),
+                Some(other) => expanded.push(other),
+                None => expanded.push('\\'),
+            },
+            _ => expanded.push(c),
+        }
+    }
+
+    expanded
+}
+
+// smartcase only looks at the letters that stand for themselves: \S or \W don't make a pattern case sensitive
+fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn same_grapheme(a: &str, b: &str, ignore_case: bool) -> bool {
+    a == b || (ignore_case && a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase)))
+}
+
+impl Regex {
+    // 'ignorecase' and 'smartcase' are the options of the same name; \c or \C in the pattern overrides both
+    pub fn new(pattern: &str, ignorecase: bool, smartcase: bool) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), index: 0, very_magic: false, groups: 0, ignore_case: None };
+        let root = parser.parse_alternation()?;
+        if parser.index < parser.chars.len() {
+            return Err(if parser.very_magic { "E55: Unmatched )".to_string() } else { "E55: Unmatched \\)".to_string() });
+        }
+
+        let ignore_case = parser.ignore_case.unwrap_or(ignorecase && !(smartcase && has_uppercase(pattern)));
+        let mut program = vec![];
+        compile(&root, &mut program);
+        program.push(Inst::Match);
+        Ok(Regex { pattern: pattern.to_string(), ignore_case, root, program, groups: parser.groups })
+    }
+
+    // the match starting at column 'start', if there is one there
+    pub fn captures_at(&self, graphemes: &[&str], start: usize) -> Option<Captures> {
+        Matcher::new(self, graphemes).captures(start..=start)
+    }
+
+    // the leftmost match starting at or after column 'from'
+    pub fn captures_from(&self, graphemes: &[&str], from: usize) -> Option<Captures> {
+        if self.is_anchored() {
+            return if from == 0 { self.captures_at(graphemes, 0) } else { None };
+        }
+        Matcher::new(self, graphemes).captures(from..=graphemes.len())
+    }
+
+    // the match with the last start before column 'before', which may overlap the ones after it
+    pub fn captures_before(&self, graphemes: &[&str], before: usize) -> Option<Captures> {
+        let last = if self.is_anchored() { 0 } else { graphemes.len() };
+        Matcher::new(self, graphemes).captures((0..=last).rev().skip_while(|start| *start >= before))
+    }
+
+    // the leftmost matches, each one picking up where the one before it ended, as (start, end) columns
+    pub fn find_all(&self, graphemes: &[&str]) -> Vec<(usize, usize)> {
+        let mut matcher = Matcher::new(self, graphemes);
+        let mut matches = vec![];
+        let mut from = 0;
+        let last = if self.is_anchored() { 0 } else { graphemes.len() };
+        while from <= last {
+            let (start, end) = match matcher.captures(from..=last).and_then(|captures| captures[0]) {
+                Some(found) => found,
+                None => break,
+            };
+            matches.push((start, end));
+            // the steps that led to this match were still under way at its end, so they don't count as failed there
+            matcher.forget(end);
+            from = if end > start { end } else { start.saturating_add(1) };
+        }
+
+        matches
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        let graphemes: Vec<&str> = line.graphemes(true).collect();
+        self.captures_from(&graphemes, 0).is_some()
+    }
+
+    fn is_anchored(&self) -> bool {
+        match &self.root {
+            Node::LineStart => true,
+            Node::Concat(nodes) => matches!(nodes.first(), Some(Node::LineStart)),
+            _ => false,
+        }
+    }
+}
+
+// what a pattern compiles to: a list of steps, where a split is a choice to come back to if the first way fails
+#[derive(Debug, Clone, PartialEq)]
+enum Inst {
+    Step(Node), // a node that takes one grapheme, or an edge that takes none
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize), // where a group starts (2 * group) or ends (2 * group + 1)
+    Match,
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Group(inner, None) => compile(inner, program),
+        Node::Group(inner, Some(index)) => {
+            program.push(Inst::Save(index.saturating_mul(2)));
+            compile(inner, program);
+            program.push(Inst::Save(index.saturating_mul(2).saturating_add(1)));
+        },
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, program)),
+        Node::Alternation(branches) => {
+            let mut jumps = vec![];
+            for (index, branch) in branches.iter().enumerate() {
+                if index + 1 == branches.len() {
+                    compile(branch, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split.saturating_add(1), 0));
+                compile(branch, program);
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split.saturating_add(1), program.len());
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        },
+        Node::Repeat { node, min, max, greedy } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            let choice = |body: usize, skip: usize| if *greedy { Inst::Split(body, skip) } else { Inst::Split(skip, body) };
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Match);
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = choice(split.saturating_add(1), program.len());
+                },
+                // each one past the minimum is optional, and skipping one skips the rest
+                Some(max) => {
+                    let splits: Vec<usize> = (*min..*max).map(|_| {
+                        let split = program.len();
+                        program.push(Inst::Match);
+                        compile(node, program);
+                        split
+                    }).collect();
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = choice(split.saturating_add(1), end);
+                    }
+                },
+            }
+        },
+        node => program.push(Inst::Step(node.clone())),
+    }
+}
+
+enum Job {
+    Run(usize, usize), // a step to try at a column
+    Restore(usize, Option<usize>), // a group edge to put back when backing out of a save
+}
+
+// a backtracking matcher that keeps its choices on a stack instead of recursing, so long lines can't overflow it.
+// whether a step goes on to match from a column doesn't depend on where the match started, so a step is only ever
+// tried once at each column, which keeps the work to the size of the program times the length of the line
+struct Matcher<'a> {
+    program:                &'a [Inst],
+    graphemes:              &'a [&'a str],
+    ignore_case:            bool,
+    groups:                 usize,
+    visited:                Vec<u64>,
+    stack:                  Vec<Job>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(regex: &'a Regex, graphemes: &'a [&'a str]) -> Self {
+        let bits = regex.program.len().saturating_mul(graphemes.len().saturating_add(1));
+        Matcher {
+            program: &regex.program,
+            graphemes,
+            ignore_case: regex.ignore_case,
+            groups: regex.groups,
+            visited: vec![0; bits.div_ceil(64)],
+            stack: vec![],
+        }
+    }
+
+    // the first of 'starts' a match starts at
+    fn captures(&mut self, starts: impl Iterator<Item = usize>) -> Option<Captures> {
+        let mut slots = vec![None; self.groups.saturating_add(1).saturating_mul(2)];
+        for start in starts {
+            if let Some(end) = self.run(start, &mut slots) {
+                let mut captures: Captures = slots.chunks(2).map(|edges| edges[0].zip(edges[1])).collect();
+                captures[0] = Some((start, end));
+                return Some(captures);
+            }
+        }
+
+        None
+    }
+
+    // lets the steps at column 'pos' be tried again
+    fn forget(&mut self, pos: usize) {
+        let first = pos.saturating_mul(self.program.len());
+        for bit in first..first.saturating_add(self.program.len()) {
+            if let Some(word) = self.visited.get_mut(bit / 64) {
+                *word &= !(1 << (bit % 64));
+            }
+        }
+    }
+
+    // false when the step was tried at this column already
+    fn visit(&mut self, pc: usize, pos: usize) -> bool {
+        let bit = pos.saturating_mul(self.program.len()).saturating_add(pc);
+        match self.visited.get_mut(bit / 64) {
+            Some(word) if *word & (1 << (bit % 64)) == 0 => {
+                *word |= 1 << (bit % 64);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // where a match starting at 'start' ends
+    fn run(&mut self, start: usize, slots: &mut [Option<usize>]) -> Option<usize> {
+        self.stack.clear();
+        self.stack.push(Job::Run(0, start));
+        while let Some(job) = self.stack.pop() {
+            let (mut pc, mut pos) = match job {
+                Job::Run(pc, pos) => (pc, pos),
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                },
+            };
+            while self.visit(pc, pos) {
+                match &self.program[pc] {
+                    Inst::Match => return Some(pos),
+                    Inst::Step(node) => match self.step(node, pos) {
+                        Some(next) => (pc, pos) = (pc.saturating_add(1), next),
+                        None => break,
+                    },
+                    Inst::Split(first, second) => {
+                        self.stack.push(Job::Run(*second, pos));
+                        pc = *first;
+                    },
+                    Inst::Jump(target) => pc = *target,
+                    Inst::Save(slot) => {
+                        self.stack.push(Job::Restore(*slot, slots[*slot]));
+                        slots[*slot] = Some(pos);
+                        pc = pc.saturating_add(1);
+                    },
+                }
+            }
+        }
+
+        None
+    }
+
+    fn is_word_at(&self, pos: usize) -> bool {
+        self.graphemes.get(pos).and_then(|grapheme| grapheme.chars().next()).is_some_and(is_word)
+    }
+
+    fn is_word_before(&self, pos: usize) -> bool {
+        pos > 0 && self.is_word_at(pos.saturating_sub(1))
+    }
+
+    // the column after 'node' matches at 'pos', if it does
+    fn step(&self, node: &Node, pos: usize) -> Option<usize> {
+        let grapheme = self.graphemes.get(pos);
+        let matched = match node {
+            Node::Grapheme(expected) => grapheme.is_some_and(|grapheme| same_grapheme(expected, grapheme, self.ignore_case)),
+            Node::Any => grapheme.is_some(),
+            Node::Class(class) => grapheme.is_some_and(|grapheme| class.matches(grapheme, self.ignore_case)),
+            Node::LineStart => return Some(pos).filter(|_| pos == 0),
+            Node::LineEnd => return Some(pos).filter(|_| pos == self.graphemes.len()),
+            Node::WordStart => return Some(pos).filter(|_| self.is_word_at(pos) && !self.is_word_before(pos)),
+            Node::WordEnd => return Some(pos).filter(|_| self.is_word_before(pos) && !self.is_word_at(pos)),
+            Node::WordBoundary => return Some(pos).filter(|_| self.is_word_before(pos) != self.is_word_at(pos)),
+            _ => false,
+        };
+
+        Some(pos.saturating_add(1)).filter(|_| matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, line: &str) -> Option<(usize, usize)> {
+        let graphemes: Vec<&str> = line.graphemes(true).collect();
+        Regex::new(pattern, false, false).unwrap().captures_from(&graphemes, 0).and_then(|captures| captures[0])
+    }
+
+    #[test]
+    fn test_regex_syntax () {
+        assert_eq!(find("b.d", "abcd"), Some((1, 4)));
+        assert_eq!(find("a*", "baa"), Some((0, 0)));
+        assert_eq!(find("ba\\+", "cbaab"), Some((1, 4)));
+        assert_eq!(find("x\\{2,3}", "xxxxx"), Some((0, 3)));
+        assert_eq!(find("x\\{-2,}", "xxxxx"), Some((0, 2)));
+        assert_eq!(find("[0-9]\\+", "ab 123"), Some((3, 6)));
+        assert_eq!(find("[^a-c ]", "abc d"), Some((4, 5)));
+        assert_eq!(find("\\d\\s\\w", "a1 b"), Some((1, 4)));
+        assert_eq!(find("[[:upper:]]", "abC"), Some((2, 3)));
+        // a '[:' that doesn't start a class name is just a '[' and a ':'
+        assert_eq!(find("[[:]\\+", "ab[:"), Some((2, 4)));
+        assert_eq!(find("[[::]x]", "a:x]"), Some((1, 4)));
+        assert_eq!(find("^ab", "abab"), Some((0, 2)));
+        assert_eq!(find("ab$", "abab"), Some((2, 4)));
+        assert_eq!(find("a^b$c", "xa^b$c"), Some((1, 6)));
+        assert_eq!(find("foo\\|bar", "a bar"), Some((2, 5)));
+        assert_eq!(find("\\(ab\\)\\+c", "ababc"), Some((0, 5)));
+        assert_eq!(find("\\<is\\>", "this is"), Some((5, 7)));
+        assert_eq!(find("is\\b", "isle is"), Some((5, 7)));
+        assert_eq!(find("a.c", "a.c"), Some((0, 3)));
+        assert_eq!(find("a\\.c", "abc a.c"), Some((4, 7)));
+        assert_eq!(find("*a", "b*a"), Some((1, 3)));
+        assert_eq!(find("\\(\\)*x", "x"), Some((0, 1)));
+
+        // \v: the rust way of writing things
+        assert_eq!(find("\\v(foo|bar)+", "xbarfoo"), Some((1, 7)));
+        assert_eq!(find("\\v(?:ab){2}", "abab"), Some((0, 4)));
+        assert_eq!(find("\\va.+?c", "abcbc"), Some((0, 3)));
+        assert_eq!(find("\\v<the>", "other the"), Some((6, 9)));
+        assert_eq!(find("\\v\\(x\\)", "(x)"), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_regex_graphemes_and_case () {
+        // e followed by a combining accent is one grapheme, in the text and in the pattern
+        assert_eq!(find("e", "cafe\u{301} e"), Some((5, 6)));
+        assert_eq!(find("e\u{301}", "cafe\u{301}"), Some((3, 4)));
+        assert_eq!(find("caf.$", "cafe\u{301}"), Some((0, 4)));
+        assert_eq!(find("\\w\\+", "日本語 x"), Some((0, 3)));
+
+        let regex = Regex::new("hello", true, true).unwrap();
+        assert!(regex.is_match("say HELLO"));
+        assert!(!Regex::new("Hello", true, true).unwrap().is_match("say HELLO"));
+        assert!(Regex::new("Hello", true, false).unwrap().is_match("say HELLO"));
+        assert!(Regex::new("\\S\\+", true, true).unwrap().ignore_case);
+        assert!(Regex::new("hello\\c", false, false).unwrap().is_match("HeLLo"));
+        assert!(!Regex::new("\\Chello", true, false).unwrap().is_match("HELLO"));
+        assert!(Regex::new("[a-c]", true, false).unwrap().is_match("B"));
+
+        let graphemes: Vec<&str> = "key = value".graphemes(true).collect();
+        let captures = Regex::new("\\(\\w\\+\\) = \\(\\w\\+\\)", false, false).unwrap().captures_from(&graphemes, 0).unwrap();
+        assert_eq!(captures, vec![Some((0, 11)), Some((0, 3)), Some((6, 11))]);
+        assert_eq!(Regex::new("a", false, false).unwrap().find_all(&["a", "a", "b", "a"]), vec![(0, 1), (1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn test_regex_long_lines () {
+        // repeats of a group used to take a level of recursion each, which overflowed the stack on lines like these
+        let line = "ab".repeat(50_000);
+        assert_eq!(find("\\(ab\\)\\+", &line), Some((0, 100_000)));
+        assert_eq!(find("\\v(a|b)+$", &line), Some((0, 100_000)));
+        assert_eq!(find("\\(ab\\)\\+c", &line), None);
+
+        // highlighting takes the matches one after another, instead of trying every column against the whole line
+        let line = format!("{}y", "x".repeat(5_000));
+        let started = std::time::Instant::now();
+        assert_eq!(Regex::new(".*y", false, false).unwrap().find_all(&line.graphemes(true).collect::<Vec<_>>()), vec![(0, 5_001)]);
+        assert_eq!(Regex::new("x*z", false, false).unwrap().find_all(&line.graphemes(true).collect::<Vec<_>>()), vec![]);
+        assert!(started.elapsed().as_secs_f64() < 0.5);
+    }
+
+    #[test]
+    fn test_regex_escape () {
+        let text = "a.b*[c]^$~\\d/";
+        assert_eq!(escape(text), "a\\.b\\*\\[c\\]\\^\\$\\~\\\\d\\/");
+        assert!(Regex::new(&escape(text), false, false).unwrap().is_match(text));
+        assert!(!Regex::new(&escape("a.b"), false, false).unwrap().is_match("axb"));
+    }
+
+    #[test]
+    fn test_regex_expand () {
+        let graphemes: Vec<&str> = "key = value".graphemes(true).collect();
+        let captures = Regex::new("\\(\\w\\+\\) = \\(\\w\\+\\)", false, false).unwrap().captures_from(&graphemes, 0).unwrap();
+        assert_eq!(expand("\\2: \\1", &captures, &graphemes), "value: key");
+        assert_eq!(expand("[&] \\& \\\\ \\3", &captures, &graphemes), "[key = value] & \\ ");
+        assert_eq!(expand("\\1\\r\\t\\2", &captures, &graphemes), "key\n\tvalue");
+    }
+
+    #[test]
+    fn test_regex_errors () {
+        let error = |pattern: &str| Regex::new(pattern, false, false).err();
+        assert_eq!(error("\\(ab"), Some("E54: Unmatched \\(".to_string()));
+        assert_eq!(error("ab\\)"), Some("E55: Unmatched \\)".to_string()));
+        assert_eq!(error("\\v(ab"), Some("E54: Unmatched (".to_string()));
+        assert_eq!(error("[ab"), Some("E769: Missing ] after [".to_string()));
+        assert_eq!(error("a**"), Some("E61: Nested *".to_string()));
+        assert_eq!(error("\\vx|+"), Some("E64: + follows nothing".to_string()));
+        assert_eq!(error("a\\{3,1}"), Some("E554: Syntax error in \\{...}".to_string()));
+        assert_eq!(error("[z-a]"), Some("E944: Reverse range in character class".to_string()));
+        assert_eq!(error("[[:foo:]]"), Some("E867: Unknown character class [:foo:]".to_string()));
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use crate::clipboard::{provider_from_option, ClipboardProvider};
+use crate::config::DEFAULT_CLIPBOARD;
+use crate::terminal::TerminalBackend;
+use crate::utils::{Operator, RangeKind};
+
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub text:           String,
+    pub kind:           RangeKind,
+}
+
+impl Register {
+    // the register's text split into the lines it puts, without the final newline of linewise text
+    pub fn lines(&self) -> Vec<&str> {
+        let text = if self.kind == RangeKind::Linewise { self.text.strip_suffix('\n').unwrap_or(&self.text) } else { &self.text };
+        text.split('\n').collect()
+    }
+
+    fn append(&mut self, other: &Register) {
+        match (self.kind, other.kind) {
+            (RangeKind::Linewise, _) => {
+                self.text.push_str(&other.text);
+                if !self.text.ends_with('\n') { self.text.push('\n'); }
+            },
+            (_, RangeKind::Linewise) => {
+                // appending lines to characters turns the whole register linewise
+                self.text.push('\n');
+                self.text.push_str(&other.text);
+                self.kind = RangeKind::Linewise;
+            },
+            (RangeKind::Blockwise, _) | (_, RangeKind::Blockwise) => {
+                self.text.push('\n');
+                self.text.push_str(&other.text);
+            },
+            _ => self.text.push_str(&other.text),
+        }
+    }
+}
+
+pub struct Registers {
+    pub unnamed:            Option<Register>,
+    pub numbered:           [Option<Register>; 10],
+    pub named:              HashMap<char, Register>,
+    pub clipboard:          Box<dyn ClipboardProvider>,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self {
+            unnamed: None,
+            numbered: Default::default(),
+            named: HashMap::new(),
+            clipboard: provider_from_option(DEFAULT_CLIPBOARD),
+        }
+    }
+}
+
+impl Registers {
+    pub fn is_valid(name: char) -> bool {
+        name == '"' || name == '_' || name == '+' || name == '*' || name.is_ascii_digit() || name.is_ascii_alphabetic()
+    }
+
+    pub fn get(&mut self, name: Option<char>) -> Option<&Register> {
+        match name {
+            None | Some('"') => self.unnamed.as_ref(),
+            Some(n @ ('+' | '*')) => {
+                // '+' and '*' also keep what we copied last, for clipboards that can't be read back
+                if let Some(text) = self.clipboard.get() {
+                    if self.named.get(&n).map(|register| register.text != text).unwrap_or(true) {
+                        let kind = if text.ends_with('\n') { RangeKind::Linewise } else { RangeKind::Charwise };
+                        self.named.insert(n, Register { text, kind });
+                    }
+                }
+                self.named.get(&n)
+            },
+            Some(n @ '0'..='9') => self.numbered[n.to_digit(10)? as usize].as_ref(),
+            Some(n) if n.is_ascii_alphabetic() => self.named.get(&n.to_ascii_lowercase()),
+            _ => None,
+        }
+    }
+
+    // records text that an operator removed or copied, 'name' being the register given with '"x'
+    pub fn store(&mut self, name: Option<char>, operator: Operator, register: Register, terminal: &mut dyn TerminalBackend) -> Result<(), String> {
+        match name {
+            Some('_') => return Ok(()),
+            Some(n @ ('+' | '*')) => {
+                self.clipboard.set(&register.text, terminal)?;
+                self.named.insert('+', register.clone());
+                self.named.insert('*', register.clone());
+            },
+            Some(n) if n.is_ascii_lowercase() => {
+                self.named.insert(n, register.clone());
+            },
+            Some(n) if n.is_ascii_uppercase() => {
+                let entry = self.named.entry(n.to_ascii_lowercase()).or_insert_with(|| Register { text: String::new(), kind: register.kind });
+                if entry.text.is_empty() {
+                    *entry = register;
+                } else {
+                    entry.append(&register);
+                }
+                self.unnamed = Some(entry.clone());
+                return Ok(());
+            },
+            Some(n @ '0'..='9') => {
+                if let Some(index) = n.to_digit(10) {
+                    self.numbered[index as usize] = Some(register.clone());
+                }
+            },
+            _ => {
+                if operator == Operator::Yank {
+                    self.numbered[0] = Some(register.clone());
+                } else {
+                    // deletes shift "1 into "2 and so on, dropping whatever was in "9
+                    self.numbered[1..].rotate_right(1);
+                    self.numbered[1] = Some(register.clone());
+                }
+            },
+        }
+
+        self.unnamed = Some(register);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::MemoryClipboard;
+    use crate::terminal::MemoryTerminal;
+
+    fn charwise(text: &str) -> Register {
+        Register { text: text.to_string(), kind: RangeKind::Charwise }
+    }
+
+    #[test]
+    fn test_register_store () {
+        let mut registers = Registers::default();
+        let mut terminal = MemoryTerminal::new(10, 4);
+
+        registers.store(None, Operator::Yank, charwise("yanked"), &mut terminal).unwrap();
+        for n in 1..=10 {
+            registers.store(None, Operator::Delete, charwise(&n.to_string()), &mut terminal).unwrap();
+        }
+        assert_eq!(registers.get(Some('0')).unwrap().text, "yanked");
+        assert_eq!(registers.get(Some('1')).unwrap().text, "10");
+        assert_eq!(registers.get(Some('9')).unwrap().text, "2");
+        assert_eq!(registers.get(None).unwrap().text, "10");
+
+        registers.store(Some('_'), Operator::Delete, charwise("gone"), &mut terminal).unwrap();
+        assert_eq!(registers.get(None).unwrap().text, "10");
+        assert_eq!(registers.get(Some('1')).unwrap().text, "10");
+
+        registers.store(Some('a'), Operator::Yank, charwise("foo"), &mut terminal).unwrap();
+        registers.store(Some('A'), Operator::Delete, charwise("bar"), &mut terminal).unwrap();
+        assert_eq!(registers.get(Some('a')).unwrap().text, "foobar");
+        assert_eq!(registers.get(Some('1')).unwrap().text, "10");
+
+        registers.store(Some('A'), Operator::Yank, Register { text: "line\n".to_string(), kind: RangeKind::Linewise }, &mut terminal).unwrap();
+        let register = registers.get(Some('a')).unwrap();
+        assert_eq!(register.text, "foobar\nline\n");
+        assert!(register.kind == RangeKind::Linewise);
+        assert_eq!(register.lines(), vec!["foobar", "line"]);
+    }
+
+    #[test]
+    fn test_clipboard_registers () {
+        let clipboard = MemoryClipboard::default();
+        let mut registers = Registers { clipboard: Box::new(clipboard.clone()), ..Registers::default() };
+        let mut terminal = MemoryTerminal::new(10, 4);
+
+        registers.store(Some('+'), Operator::Yank, Register { text: "copied\n".to_string(), kind: RangeKind::Linewise }, &mut terminal).unwrap();
+        assert_eq!(clipboard.contents.borrow().as_deref(), Some("copied\n"));
+        assert_eq!(registers.get(None).unwrap().text, "copied\n");
+        assert!(registers.get(Some('*')).unwrap().kind == RangeKind::Linewise);
+
+        *clipboard.contents.borrow_mut() = Some("from elsewhere".to_string());
+        let register = registers.get(Some('+')).unwrap();
+        assert_eq!(register.text, "from elsewhere");
+        assert!(register.kind == RangeKind::Charwise);
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashSet;
 use std::time::Duration;
 use std::thread;
 use crate::highlighting::Type;
+use crate::regex::Regex;
+use crate::terminal::{Color, TerminalBackend};
 use crate::utils::{find_grapheme_index, HighlightStreak, HighlightingOptions, Position, NumberMode};
 use unicode_segmentation::UnicodeSegmentation;
 
@@ -9,7 +11,10 @@ pub struct Row {
     pub string:         String,
     highlighting:       Vec<Type>,
     pub is_highlighted: bool,
+    pub hl_start:       HighlightStreak, // the comment/quote streak the row was highlighted with
+    pub hl_end:         HighlightStreak, // and the one it left for the row after it
     pub len:            usize,
+    pub has_multibyte:  bool,
 }
 
 impl Default for Row {
@@ -18,7 +23,10 @@ impl Default for Row {
             string: String::new(),
             highlighting: vec![],
             is_highlighted: false,
+            hl_start: HighlightStreak::default(),
+            hl_end: HighlightStreak::default(),
             len: 0,
+            has_multibyte: false,
         }
     }
 }
@@ -27,22 +35,137 @@ impl From<String> for Row {
     fn from(string: String) -> Self {
         let mut new_row = Row::default();
         new_row.string = string.clone();
-        new_row.len = string[..].graphemes(true).count();
+        new_row.update_len();
 
         new_row
     }
 }
 
+impl From<&str> for Row {
+    fn from(string: &str) -> Self {
+        Row::from(string.to_owned())
+    }
+}
+
 impl Row {
+    pub fn update_len(&mut self) {
+        self.len = self.string[..].graphemes(true).count();
+        self.has_multibyte = !self.string.is_ascii();
+    }
+
+    pub fn unhighlight(&mut self) {
+        self.highlighting.clear();
+        self.is_highlighted = false;
+    }
+
+    // byte offset of the grapheme at 'at' (or the end of the string when out of bounds)
+    pub fn byte_index(&self, at: usize) -> usize {
+        if !self.has_multibyte {
+            return if at < self.string.len() { at } else { self.string.len() };
+        }
+        self.string
+            .grapheme_indices(true)
+            .nth(at)
+            .map(|(index, _)| index)
+            .unwrap_or(self.string.len())
+    }
+
+    pub fn insert(&mut self, at: usize, c: char) {
+        let index = self.byte_index(at);
+        self.string.insert(index, c);
+        self.update_len();
+        self.unhighlight();
+    }
+
+    pub fn insert_str(&mut self, at: usize, string: &str) {
+        let index = self.byte_index(at);
+        self.string.insert_str(index, string);
+        self.update_len();
+        self.unhighlight();
+    }
+
+    pub fn delete(&mut self, at: usize) {
+        if at >= self.len {
+            return;
+        }
+        let start = self.byte_index(at);
+        let end = self.byte_index(at.saturating_add(1));
+        self.string.replace_range(start..end, "");
+        self.update_len();
+        self.unhighlight();
+    }
+
+    pub fn substring(&self, start: usize, end: usize) -> &str {
+        let start = self.byte_index(start);
+        let end = self.byte_index(end);
+        if start >= end {
+            return "";
+        }
+
+        &self.string[start..end]
+    }
+
+    pub fn delete_range(&mut self, start: usize, end: usize) {
+        let start = self.byte_index(start);
+        let end = self.byte_index(end);
+        if start >= end {
+            return;
+        }
+        self.string.replace_range(start..end, "");
+        self.update_len();
+        self.unhighlight();
+    }
+
+    pub fn graphemes(&self) -> Vec<&str> {
+        if !self.has_multibyte {
+            return (0..self.string.len()).map(|index| &self.string[index..index + 1]).collect();
+        }
+        self.string.graphemes(true).collect()
+    }
+
+    // where 'regex' matches in the row, as (start, end) grapheme columns, the way highlighting shows them
+    pub fn find_matches(&self, regex: &Regex) -> Vec<(usize, usize)> {
+        regex.find_all(&self.graphemes())
+    }
+
+    // the first column at or after 'from' that a match starts at
+    pub fn match_from(&self, regex: &Regex, from: usize) -> Option<usize> {
+        regex.captures_from(&self.graphemes(), from).and_then(|captures| captures[0]).map(|(start, _)| start)
+    }
+
+    // the last column before 'before' that a match starts at. a search stops at every one, even inside another match
+    pub fn match_before(&self, regex: &Regex, before: usize) -> Option<usize> {
+        regex.captures_before(&self.graphemes(), before).and_then(|captures| captures[0]).map(|(start, _)| start)
+    }
+
+    pub fn append(&mut self, new: &Self) {
+        self.string.push_str(&new.string);
+        self.update_len();
+        self.unhighlight();
+    }
+
+    pub fn split(&mut self, at: usize) -> Self {
+        let index = self.byte_index(at);
+        let remainder = self.string.split_off(index);
+        self.update_len();
+        self.unhighlight();
+
+        Row::from(remainder)
+    }
+
     pub fn highlight(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         hl_streak: &mut HighlightStreak,
     ) {
-        if self.is_highlighted {
+        // a row that didn't change and starts in the same streak comes out the same
+        if self.is_highlighted && self.hl_start == *hl_streak {
+            *hl_streak = self.hl_end.clone();
             return;
         }
+        self.highlighting.clear();
+        self.hl_start = hl_streak.clone();
         let ref mut index = 0;
         let curr_str_len = self.string.graphemes(true).count();
 
@@ -61,11 +184,20 @@ impl Row {
                 *index += 1;
             }
         }
+
+        // matches of the search pattern go over whatever the syntax made of them
+        if let Some(regex) = word {
+            for (start, end) in self.find_matches(regex) {
+                for entry in self.highlighting.iter_mut().take(end).skip(start) {
+                    *entry = Type::Pattern;
+                }
+            }
+        }
+        self.hl_end = hl_streak.clone();
         self.is_highlighted = true;
     }
 
-    pub fn render(&self, start: u16, end: u16) -> u16 {
-        let mut res_string: String = String::new();
+    pub fn render(&self, terminal: &mut dyn TerminalBackend, start: u16, end: u16) -> u16 {
         if (self.string.is_empty()) {
             return 0_u16;
         }
@@ -76,14 +208,10 @@ impl Row {
             .take(end.saturating_sub(start) as usize)
             .enumerate()
         {
-            res_string.push_str(
-                format!("{}{}", termion::color::Fg(self.highlighting.get(index.saturating_add(start as usize)) .unwrap_or(&Type::None).to_color()), entry)
-                .as_str(),
-            );
+            terminal.set_fg(self.highlighting.get(index.saturating_add(start as usize)).unwrap_or(&Type::None).to_color());
+            terminal.print(entry);
         }
-        res_string.push_str(format!("{}", termion::color::Fg(termion::color::Reset)).as_str());
-
-        print!("{}", res_string);
+        terminal.set_fg(Color::Reset);
 
         end.saturating_sub(start)
     }
@@ -91,7 +219,7 @@ impl Row {
     pub fn highlight_number(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -106,7 +234,7 @@ impl Row {
     pub fn highlight_decimal(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
         mode: NumberMode
@@ -291,7 +419,7 @@ impl Row {
     pub fn highlight_float(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -332,7 +460,7 @@ impl Row {
     pub fn highlight_octal(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -342,7 +470,7 @@ impl Row {
     pub fn highlight_o_or_b_number(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
         mode: NumberMode
@@ -447,7 +575,7 @@ impl Row {
     pub fn highlight_binary(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -457,7 +585,7 @@ impl Row {
     pub fn highlight_hex(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -467,7 +595,7 @@ impl Row {
     pub fn highlight_keyword(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -553,7 +681,7 @@ impl Row {
     pub fn highlight_pattern(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -563,7 +691,7 @@ impl Row {
     pub fn highlight_char(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -630,7 +758,7 @@ impl Row {
     pub fn highlight_str(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -739,7 +867,7 @@ impl Row {
     pub fn highlight_ml(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -822,7 +950,7 @@ impl Row {
     pub fn highlight_comment(
         &mut self,
         options: &HighlightingOptions,
-        word: &Option<String>,
+        word: &Option<Regex>,
         streak: &mut HighlightStreak,
         index: &mut usize,
     ) -> bool {
@@ -897,6 +1025,64 @@ mod tests {
         assert!(new_row.highlighting.len() == 10);
     }
 
+    #[test]
+    fn test_row_editing() {
+        let mut row = Row::from("héllo");
+        assert_eq!(row.len, 5);
+        assert!(row.has_multibyte);
+
+        row.insert(5, '!');
+        assert_eq!(row.string, "héllo!");
+        assert_eq!(row.len, 6);
+
+        row.delete(1);
+        assert_eq!(row.string, "hllo!");
+        assert!(!row.has_multibyte);
+
+        let tail = row.split(2);
+        assert_eq!(row.string, "hl");
+        assert_eq!(tail.string, "lo!");
+        assert_eq!(row.len, 2);
+
+        row.append(&tail);
+        assert_eq!(row.string, "hllo!");
+        assert_eq!(row.len, 5);
+    }
+
+    #[test]
+    fn test_row_editing_grapheme_clusters() {
+        let mut row = Row::from("ae\u{301}b");
+        assert_eq!(row.len, 3);
+
+        row.delete(1); // removes the whole 'é' cluster
+        assert_eq!(row.string, "ab");
+
+        row.insert(1, 'e');
+        row.insert(2, '\u{301}');
+        assert_eq!(row.len, 3);
+        assert!(!row.is_highlighted);
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let regex = |pattern: &str| Regex::new(pattern, false, false).unwrap();
+        assert_eq!(Row::from("aaa b aa").find_matches(&regex("aa")), vec![(0, 2), (6, 8)]);
+        // a search still stops inside a match, so it finds the start at 1 as well
+        assert_eq!(Row::from("aaa b aa").match_from(&regex("aa"), 1), Some(1));
+        assert_eq!(Row::from("aaa b aa").match_before(&regex("aa"), 6), Some(1));
+        assert_eq!(Row::from("aaa b aa").match_before(&regex("aa"), 0), None);
+        assert_eq!(Row::from("né né").find_matches(&regex("né")), vec![(0, 2), (3, 5)]);
+        // 'e' alone is not the 'é' cluster
+        assert_eq!(Row::from("ae\u{301} e").find_matches(&regex("e")), vec![(3, 4)]);
+        assert_eq!(Row::from("ab").find_matches(&regex("x*")), vec![(0, 0), (1, 1), (2, 2)]);
+
+        let mut row = Row::from("let x = xs;");
+        row.highlight(&HighlightingOptions::default(), &Some(regex("\\<x")), &mut HighlightStreak::default());
+        assert!(matches!(row.highlighting[4], Type::Pattern));
+        assert!(matches!(row.highlighting[8], Type::Pattern));
+        assert!(!matches!(row.highlighting[5], Type::Pattern));
+    }
+
     #[test]
     fn test_float_parsing() {
         let mut new_row = Row::default();
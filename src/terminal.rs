@@ -1,11 +1,46 @@
+use crate::clipboard::base64_encode;
 use crate::utils::{Position, Size};
+use std::cell::RefCell;
 use std::fmt;
+use std::rc::Rc;
 use std::io::Write;
 use termion::raw::{IntoRawMode, RawTerminal};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Reset,
+    Black,
+    White,
+    LightBlack,
+    LightWhite,
+    AnsiValue(u8),
+    Rgb(u8, u8, u8),
+}
+
+// everything the editor draws goes through one of these: the real terminal, or a grid in memory for tests
+pub trait TerminalBackend {
+    // the size of the text area; the two lines below it hold the status and message bars
+    fn get_size(&self) -> Size;
+    fn goto(&mut self, dest: Position);
+    fn clear_screen(&mut self);
+    fn clear_current_line(&mut self);
+    fn cursor_hide(&mut self);
+    fn cursor_show(&mut self);
+    fn save_cursor(&mut self);
+    fn restore_cursor(&mut self);
+    fn set_fg(&mut self, color: Color);
+    fn set_bg(&mut self, color: Color);
+    fn print(&mut self, text: &str);
+    fn flush(&mut self) -> Result<(), std::io::Error>;
+    // puts the text on the system clipboard, for terminals that support it
+    fn set_clipboard(&mut self, text: &str) -> Result<(), std::io::Error>;
+}
 
 pub struct Terminal {
     size:               Size,
-    _stdout:            RawTerminal<std::io::Stdout>,
+    // None when stdout isn't a terminal, e.g. under 'cargo test'
+    _stdout:            Option<RawTerminal<std::io::Stdout>>,
 }
 
 impl From<(u16, u16)> for Size {
@@ -20,8 +55,8 @@ impl From<(u16, u16)> for Size {
 
 impl Default for Terminal {
     fn default() -> Self {
-        let stdout = std::io::stdout().into_raw_mode().unwrap();
-        let Size { width, height } = Size::from(termion::terminal_size().unwrap());
+        let stdout = std::io::stdout().into_raw_mode().ok();
+        let Size { width, height } = Size::from(termion::terminal_size().unwrap_or((80, 24)));
         Self {
             _stdout: stdout,
             size: Size {
@@ -33,15 +68,17 @@ impl Default for Terminal {
 }
 
 impl Terminal {
-    pub fn get_std_buffer(&self) -> &RawTerminal<std::io::Stdout> {
-        &self._stdout
+    pub fn get_std_buffer(&self) -> Option<&RawTerminal<std::io::Stdout>> {
+        self._stdout.as_ref()
     }
+}
 
-    pub fn get_size(&self) -> Size {
+impl TerminalBackend for Terminal {
+    fn get_size(&self) -> Size {
         self.size
     }
 
-    pub fn goto(&self, dest: Position) {
+    fn goto(&mut self, dest: Position) {
         let Position { x, y } = dest;
         print!(
             "{}",
@@ -49,32 +86,268 @@ impl Terminal {
         );
     }
 
-    pub fn clear_screen(&self) {
+    fn clear_screen(&mut self) {
         print!("{}", termion::clear::All);
     }
 
-    pub fn clear_current_line(&self) {
+    fn clear_current_line(&mut self) {
         print!("{}", termion::clear::CurrentLine);
     }
 
-    pub fn cursor_hide(&self) {
+    fn cursor_hide(&mut self) {
         print!("{}", termion::cursor::Hide);
     }
 
-    pub fn cursor_show(&self) {
+    fn cursor_show(&mut self) {
         print!("{}{}", termion::cursor::Show, termion::cursor::SteadyBlock);
     }
 
-    pub fn save_cursor(&mut self) {
+    fn save_cursor(&mut self) {
         print!("{}", termion::cursor::Save);
     }
 
-    pub fn restore_cursor(&mut self) {
+    fn restore_cursor(&mut self) {
         print!("{}", termion::cursor::Restore);
     }
 
-    pub fn flush(&mut self) -> Result<(), std::io::Error> {
-        self._stdout.flush()?;
+    fn set_fg(&mut self, color: Color) {
+        print!("{}", Foreground(color));
+    }
+
+    fn set_bg(&mut self, color: Color) {
+        print!("{}", Background(color));
+    }
+
+    fn print(&mut self, text: &str) {
+        print!("{}", text);
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self._stdout.as_mut() {
+            Some(stdout) => stdout.flush(),
+            None => std::io::stdout().flush(),
+        }
+    }
+
+    // OSC 52: asks the terminal to put the (base64 encoded) text on the system clipboard
+    fn set_clipboard(&mut self, text: &str) -> Result<(), std::io::Error> {
+        print!("\x1b]52;c;{}\x07", base64_encode(text.as_bytes()));
+        self.flush()
+    }
+}
+
+struct Foreground(Color);
+struct Background(Color);
+
+impl fmt::Display for Foreground {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use termion::color::Fg;
+
+        match self.0 {
+            Color::Reset => write!(f, "{}", Fg(termion::color::Reset)),
+            Color::Black => write!(f, "{}", Fg(termion::color::Black)),
+            Color::White => write!(f, "{}", Fg(termion::color::White)),
+            Color::LightBlack => write!(f, "{}", Fg(termion::color::LightBlack)),
+            Color::LightWhite => write!(f, "{}", Fg(termion::color::LightWhite)),
+            Color::AnsiValue(value) => write!(f, "{}", Fg(termion::color::AnsiValue(value))),
+            Color::Rgb(r, g, b) => write!(f, "{}", Fg(termion::color::Rgb(r, g, b))),
+        }
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use termion::color::Bg;
+
+        match self.0 {
+            Color::Reset => write!(f, "{}", Bg(termion::color::Reset)),
+            Color::Black => write!(f, "{}", Bg(termion::color::Black)),
+            Color::White => write!(f, "{}", Bg(termion::color::White)),
+            Color::LightBlack => write!(f, "{}", Bg(termion::color::LightBlack)),
+            Color::LightWhite => write!(f, "{}", Bg(termion::color::LightWhite)),
+            Color::AnsiValue(value) => write!(f, "{}", Bg(termion::color::AnsiValue(value))),
+            Color::Rgb(r, g, b) => write!(f, "{}", Bg(termion::color::Rgb(r, g, b))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub grapheme:           String,
+    pub fg:                 Color,
+    pub bg:                 Color,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self { grapheme: " ".to_string(), fg: Color::Reset, bg: Color::Reset }
+    }
+}
+
+// what a MemoryTerminal has drawn, one grapheme per cell
+pub struct Screen {
+    pub cells:              Vec<Vec<Cell>>,
+    pub cursor:             Position,
+    pub cursor_visible:     bool,
+    // what was last sent to the clipboard
+    pub clipboard:          Option<String>,
+    saved_cursor:           Position,
+    fg:                     Color,
+    bg:                     Color,
+}
+
+impl Screen {
+    pub fn cell(&self, x: u16, y: u16) -> Option<&Cell> {
+        self.cells.get(y as usize)?.get(x as usize)
+    }
+
+    // the text on each line, without trailing blanks
+    pub fn lines(&self) -> Vec<String> {
+        self.cells
+            .iter()
+            .map(|line| line.iter().map(|cell| cell.grapheme.as_str()).collect::<String>().trim_end().to_string())
+            .collect()
+    }
+
+    fn blank(&self) -> Cell {
+        Cell { bg: self.bg, ..Cell::default() }
+    }
+
+    fn print(&mut self, text: &str) {
+        let last_line = self.cells.len().saturating_sub(1) as u16;
+        for grapheme in text.graphemes(true) {
+            match grapheme {
+                "\n" => self.cursor.y = self.cursor.y.saturating_add(1).min(last_line),
+                "\r" => self.cursor.x = 0,
+                "\r\n" => self.cursor = Position { x: 0, y: self.cursor.y.saturating_add(1).min(last_line) },
+                _ => {
+                    // like a terminal without autowrap, anything past the last column is dropped
+                    let cell = Cell { grapheme: grapheme.to_string(), fg: self.fg, bg: self.bg };
+                    if let Some(target) = self.cells.get_mut(self.cursor.y as usize).and_then(|line| line.get_mut(self.cursor.x as usize)) {
+                        *target = cell;
+                    }
+                    self.cursor.x = self.cursor.x.saturating_add(1);
+                },
+            }
+        }
+    }
+}
+
+// draws into a Screen in memory; clones share it, so tests can look at what the editor drew
+#[derive(Clone)]
+pub struct MemoryTerminal {
+    pub screen:             Rc<RefCell<Screen>>,
+}
+
+impl MemoryTerminal {
+    // 'width' and 'height' are the whole screen's, bars included
+    pub fn new(width: u16, height: u16) -> Self {
+        let screen = Screen {
+            cells: vec![vec![Cell::default(); width as usize]; height as usize],
+            cursor: Position::default(),
+            cursor_visible: true,
+            clipboard: None,
+            saved_cursor: Position::default(),
+            fg: Color::Reset,
+            bg: Color::Reset,
+        };
+
+        Self { screen: Rc::new(RefCell::new(screen)) }
+    }
+}
+
+impl TerminalBackend for MemoryTerminal {
+    fn get_size(&self) -> Size {
+        let screen = self.screen.borrow();
+        Size {
+            width: screen.cells.first().map(|line| line.len()).unwrap_or(0) as u16,
+            height: (screen.cells.len() as u16).saturating_sub(2),
+        }
+    }
+
+    fn goto(&mut self, dest: Position) {
+        self.screen.borrow_mut().cursor = dest;
+    }
+
+    fn clear_screen(&mut self) {
+        let mut screen = self.screen.borrow_mut();
+        let blank = screen.blank();
+        for line in screen.cells.iter_mut() {
+            line.fill(blank.clone());
+        }
+    }
+
+    fn clear_current_line(&mut self) {
+        let mut screen = self.screen.borrow_mut();
+        let (blank, y) = (screen.blank(), screen.cursor.y as usize);
+        if let Some(line) = screen.cells.get_mut(y) {
+            line.fill(blank);
+        }
+    }
+
+    fn cursor_hide(&mut self) {
+        self.screen.borrow_mut().cursor_visible = false;
+    }
+
+    fn cursor_show(&mut self) {
+        self.screen.borrow_mut().cursor_visible = true;
+    }
+
+    fn save_cursor(&mut self) {
+        let mut screen = self.screen.borrow_mut();
+        screen.saved_cursor = screen.cursor;
+    }
+
+    fn restore_cursor(&mut self) {
+        let mut screen = self.screen.borrow_mut();
+        screen.cursor = screen.saved_cursor;
+    }
+
+    fn set_fg(&mut self, color: Color) {
+        self.screen.borrow_mut().fg = color;
+    }
+
+    fn set_bg(&mut self, color: Color) {
+        self.screen.borrow_mut().bg = color;
+    }
+
+    fn print(&mut self, text: &str) {
+        self.screen.borrow_mut().print(text);
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
         Ok(())
     }
+
+    fn set_clipboard(&mut self, text: &str) -> Result<(), std::io::Error> {
+        self.screen.borrow_mut().clipboard = Some(text.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_terminal () {
+        let mut terminal = MemoryTerminal::new(6, 4);
+        assert_eq!((terminal.get_size().width, terminal.get_size().height), (6, 2));
+
+        terminal.set_bg(Color::Black);
+        terminal.print("héllo world\n\r");
+        terminal.set_fg(Color::Rgb(1, 2, 3));
+        terminal.print("ab");
+        terminal.goto(Position { x: 1, y: 3 });
+        terminal.clear_current_line();
+        terminal.print("x");
+
+        let screen = terminal.screen.borrow();
+        assert_eq!(screen.lines(), vec!["héllo", "ab", "", " x"]);
+        assert_eq!(screen.cell(1, 0).unwrap().bg, Color::Black);
+        assert_eq!(screen.cell(1, 1).unwrap().fg, Color::Rgb(1, 2, 3));
+        assert_eq!(screen.cell(0, 2).unwrap().bg, Color::Reset);
+        assert_eq!(screen.cell(0, 3).unwrap().bg, Color::Black);
+        assert_eq!((screen.cursor.x, screen.cursor.y), (2, 3));
+    }
 }
@@ -0,0 +1,225 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::UNDO_DIR;
+use crate::document::write_atomically;
+use crate::utils::{state_home, Position};
+
+// an edit replaces the lines starting at 'first': 'old' is what was there before, 'new' what is there after
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edit {
+    pub first:              usize,
+    pub old:                Vec<String>,
+    pub new:                Vec<String>,
+}
+
+impl Edit {
+    fn is_single_line(&self) -> bool {
+        self.old.len() == 1 && self.new.len() == 1
+    }
+}
+
+// one undo step: everything a single command (or a whole insert session) changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UndoNode {
+    pub parent:             usize,
+    pub edits:              Vec<Edit>,
+    pub cursor:             Position,
+    pub redo_child:         Option<usize>,
+}
+
+// the history is a tree: undoing and then making a change starts a new branch instead of dropping the old one.
+// nodes are kept in the order they were made, which is the order g- and g+ walk them in
+pub struct UndoTree {
+    pub nodes:              Vec<UndoNode>,
+    pub current:            usize,
+    pub saved:              usize,
+    // the last changed line and its text before the changes, for 'U'
+    pub line:               Option<(usize, String)>,
+    pending:                Vec<Edit>,
+    pending_cursor:         Option<Position>,
+    pending_line:           Option<(usize, String)>,
+    pub recording:          bool,
+}
+
+impl Default for UndoTree {
+    fn default() -> Self {
+        Self {
+            nodes: vec![UndoNode { parent: 0, edits: vec![], cursor: Position::default(), redo_child: None }],
+            current: 0,
+            saved: 0,
+            line: None,
+            pending: vec![],
+            pending_cursor: None,
+            pending_line: None,
+            recording: true,
+        }
+    }
+}
+
+impl UndoTree {
+    // remembers where the cursor was before a step starts, so undoing it can put the cursor back there
+    pub fn mark_cursor(&mut self, cursor: Position) {
+        if self.pending.is_empty() {
+            self.pending_cursor = Some(cursor);
+        }
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        if !self.recording {
+            return;
+        }
+        if self.pending.is_empty() {
+            self.pending_line = edit.old.first().filter(|_| edit.is_single_line()).map(|line| (edit.first, line.clone()));
+        }
+
+        // typing along a line keeps replacing the same line, so the edits fold into one
+        if let Some(last) = self.pending.last_mut() {
+            if last.is_single_line() && edit.is_single_line() && last.first == edit.first {
+                last.new = edit.new;
+                return;
+            }
+        }
+        self.pending.push(edit);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // closes the step being recorded. returns false when there was nothing to close
+    pub fn commit(&mut self) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        let edits = std::mem::take(&mut self.pending);
+
+        let line = edits.first().map(|edit| edit.first);
+        if edits.iter().all(|edit| edit.is_single_line() && Some(edit.first) == line) {
+            if self.line.as_ref().map(|(y, _)| *y) != line {
+                self.line = self.pending_line.take();
+            }
+        } else {
+            self.line = None;
+        }
+
+        self.nodes.push(UndoNode {
+            parent: self.current,
+            edits,
+            cursor: self.pending_cursor.take().unwrap_or_default(),
+            redo_child: None,
+        });
+        let index = self.nodes.len().saturating_sub(1);
+        self.nodes[self.current].redo_child = Some(index);
+        self.current = index;
+
+        true
+    }
+
+    // writes the history of the file at 'path', whose saved text is 'contents', to the undo directory
+    pub fn write(&self, undo_dir: &Path, path: &Path, contents: &str) -> Result<(), Error> {
+        let path = fs::canonicalize(path)?;
+        let file = UndoFile {
+            path: path.to_string_lossy().to_string(),
+            content_hash: hash(contents.as_bytes()),
+            nodes: self.nodes.clone(),
+            current: self.current,
+        };
+        let data = serde_json::to_vec(&file).map_err(Error::other)?;
+
+        fs::create_dir_all(undo_dir)?;
+        write_atomically(&undo_file_path(undo_dir, &path), &data, false)
+    }
+
+    // the history saved for 'path', as long as the file still holds what it held when the history was written
+    pub fn read(undo_dir: &Path, path: &Path, contents: &str) -> Option<UndoTree> {
+        let path = fs::canonicalize(path).ok()?;
+        let data = fs::read(undo_file_path(undo_dir, &path)).ok()?;
+        let file: UndoFile = serde_json::from_slice(&data).ok()?;
+
+        let valid = file.path == path.to_string_lossy()
+            && file.content_hash == hash(contents.as_bytes())
+            && file.current < file.nodes.len()
+            && file.nodes.iter().all(|node| node.parent < file.nodes.len());
+        if !valid {
+            return None;
+        }
+
+        Some(UndoTree { nodes: file.nodes, current: file.current, saved: file.current, ..UndoTree::default() })
+    }
+
+    // the nodes between 'from' (exclusive) and 'to', walking down from 'from', when 'from' is an ancestor of 'to'
+    pub fn path_down(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut path = vec![];
+        let mut node = to;
+        while node != from {
+            if node == 0 {
+                return None;
+            }
+            path.push(node);
+            node = self.nodes[node].parent;
+        }
+        path.reverse();
+
+        Some(path)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    path:                   String,
+    content_hash:           u64,
+    nodes:                  Vec<UndoNode>,
+    current:                usize,
+}
+
+pub fn default_undo_dir() -> Option<PathBuf> {
+    Some(state_home()?.join(UNDO_DIR))
+}
+
+fn undo_file_path(undo_dir: &Path, path: &Path) -> PathBuf {
+    undo_dir.join(format!("{:016x}.json", hash(path.to_string_lossy().as_bytes())))
+}
+
+// FNV-1a: unlike the std hasher it gives the same value across builds, which the file names depend on
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(first: usize, old: &str, new: &str) -> Edit {
+        Edit { first, old: vec![old.to_string()], new: vec![new.to_string()] }
+    }
+
+    #[test]
+    fn test_undo_tree_grouping () {
+        let mut tree = UndoTree::default();
+        assert!(!tree.commit());
+
+        tree.mark_cursor(Position { x: 3, y: 1 });
+        tree.record(edit(1, "ab", "abc"));
+        tree.record(edit(1, "abc", "abcd"));
+        tree.mark_cursor(Position { x: 9, y: 9 });
+        assert!(tree.commit());
+        assert_eq!(tree.nodes[1].edits, vec![edit(1, "ab", "abcd")]);
+        assert_eq!((tree.nodes[1].cursor.x, tree.nodes[1].cursor.y), (3, 1));
+        assert_eq!(tree.line, Some((1, "ab".to_string())));
+
+        tree.record(edit(1, "abcd", "x"));
+        tree.commit();
+        assert_eq!(tree.line, Some((1, "ab".to_string())));
+
+        // a branch made from the root
+        tree.current = 0;
+        tree.record(Edit { first: 0, old: vec!["a".to_string()], new: vec!["a".to_string(), "b".to_string()] });
+        tree.commit();
+        assert_eq!(tree.line, None);
+        assert_eq!((tree.current, tree.nodes[3].parent, tree.nodes[0].redo_child), (3, 0, Some(3)));
+        assert_eq!(tree.path_down(0, 2), Some(vec![1, 2]));
+        assert_eq!(tree.path_down(1, 3), None);
+    }
+}
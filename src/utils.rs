@@ -1,4 +1,4 @@
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::error::Error;
 use std::collections::{HashMap, HashSet};
 use unicode_segmentation::UnicodeSegmentation;
@@ -8,6 +8,8 @@ use tokio::io::AsyncWriteExt;
 use std::sync::{Arc, Mutex};
 use std::collections::VecDeque;
 use std::io;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
 use std::io::{stdin, ErrorKind, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use chrono::format::Item::{Error as ChronoError};
@@ -35,45 +37,105 @@ pub struct FSMAction {
 }
 
 pub trait Promptable {
-    fn on_prompt_loop_start(&mut self, prompt: &str) -> Result<(), std::io::Error> {
+    // 'cursor' is where the cursor is in 'prompt', in chars
+    fn on_prompt_loop_start(&mut self, prompt: &str, cursor: usize) -> Result<(), std::io::Error> {
         Ok(())
     }
+    // None once there is nothing left to read, which ends a prompt the way <Esc> does
+    fn next_key(&mut self) -> Option<Result<Key, std::io::Error>>;
+    // the lines entered at a prompt before, oldest first
+    fn history(&self, prompt: &str) -> Vec<String> {
+        vec![]
+    }
+    fn add_history(&mut self, prompt: &str, line: &str) {}
+    // what the word ending 'text' could be completed to, and the char index in 'text' the word starts at
+    fn complete(&self, prompt: &str, text: &str) -> Option<(usize, Vec<String>)> {
+        None
+    }
+    // 'callback' sees every key along with the text typed so far, <Esc> included.
+    // Up and Down go through the lines entered at the same prompt before that start with what was typed,
+    // and Tab (or Shift-Tab going the other way) goes through the completions of the word before the cursor
     fn prompt<C>(&mut self, mut callback: C, prompt: Option<String>) -> Result<Option<String>, std::io::Error>
-    where C: FnMut(&mut Self, Key) {
-        use std::io::{Stdin, Write, stdin};
-        use termion::input::TermRead;
+    where C: FnMut(&mut Self, Key, &str) {
+        let prefix = prompt.unwrap_or_default();
+        let history = self.history(&prefix);
+        let mut line = PromptLine::default();
+        // the history line shown, and what was typed before going through the history
+        let mut browsing: Option<(usize, String)> = None;
+        let mut completion: Option<Completions> = None;
 
-
-        let mut result = String::new();
         loop {
-            self.on_prompt_loop_start(&result);
+            self.on_prompt_loop_start(&format!("{}{}", prefix, line.text), prefix.chars().count().saturating_add(line.cursor));
 
-            match stdin().keys().next().unwrap_or(Err(io::Error::new(ErrorKind::InvalidInput, "")))? {
-                Key::Backspace => {
-                    result.pop();
-                },
+            let key = self.next_key().unwrap_or(Ok(Key::Esc))?;
+            if !matches!(key, Key::Char('\t') | Key::BackTab) {
+                completion = None;
+            }
+            match key {
                 Key::Char('\n') => {
-                    callback(self, Key::Char('\n'));
+                    callback(self, key, &line.text);
                     break;
                 },
-                Key::Char(x) => {
-                    if !(x.is_control()) {
-                        callback(self, Key::Char(x));
-                        result.push(x);
-                    }
-                },
                 Key::Esc => {
-                    result.truncate(0);
+                    line = PromptLine::default();
+                    callback(self, key, &line.text);
                     break;
                 },
-                _ => ()
+                Key::Backspace => line.backspace(),
+                Key::Delete => line.delete(),
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = min(line.cursor.saturating_add(1), line.len()),
+                Key::Home | Key::Ctrl('b') => line.cursor = 0,
+                Key::End | Key::Ctrl('e') => line.cursor = line.len(),
+                Key::Ctrl('w') => line.delete_word(),
+                Key::Ctrl('u') => line.replace(0, ""),
+                Key::Up | Key::Down => {
+                    let (shown, typed) = browsing.take().unwrap_or((history.len(), line.text.clone()));
+                    let found = if key == Key::Up {
+                        history[..shown].iter().rposition(|entry| entry.starts_with(&typed))
+                    } else {
+                        history.iter().enumerate().skip(shown.saturating_add(1)).find(|(_, entry)| entry.starts_with(&typed)).map(|(index, _)| index)
+                    };
+                    match found {
+                        Some(index) => {
+                            line = PromptLine::from(history[index].as_str());
+                            browsing = Some((index, typed));
+                        },
+                        // down past the newest line is back to what was typed
+                        None if key == Key::Down => line = PromptLine::from(typed.as_str()),
+                        None => browsing = Some((shown, typed)),
+                    }
+                    callback(self, key, &line.text);
+                    continue;
+                },
+                Key::Char('\t') | Key::BackTab => {
+                    if completion.is_none() {
+                        let before = line.text.chars().take(line.cursor).collect::<String>();
+                        completion = self.complete(&prefix, &before)
+                            .filter(|(_, candidates)| !candidates.is_empty())
+                            .map(|(start, candidates)| Completions::new(start, candidates, &before));
+                    }
+                    if let Some(completions) = completion.as_mut() {
+                        let text = completions.next(key == Key::BackTab);
+                        line.replace(completions.start, &text);
+                        // with only one way to complete it, the next Tab goes on from there, into a directory say
+                        if completions.candidates.len() == 1 {
+                            completion = None;
+                        }
+                    }
+                },
+                Key::Char(x) if !x.is_control() => line.insert(x),
+                _ => continue,
             }
 
+            browsing = None;
+            callback(self, key, &line.text);
         }
 
-        if result.is_empty() { return Ok(None); }
+        if line.text.is_empty() { return Ok(None); }
+        self.add_history(&prefix, &line.text);
 
-        Ok(Some(result))
+        Ok(Some(line.text))
     }
 
     fn prompt_exec<C>(&mut self, mut callback: C, prompt: Option<String>) -> Result<(), std::io::Error>
@@ -82,7 +144,7 @@ pub trait Promptable {
         use termion::input::TermRead;
 
         loop {
-            match stdin().keys().next().unwrap_or(Err(io::Error::new(ErrorKind::InvalidInput, "")))? {
+            match self.next_key().unwrap_or(Ok(Key::Esc))? {
                 Key::Esc => {
                     return Ok(())
                 },
@@ -100,12 +162,328 @@ pub trait Promptable {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone)]
+// the text typed at a prompt, with the cursor somewhere in it (counted in chars)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PromptLine {
+    pub text:           String,
+    pub cursor:         usize,
+}
+
+impl From<&str> for PromptLine {
+    fn from(text: &str) -> Self {
+        Self { text: text.to_string(), cursor: text.chars().count() }
+    }
+}
+
+impl PromptLine {
+    pub fn len(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    fn byte_index(&self, at: usize) -> usize {
+        self.text.char_indices().nth(at).map(|(index, _)| index).unwrap_or(self.text.len())
+    }
+
+    pub fn insert(&mut self, c: char) {
+        let index = self.byte_index(self.cursor);
+        self.text.insert(index, c);
+        self.cursor = self.cursor.saturating_add(1);
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let index = self.byte_index(self.cursor);
+            self.text.remove(index);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.len() {
+            let index = self.byte_index(self.cursor);
+            self.text.remove(index);
+        }
+    }
+
+    // puts 'text' in place of what is between 'start' and the cursor, and the cursor after it
+    pub fn replace(&mut self, start: usize, text: &str) {
+        let (from, to) = (self.byte_index(min(start, self.cursor)), self.byte_index(self.cursor));
+        self.text.replace_range(from..to, text);
+        self.cursor = min(start, self.cursor).saturating_add(text.chars().count());
+    }
+
+    // Ctrl-W: the blanks before the cursor and the word before them, a word being a run of keyword characters or of other non-blanks
+    pub fn delete_word(&mut self) {
+        let chars: Vec<char> = self.text.chars().take(self.cursor).collect();
+        let mut start = chars.len();
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        if let Some(last) = start.checked_sub(1).map(|index| chars[index]) {
+            let same = |c: char| !c.is_whitespace() && is_word(c) == is_word(last);
+            while start > 0 && same(chars[start - 1]) {
+                start -= 1;
+            }
+        }
+        self.replace(start, "");
+    }
+}
+
+// the ways to complete a word at a prompt. Tab goes through them and back round to the word as it was typed
+#[derive(Debug, Clone)]
+pub struct Completions {
+    pub start:          usize,
+    pub candidates:     Vec<String>,
+    pub typed:          String,
+    pub index:          usize, // candidates.len() is the word as typed
+}
+
+impl Completions {
+    pub fn new(start: usize, candidates: Vec<String>, text: &str) -> Self {
+        let typed = text.chars().skip(start).collect();
+        Self { start, index: candidates.len(), candidates, typed }
+    }
+
+    pub fn next(&mut self, backward: bool) -> String {
+        let options = self.candidates.len().saturating_add(1);
+        self.index = if backward { (self.index + options - 1) % options } else { (self.index + 1) % options };
+
+        self.candidates.get(self.index).cloned().unwrap_or_else(|| self.typed.clone())
+    }
+}
+
+// a change '.' can replay: the command's keys without its count or register, e.g. "dw", "ciw", "p" or "A"
+#[derive(Debug, Clone, Default)]
+pub struct RepeatableChange {
+    pub keys:           String,
+    pub count:          usize,
+    pub register:       Option<char>,
+    pub inserted:       Option<String>,
+}
+
+impl RepeatableChange {
+    pub fn replay_keys(&self) -> Vec<Key> {
+        let mut keys = vec![];
+        if let Some(register) = self.register {
+            keys.push(Key::Char('"'));
+            keys.push(Key::Char(register));
+        }
+        if self.count > 0 {
+            keys.extend(self.count.to_string().chars().map(Key::Char));
+        }
+        keys.extend(self.keys.chars().map(Key::Char));
+        if let Some(text) = self.inserted.as_ref() {
+            keys.extend(text.chars().map(Key::Char));
+            keys.push(Key::Esc);
+        }
+
+        keys
+    }
+}
+
+// keys that have no character of their own are kept in a private use area so a macro register round-trips them
+const SPECIAL_KEYS: [Key; 10] = [Key::Left, Key::Right, Key::Up, Key::Down, Key::Home, Key::End,
+                                 Key::PageUp, Key::PageDown, Key::Delete, Key::Insert];
+const SPECIAL_KEY_BASE: u32 = 0xE000;
+
+// how macros are stored in registers: <Esc>, <BS> and ctrl keys become the control characters vim shows as ^[, ^? and ^R
+pub fn keys_to_string(keys: &[Key]) -> String {
+    keys.iter().filter_map(|key| match key {
+        Key::Char(c) => Some(*c),
+        Key::Esc => Some('\x1b'),
+        Key::Backspace => Some('\x7f'),
+        Key::Ctrl(c) if c.is_ascii_alphabetic() => char::from_u32((c.to_ascii_lowercase() as u32) & 0x1f),
+        key => SPECIAL_KEYS.iter().position(|special| special == key)
+            .and_then(|index| char::from_u32(SPECIAL_KEY_BASE.saturating_add(index as u32))),
+    }).collect()
+}
+
+pub fn string_to_keys(text: &str) -> Vec<Key> {
+    text.chars().map(|c| match c {
+        '\x1b' => Key::Esc,
+        '\x7f' => Key::Backspace,
+        '\n' | '\t' => Key::Char(c),
+        '\x01'..='\x1a' => Key::Ctrl(char::from_u32(c as u32 + 0x60).unwrap_or('@')),
+        c if (c as u32) >= SPECIAL_KEY_BASE && ((c as u32) - SPECIAL_KEY_BASE) < SPECIAL_KEYS.len() as u32 => {
+            SPECIAL_KEYS[((c as u32) - SPECIAL_KEY_BASE) as usize]
+        },
+        c => Key::Char(c),
+    }).collect()
+}
+
+#[derive(Default, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Position {
     pub x: u16,
     pub y: u16,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SearchDirection {
+    Forward,
+    Backward
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct Coordinate(pub i64, pub i64); // (x, y), -1 when nothing was found
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum RangeKind {
+    #[default]
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+// the first and last lines of an Ex command's range, counting from 1
+pub type LineRange = (usize, usize);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TextRange {
+    pub start:          Position,
+    pub end:            Position, // exclusive for charwise ranges, the last line for linewise ones; a block spans the columns start.x..end.x
+    pub kind:           RangeKind,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+    Togglecase,
+}
+
+impl Operator {
+    pub fn from_key(key: char) -> Option<Self> {
+        match key {
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            _ => None
+        }
+    }
+
+    pub fn key(&self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Change => 'c',
+            Operator::Yank => 'y',
+            Operator::ShiftRight => '>',
+            Operator::ShiftLeft => '<',
+            Operator::Lowercase => 'u',
+            Operator::Uppercase => 'U',
+            Operator::Togglecase => '~',
+        }
+    }
+
+    // the operators a visual selection takes, which don't need a motion after them
+    pub fn from_visual_key(key: char) -> Option<Self> {
+        match key {
+            'd' | 'x' => Some(Operator::Delete),
+            'c' | 's' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::ShiftRight),
+            '<' => Some(Operator::ShiftLeft),
+            'u' => Some(Operator::Lowercase),
+            'U' => Some(Operator::Uppercase),
+            '~' => Some(Operator::Togglecase),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Motion {
+    Left,               // h
+    Right,              // l
+    Down,               // j
+    Up,                 // k
+    NextWordStart,      // w
+    NextWordEnd,        // e
+    PrevWordStart,      // b
+    PrevWordEnd,        // ge
+    LineStart,          // 0
+    FirstLineGraph,     // ^
+    LastLineGraph,      // g_
+    LineEnd,            // $
+    LastLine,           // G
+    FirstLine,          // gg
+    TopScreen,          // H
+    MidScreen,          // M
+    BottomScreen,       // L
+    CurrentLine,        // the doubled operator forms: dd, cc, yy
+}
+
+impl Motion {
+    pub fn from_key(key: char) -> Option<Self> {
+        match key {
+            'h' => Some(Motion::Left),
+            'l' => Some(Motion::Right),
+            'j' => Some(Motion::Down),
+            'k' => Some(Motion::Up),
+            'w' => Some(Motion::NextWordStart),
+            'e' => Some(Motion::NextWordEnd),
+            'b' => Some(Motion::PrevWordStart),
+            '0' => Some(Motion::LineStart),
+            '^' | '_' => Some(Motion::FirstLineGraph),
+            '$' => Some(Motion::LineEnd),
+            'G' => Some(Motion::LastLine),
+            'H' => Some(Motion::TopScreen),
+            'M' => Some(Motion::MidScreen),
+            'L' => Some(Motion::BottomScreen),
+            _ => None
+        }
+    }
+
+    pub fn from_g_key(key: char) -> Option<Self> {
+        match key {
+            'g' => Some(Motion::FirstLine),
+            'e' => Some(Motion::PrevWordEnd),
+            '_' => Some(Motion::LastLineGraph),
+            _ => None
+        }
+    }
+
+    pub fn is_linewise(&self) -> bool {
+        matches!(self, Motion::Down | Motion::Up | Motion::LastLine | Motion::FirstLine
+            | Motion::TopScreen | Motion::MidScreen | Motion::BottomScreen | Motion::CurrentLine)
+    }
+
+    pub fn is_inclusive(&self) -> bool {
+        matches!(self, Motion::NextWordEnd | Motion::PrevWordEnd | Motion::LastLineGraph | Motion::LineEnd)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TextObject {
+    Word,                   // iw, aw
+    Bracket(char, char),    // i( a( i[ a[ i{ a{ i< a<
+    Quote(char),            // i" a" i' a' i` a`
+    Paragraph,              // ip, ap
+    Sentence,               // is, as
+}
+
+impl TextObject {
+    pub fn from_key(key: char) -> Option<Self> {
+        match key {
+            'w' => Some(TextObject::Word),
+            'p' => Some(TextObject::Paragraph),
+            's' => Some(TextObject::Sentence),
+            'b' => Some(TextObject::Bracket('(', ')')),
+            'B' => Some(TextObject::Bracket('{', '}')),
+            '"' | '\'' | '`' => Some(TextObject::Quote(key)),
+            c if is_brace(c) => {
+                let open = if is_opening_brace(c) { c } else { get_matching_enclosable(c)? };
+                Some(TextObject::Bracket(open, get_matching_enclosable(open)?))
+            },
+            _ => None
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ScrollDirection {
     Up,
@@ -115,11 +493,65 @@ pub enum ScrollDirection {
     None
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Selection {
-    pub position:       Position,
+    pub position:       Position, // where the selection was started; the cursor is its other end
     pub start:          (u16, u16),
-    pub end:            (u16, u16), // range is: [start, end]
+    pub end:            (u16, u16), // range is: [start, end]. for blockwise selections, the top left and bottom right corners
+    pub kind:           RangeKind,
+    pub to_line_end:    bool, // a block extended with '$' reaches the end of every line
+}
+
+impl Selection {
+    pub fn new(position: Position, kind: RangeKind) -> Self {
+        Self { position, start: (position.x, position.y), end: (position.x, position.y), kind, to_line_end: false }
+    }
+
+    // recomputes the range from the anchor and wherever the cursor has moved to
+    pub fn update(&mut self, cursor: Position) {
+        let anchor = self.position;
+        if self.kind == RangeKind::Blockwise {
+            self.start = (min(anchor.x, cursor.x), min(anchor.y, cursor.y));
+            self.end = (max(anchor.x, cursor.x), max(anchor.y, cursor.y));
+        } else if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            self.start = (anchor.x, anchor.y);
+            self.end = (cursor.x, cursor.y);
+        } else {
+            self.start = (cursor.x, cursor.y);
+            self.end = (anchor.x, anchor.y);
+        }
+    }
+
+    // the columns of line 'y' the selection covers, the end being exclusive
+    pub fn columns(&self, y: u16, line_len: usize) -> Option<(usize, usize)> {
+        let ((start_x, start_y), (end_x, end_y)) = (self.start, self.end);
+        if y < start_y || y > end_y {
+            return None;
+        }
+
+        let (from, to) = match self.kind {
+            RangeKind::Linewise => (0, line_len),
+            RangeKind::Blockwise => (start_x as usize, if self.to_line_end { line_len } else { (end_x as usize).saturating_add(1) }),
+            RangeKind::Charwise => (
+                if y == start_y { start_x as usize } else { 0 },
+                if y == end_y { (end_x as usize).saturating_add(1) } else { line_len },
+            ),
+        };
+
+        Some((min(from, line_len), min(to, line_len)))
+    }
+
+    // the range an operator works on
+    pub fn range(&self) -> TextRange {
+        let ((start_x, start_y), (end_x, end_y)) = (self.start, self.end);
+        let end_x = match self.kind {
+            RangeKind::Linewise => start_x,
+            RangeKind::Blockwise if self.to_line_end => u16::MAX,
+            _ => end_x.saturating_add(1),
+        };
+
+        TextRange { start: Position { x: start_x, y: start_y }, end: Position { x: end_x, y: end_y }, kind: self.kind }
+    }
 }
 
 #[derive(Debug, PartialEq, Hash)]
@@ -153,7 +585,7 @@ pub enum NumberMode {
     None
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct HighlightStreak {
     pub comment:    u16,
     pub quote:      bool,
@@ -309,9 +741,11 @@ pub static LOGGER: OnceCell<OrderedLogger> = OnceCell::new();
 macro_rules! log {
     ($($arg: tt)*) => {
         {
-            let log_message = format!($($arg)*);
-            let logger = $crate::LOGGER.get().expect("Logger not initialized");
-            &logger.log(&log_message).unwrap();
+            // logging is off unless a log file was given with --log
+            if let Some(logger) = $crate::LOGGER.get() {
+                let log_message = format!($($arg)*);
+                logger.log(&log_message).unwrap();
+            }
         }
     }
 }
@@ -324,6 +758,40 @@ pub enum TerminalMode {
     Insert,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InsertKind {
+    Before,     // i
+    After,      // a
+    LineStart,  // I
+    LineEnd,    // A
+    LineBelow,  // o
+    LineAbove,  // O
+}
+
+#[derive(Debug)]
+pub struct InsertSession {
+    pub kind:           InsertKind,
+    pub count:          usize,
+    pub text:           String, // what was typed, replayed (count - 1) times on <Esc>
+    pub block:          Option<BlockInsert>,
+}
+
+// 'I', 'A' and 'c' on a block: what was typed on its first line goes into the other lines too on <Esc>
+#[derive(Debug, Clone, Copy)]
+pub struct BlockInsert {
+    pub lines:          (u16, u16), // the block's other lines, first and last
+    pub column:         u16,
+    pub pad:            bool, // 'A' pads lines too short to reach the column with spaces; otherwise they're left alone
+    pub to_line_end:    bool, // '$A' appends at the end of each line instead
+}
+
+// where files that outlive a session go: $XDG_STATE_HOME, or ~/.local/state
+pub fn state_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+}
+
 pub fn die(err: impl Error) {
     panic!("{}", err);
 }
@@ -350,19 +818,19 @@ pub fn is_even(s: usize) -> bool {
 }
 
 pub fn is_brace(c: char) -> bool {
-    c == '[' || c == ']' || c == '{' || c == '}' || c == '(' || c == ')'
+    c == '[' || c == ']' || c == '{' || c == '}' || c == '(' || c == ')' || c == '<' || c == '>'
 }
 
 pub fn is_opening_brace(c: char) -> bool {
-    c == '[' || c == '{' || c == '('
+    c == '[' || c == '{' || c == '(' || c == '<'
 }
 
 pub fn is_closing_brace(c: char) -> bool {
-    c == ']' || c == '}' || c == ')'
+    c == ']' || c == '}' || c == ')' || c == '>'
 }
 
 pub fn get_matching_enclosable(c: char) -> Option<char> {
-    let brace_hash: HashMap<char, char> = HashMap::from([['[', ']'], ['{', '}'], ['(', ')'], [']', '['], ['}', '{'], [')', '(']]);
+    let brace_hash: HashMap<char, char> = HashMap::from([('[', ']'), ('{', '}'), ('(', ')'), ('<', '>'), (']', '['), ('}', '{'), (')', '('), ('>', '<')]);
 
     if let Some(c) = brace_hash.get(&c) {
         Some(*c)
@@ -484,8 +952,12 @@ pub fn get_isolated_v_str_class(c: &str) -> VCharacterClass {
     }
 }
 
-pub fn find_string_position(texts: &Vec<&str>, condition: impl Fn(&str) -> bool) -> Option<usize> {
-    texts.iter().enumerate().find(|(i, & c)| condition(c)).map(|(i, _)| i)
+pub fn find_string_position(texts: Vec<&str>, condition: impl Fn(&str) -> bool, reverse: bool, length: usize) -> Option<usize> {
+    if reverse {
+        texts.iter().rev().take(length).enumerate().find(|(i, & c)| condition(c)).map(|(i, _)| i)
+    } else {
+        texts.iter().take(length).enumerate().find(|(i, & c)| condition(c)).map(|(i, _)| i)
+    }
 }
 
 pub fn v_jump_to_line(editor: &mut Editor, fsm: &mut EditorFSM, final_key: &char) -> () { // a vertical line jump
@@ -525,4 +997,42 @@ mod tests {
             println!("char not found!");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_prompt_line () {
+        let mut line = PromptLine::from("set ic");
+        line.cursor = 3;
+        line.insert('!');
+        assert_eq!((line.text.as_str(), line.cursor), ("set! ic", 4));
+        line.backspace();
+        line.delete();
+        assert_eq!((line.text.as_str(), line.cursor), ("setic", 3));
+
+        let mut line = PromptLine::from("s/foo.bar/x  ");
+        line.delete_word();
+        assert_eq!(line.text, "s/foo.bar/");
+        line.delete_word();
+        assert_eq!(line.text, "s/foo.bar");
+        line.delete_word();
+        assert_eq!(line.text, "s/foo.");
+        line.replace(0, "wq");
+        assert_eq!((line.text.as_str(), line.cursor), ("wq", 2));
+
+        // Tab goes round the completions and back to what was typed
+        let mut completions = Completions::new(3, vec!["ignorecase".to_string(), "incsearch".to_string()], "se i");
+        assert_eq!(completions.typed, "i");
+        assert_eq!(completions.next(false), "ignorecase");
+        assert_eq!(completions.next(false), "incsearch");
+        assert_eq!(completions.next(false), "i");
+        assert_eq!(completions.next(true), "incsearch");
+    }
+
+    #[test]
+    fn test_macro_keys_round_trip() {
+        let keys = vec![Key::Char('i'), Key::Char('é'), Key::Char('\n'), Key::Esc, Key::Ctrl('r'), Key::Backspace, Key::Left, Key::Delete];
+        let text = keys_to_string(&keys);
+
+        assert_eq!(text.chars().take(5).collect::<String>(), "ié\n\x1b\x12");
+        assert_eq!(string_to_keys(&text), keys);
+    }
+}